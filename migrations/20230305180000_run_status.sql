@@ -0,0 +1,31 @@
+/*
+ * Runs are created before an agent has picked them up, so the status becomes
+ * a textual state rather than a unix return code and the log_url is only
+ * known once an agent has accepted the work
+ */
+CREATE TABLE runs_new (
+    uuid TEXT NOT NULL PRIMARY KEY,
+    num INTEGER NOT NULL,
+    status TEXT NOT NULL DEFAULT 'pending',
+    log_url TEXT,
+
+    project TEXT NOT NULL,
+    definition TEXT NOT NULL,
+    scm_info TEXT NOT NULL,
+
+    created_at DATETIME NOT NULL DEFAULT (DATETIME('now')),
+    FOREIGN KEY(project) REFERENCES projects(uuid),
+    FOREIGN KEY(scm_info) REFERENCES scm_info(uuid),
+    FOREIGN KEY(definition) REFERENCES run_definition(uuid)
+);
+
+INSERT INTO runs_new (uuid, num, status, log_url, project, definition, scm_info, created_at)
+    SELECT uuid, num,
+        CASE status WHEN 0 THEN 'succeeded' ELSE 'failed' END,
+        log_url, project, definition, scm_info, created_at
+    FROM runs;
+
+DROP TABLE runs;
+ALTER TABLE runs_new RENAME TO runs;
+
+CREATE UNIQUE INDEX runs_project_num ON runs(project, num);
@@ -0,0 +1,4 @@
+/*
+ * WebSockets URL on the agent for streaming the logs of a run as it executes
+ */
+ALTER TABLE runs ADD COLUMN stream_url TEXT;
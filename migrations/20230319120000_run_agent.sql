@@ -0,0 +1,4 @@
+/*
+ * Name of the agent which the run was dispatched to
+ */
+ALTER TABLE runs ADD COLUMN agent TEXT;
@@ -0,0 +1,6 @@
+/*
+ * Additional details about each run needed to present its history
+ */
+ALTER TABLE runs ADD COLUMN trigger TEXT NOT NULL DEFAULT 'manual';
+ALTER TABLE runs ADD COLUMN started_at DATETIME;
+ALTER TABLE runs ADD COLUMN finished_at DATETIME;
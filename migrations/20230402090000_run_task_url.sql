@@ -0,0 +1,4 @@
+/*
+ * URL of the task on the agent executing the run, used for cancelling it
+ */
+ALTER TABLE runs ADD COLUMN task_url TEXT;
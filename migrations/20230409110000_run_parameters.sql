@@ -0,0 +1,4 @@
+/*
+ * Values of the parameters the run was triggered with, stored as a JSON object
+ */
+ALTER TABLE runs ADD COLUMN parameters TEXT NOT NULL DEFAULT '{}';
@@ -0,0 +1,4 @@
+/*
+ * Commit which the ref of the scm_info resolved to when the run was created
+ */
+ALTER TABLE scm_info ADD COLUMN sha TEXT;
@@ -0,0 +1,5 @@
+/*
+ * Who caused the run to be created, such as the user who pushed the commit a webhook
+ * was sent for
+ */
+ALTER TABLE runs ADD COLUMN sender TEXT;
@@ -0,0 +1,5 @@
+/*
+ * The time the schedule of the project last fired at, so that a restarted server
+ * neither fires it again nor skips it
+ */
+ALTER TABLE projects ADD COLUMN scheduled_at DATETIME;
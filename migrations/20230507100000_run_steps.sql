@@ -0,0 +1,23 @@
+/*
+ * The steps of each run, which are dispatched to agents individually. The stage and
+ * position locate the step within the definition of the run
+ */
+CREATE TABLE steps (
+    uuid TEXT NOT NULL PRIMARY KEY,
+    run TEXT NOT NULL,
+    stage INTEGER NOT NULL,
+    position INTEGER NOT NULL,
+    stage_name TEXT NOT NULL,
+    name TEXT NOT NULL,
+    status TEXT NOT NULL,
+    agent TEXT,
+    log_url TEXT,
+    stream_url TEXT,
+    task_url TEXT,
+    created_at DATETIME NOT NULL DEFAULT (DATETIME('now')),
+    started_at DATETIME,
+    finished_at DATETIME,
+    FOREIGN KEY(run) REFERENCES runs(uuid)
+);
+
+CREATE INDEX steps_run ON steps (run, stage, position);
@@ -0,0 +1,7 @@
+/*
+ * Secret handed to the agents executing the run in their callback URLs, which has to be
+ * presented to update the status of the run or its steps
+ */
+ALTER TABLE runs ADD COLUMN token TEXT;
+
+UPDATE runs SET token = LOWER(HEX(RANDOMBLOB(16)));
@@ -53,6 +53,12 @@ pub struct Git {
     data: Option<HashMap<String, String>>,
 }
 
+impl Git {
+    pub fn path(&self) -> &std::path::Path {
+        &self.path
+    }
+}
+
 impl Capability for Git {
     fn binary_name() -> String {
         "git".into()
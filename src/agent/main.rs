@@ -1,17 +1,31 @@
 #[macro_use]
 extern crate serde_json;
 
-use std::path::PathBuf;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 use async_std::channel::{bounded, Receiver, Sender};
+use async_std::sync::{Arc, RwLock};
 use dotenv::dotenv;
+use gumdrop::Options;
 use log::*;
-use synchronik::CommandRequest;
+use synchronik::{CommandRequest, TaskState, TaskStatus};
 use uuid::Uuid;
 
 const AGENT_LOGS_DIR: &str = "agent-logs";
+// Number of tasks whose status is retained in memory for the task APIs
+const MAX_TASKS: usize = 100;
 
 mod caps;
+mod processes;
+#[cfg(test)]
+#[path = "../testing.rs"]
+mod testing;
+mod workspace;
+
+use crate::processes::Processes;
+use crate::workspace::Workspaces;
 
 mod routes {
     use tide::{Body, Request};
@@ -26,15 +40,20 @@ mod routes {
     pub mod api {
         use crate::caps::*;
         use crate::*;
-        use synchronik::{CommandRequest, CommandResponse};
+        use async_std::io::ReadExt;
+        use synchronik::{CommandRequest, CommandResponse, TasksResponse};
         use tide::{Body, Request, Response, StatusCode};
+        use tide_websockets::{WebSocket, WebSocketConnection};
         use uuid::Uuid;
 
-        use std::path::Path;
-
         pub fn register(app: &mut tide::Server<State>) {
             app.at("/api/v1/capabilities").get(get_caps);
             app.at("/api/v1/execute").put(execute);
+            app.at("/api/v1/tasks").get(list_tasks);
+            app.at("/api/v1/tasks/:uuid").get(get_task);
+            app.at("/api/v1/tasks/:uuid/cancel").post(cancel_task);
+            app.at("/api/v1/tasks/:uuid/stream")
+                .get(WebSocket::new(stream_task));
         }
 
         /*
@@ -53,33 +72,177 @@ mod routes {
             let c: CommandRequest = req.body_json().await?;
             debug!("Commands to exec: {:?}", c);
             let uuid = Uuid::new_v4();
+            let log_file_path = log_file_for(&uuid);
             // Create my log directory
-            let log_dir = Path::new(AGENT_LOGS_DIR).join(uuid.hyphenated().to_string());
             // TODO: Handle this error
-            std::fs::create_dir(log_dir.clone()).expect("Failed to create log dir");
+            if let Some(log_dir) = log_file_path.parent() {
+                std::fs::create_dir(log_dir).expect("Failed to create log dir");
+            }
+
+            let log = req
+                .url()
+                .join(&format!("../../{}", log_file_path.display()))
+                .unwrap();
 
-            let log_file_path = log_dir.join("console.log");
+            let mut status = TaskStatus::new(uuid, &c.commands);
+            status.log = Some(log.clone());
+            track_task(&mut *req.state().tasks.write().await, status);
+
+            let workspace = req
+                .state()
+                .workspaces
+                .path_for(&uuid, c.workspace.as_deref());
             let work = Work {
                 task: uuid,
                 log_file: log_file_path.clone(),
+                workspace,
                 command: c,
             };
             req.state().channel.send(work).await?;
 
+            let mut stream = req.url().join(&format!("/api/v1/tasks/{}/stream", uuid))?;
+            let scheme = match stream.scheme() {
+                "https" => "wss",
+                _ => "ws",
+            };
+            stream
+                .set_scheme(scheme)
+                .expect("Failed to set the WebSockets scheme");
+
             let response = CommandResponse {
                 uuid,
-                stream: None,
-                task: None,
-                log: req
-                    .url()
-                    .join(&format!("../../{}", log_file_path.display()))
-                    .unwrap(),
+                stream: Some(stream),
+                task: Some(req.url().join(&format!("/api/v1/tasks/{}", uuid))?),
+                log,
             };
             let mut http_response = Response::new(StatusCode::Created);
             http_response.set_body(Body::from_json(&response)?);
             Ok(http_response)
         }
 
+        /*
+         * GET /tasks
+         *
+         * List the tasks known to this agent, most recently created first
+         */
+        pub async fn list_tasks(req: Request<State>) -> Result<Body, tide::Error> {
+            let mut tasks: Vec<TaskStatus> =
+                req.state().tasks.read().await.values().cloned().collect();
+            tasks.sort_by_key(|t| std::cmp::Reverse(t.created_at));
+            Body::from_json(&TasksResponse { tasks })
+        }
+
+        /*
+         * GET /tasks/{uuid}
+         */
+        pub async fn get_task(req: Request<State>) -> Result<Response, tide::Error> {
+            let uuid: Uuid = match req.param("uuid")?.parse() {
+                Ok(uuid) => uuid,
+                Err(_) => return Ok(Response::new(StatusCode::BadRequest)),
+            };
+
+            match req.state().tasks.read().await.get(&uuid) {
+                Some(status) => {
+                    let mut response = Response::new(StatusCode::Ok);
+                    response.set_body(Body::from_json(status)?);
+                    Ok(response)
+                }
+                None => Ok(Response::new(StatusCode::NotFound)),
+            }
+        }
+
+        /*
+         * POST /tasks/{uuid}/cancel
+         *
+         * Stop the task, terminating the process group of the command it is executing
+         */
+        pub async fn cancel_task(req: Request<State>) -> Result<Response, tide::Error> {
+            let uuid: Uuid = match req.param("uuid")?.parse() {
+                Ok(uuid) => uuid,
+                Err(_) => return Ok(Response::new(StatusCode::BadRequest)),
+            };
+
+            let state = match req.state().tasks.read().await.get(&uuid) {
+                Some(status) => status.state,
+                None => return Ok(Response::new(StatusCode::NotFound)),
+            };
+            if state.is_finished() {
+                return Ok(Response::new(StatusCode::Conflict));
+            }
+
+            info!("Cancelling task {}", uuid);
+            req.state().processes.stop(uuid, TaskState::Cancelled);
+
+            /*
+             * A task which has not started yet is cancelled right away, the worker will
+             * skip over it once it is received
+             */
+            let status = update_task(&req.state().tasks, &uuid, |t| {
+                if t.state == TaskState::Pending {
+                    t.state = TaskState::Cancelled;
+                    t.finished_at = Some(chrono::Utc::now());
+                }
+            })
+            .await;
+
+            let mut response = Response::new(StatusCode::Accepted);
+            response.set_body(Body::from_json(&status)?);
+            Ok(response)
+        }
+
+        /*
+         * GET /tasks/{uuid}/stream
+         *
+         * Upgrade to a WebSocket which replays the log of the task so far and then tails
+         * new output until the task has finished
+         */
+        pub async fn stream_task(
+            req: Request<State>,
+            stream: WebSocketConnection,
+        ) -> Result<(), tide::Error> {
+            let uuid: Uuid = req.param("uuid")?.parse()?;
+            let log_file_path = log_file_for(&uuid);
+            let mut log_file = None;
+            // Bytes of a character which was only partly written by the previous read
+            let mut pending = vec![];
+
+            loop {
+                /*
+                 * Check whether the task has finished before reading so that the last
+                 * read picks up everything the task wrote
+                 */
+                let finished = match req.state().tasks.read().await.get(&uuid) {
+                    Some(status) => status.state.is_finished(),
+                    None => true,
+                };
+
+                if log_file.is_none() {
+                    log_file = async_std::fs::File::open(&log_file_path).await.ok();
+                }
+
+                if let Some(file) = log_file.as_mut() {
+                    file.read_to_end(&mut pending).await?;
+                    let text = take_complete_utf8(&mut pending);
+                    if !text.is_empty() {
+                        stream.send_string(text).await?;
+                    }
+                }
+
+                if finished {
+                    /*
+                     * Nothing more is coming to complete the last character
+                     */
+                    if !pending.is_empty() {
+                        stream
+                            .send_string(String::from_utf8_lossy(&pending).into())
+                            .await?;
+                    }
+                    return Ok(());
+                }
+                async_std::task::sleep(std::time::Duration::from_millis(250)).await;
+            }
+        }
+
         /*
          * GET /capabilities
          */
@@ -103,60 +266,474 @@ mod routes {
 struct Work {
     task: Uuid,
     log_file: PathBuf,
+    // Directory the commands are executed in
+    workspace: PathBuf,
     command: CommandRequest,
 }
 
+/*
+ * The status of every task this agent has been asked to execute, keyed by the task's Uuid
+ */
+type Tasks = Arc<RwLock<HashMap<Uuid, TaskStatus>>>;
+
 /*
  * State struct just carries data into Tide request handlers
  */
 #[derive(Clone, Debug)]
 pub struct State {
     channel: Sender<Work>,
+    tasks: Tasks,
+    processes: Processes,
+    workspaces: Workspaces,
 }
 
 /*
- * The worker function just does a busy loop executing Work
+ * Take the text from the buffer, leaving behind the bytes at its end which start a
+ * character without finishing it so that they can be completed by the next read
  */
-async fn worker(receiver: Receiver<Work>) {
-    debug!("Worker thread starting");
+fn take_complete_utf8(buf: &mut Vec<u8>) -> String {
+    let mut incomplete = 0;
+    for back in 1..=buf.len().min(3) {
+        let byte = buf[buf.len() - back];
+        // Continuation bytes are 0b10xxxxxx, anything else starts a character
+        if byte & 0xC0 != 0x80 {
+            let width = match byte {
+                0xF0.. => 4,
+                0xE0.. => 3,
+                0xC0.. => 2,
+                _ => 1,
+            };
+            if width > back {
+                incomplete = back;
+            }
+            break;
+        }
+    }
 
-    while let Ok(work) = receiver.recv().await {
-        let log_file = std::fs::File::create(&work.log_file).unwrap();
-        let mut bufw = std::io::BufWriter::new(log_file);
-        debug!(
-            "Starting to execute the commands, output in {:?}",
-            &work.log_file
-        );
-        for command in work.command.commands.iter() {
-            debug!("Command: {:?}", command);
-            use os_pipe::pipe;
-            use std::process::Command;
-            let mut cmd = Command::new("sh");
-            cmd.args(["-xec", &command.script]);
-            let (mut reader, writer) = pipe().expect("Failed to create pipe");
-            let writer_clone = writer.try_clone().expect("Failed to clone writer pipe");
-            cmd.stdout(writer);
-            cmd.stderr(writer_clone);
-            let mut handle = cmd.spawn().expect("Failed to launch command");
+    let rest = buf.split_off(buf.len() - incomplete);
+    let text = String::from_utf8_lossy(buf).into_owned();
+    *buf = rest;
+    text
+}
+
+/*
+ * Compute the path of the console log for the given task
+ */
+fn log_file_for(uuid: &Uuid) -> PathBuf {
+    PathBuf::from(AGENT_LOGS_DIR)
+        .join(uuid.hyphenated().to_string())
+        .join("console.log")
+}
+
+/*
+ * Start tracking the status of a new task, discarding the oldest finished tasks once
+ * more than MAX_TASKS are being tracked
+ */
+fn track_task(tasks: &mut HashMap<Uuid, TaskStatus>, status: TaskStatus) {
+    tasks.insert(status.uuid, status);
+
+    if tasks.len() > MAX_TASKS {
+        let mut finished: Vec<(Uuid, chrono::DateTime<chrono::Utc>)> = tasks
+            .values()
+            .filter(|t| t.state.is_finished())
+            .map(|t| (t.uuid, t.created_at))
+            .collect();
+        finished.sort_by_key(|(_, created_at)| *created_at);
+
+        for (uuid, _) in finished.iter().take(tasks.len() - MAX_TASKS) {
+            tasks.remove(uuid);
+        }
+    }
+}
+
+/*
+ * Apply the given change to the status of the task, returning the updated status
+ */
+async fn update_task<F: FnOnce(&mut TaskStatus)>(
+    tasks: &Tasks,
+    uuid: &Uuid,
+    change: F,
+) -> Option<TaskStatus> {
+    let mut tasks = tasks.write().await;
+    tasks.get_mut(uuid).map(|status| {
+        change(status);
+        status.clone()
+    })
+}
+
+/*
+ * Run the script to completion with its output going to the log file, returning the
+ * exit code of the script
+ *
+ * The script is run in its own process group so that cancelling the task can stop
+ * everything the script has spawned
+ */
+fn run_command(
+    task: Uuid,
+    script: &str,
+    env: &HashMap<String, String>,
+    cwd: &Path,
+    mut log_file: std::fs::File,
+    processes: &Processes,
+) -> Option<i32> {
+    use os_pipe::pipe;
+    use std::os::unix::process::CommandExt;
+    use std::process::Command;
+
+    let mut cmd = Command::new("sh");
+    cmd.args(["-xec", script]);
+    cmd.envs(env);
+    cmd.current_dir(cwd);
+    cmd.process_group(0);
+    let (mut reader, writer) = pipe().expect("Failed to create pipe");
+    let writer_clone = writer.try_clone().expect("Failed to clone writer pipe");
+    cmd.stdout(writer);
+    cmd.stderr(writer_clone);
+    match cmd.spawn() {
+        Ok(mut handle) => {
             drop(cmd);
+            processes.started(task, handle.id());
 
-            debug!("executing: {}", &command.script);
-            std::io::copy(&mut reader, &mut bufw).expect("Failed to copy streams");
+            debug!("executing: {}", script);
+            std::io::copy(&mut reader, &mut log_file).expect("Failed to copy streams");
 
             let status = handle.wait().expect("Failed to wait on handle");
-            debug!("status of {}: {:?}", &command.script, status);
+            processes.exited(&task);
+            debug!("status of {}: {:?}", script, status);
+            status.code()
+        }
+        Err(e) => {
+            error!("Failed to launch {}: {:?}", script, e);
+            None
+        }
+    }
+}
+
+/*
+ * Execute the commands for the Work serially, stopping at the first command which
+ * does not exit successfully
+ */
+async fn execute(work: &Work, tasks: &Tasks, processes: &Processes) -> Option<TaskStatus> {
+    /*
+     * The log is written to directly rather than buffered so that the output is visible
+     * to streaming clients as soon as the commands produce it
+     */
+    let log_file = std::fs::File::create(&work.log_file).unwrap();
+
+    if let Err(e) = std::fs::create_dir_all(&work.workspace) {
+        error!(
+            "Failed to create the workspace {:?}: {:?}",
+            work.workspace, e
+        );
+        mark(&log_file, "Failed to create the workspace");
+        processes.finished(&work.task);
+        return update_task(tasks, &work.task, |t| {
+            t.state = TaskState::Failed;
+            t.finished_at = Some(chrono::Utc::now());
+        })
+        .await;
+    }
+
+    if processes.stopped(&work.task).is_some() {
+        debug!("Task {} was cancelled before it started", work.task);
+        processes.finished(&work.task);
+        return update_task(tasks, &work.task, |t| {
+            t.state = TaskState::Cancelled;
+            t.finished_at.get_or_insert_with(chrono::Utc::now);
+        })
+        .await;
+    }
+    debug!(
+        "Starting to execute the commands for {}, output in {:?}",
+        work.task, &work.log_file
+    );
+    update_task(tasks, &work.task, |t| {
+        t.state = TaskState::Running;
+        t.started_at = Some(chrono::Utc::now());
+    })
+    .await;
+
+    let started = std::time::Instant::now();
+    let mut state = match &work.command.source {
+        Some(source) => checkout(work, source, &log_file, processes).await,
+        None => TaskState::Succeeded,
+    };
+    for (index, command) in work.command.commands.iter().enumerate() {
+        debug!("Command: {:?}", command);
+        if state != TaskState::Succeeded {
+            break;
+        }
+        if let Some(stopped) = processes.stopped(&work.task) {
+            state = stopped;
+            break;
+        }
+
+        /*
+         * The command may run until its own timeout or until whatever remains of the
+         * timeout for the whole task, whichever comes first
+         */
+        let remaining = work
+            .command
+            .timeout
+            .map(|t| Duration::from_secs(t).saturating_sub(started.elapsed()));
+        let limit = command
+            .timeout
+            .map(Duration::from_secs)
+            .into_iter()
+            .chain(remaining)
+            .min();
+
+        update_task(tasks, &work.task, |t| {
+            t.commands[index].state = TaskState::Running
+        })
+        .await;
+
+        let mut env = work.command.env.clone();
+        env.extend(command.env.clone());
+        env.insert(
+            "SYNCHRONIK_WORKSPACE".into(),
+            work.workspace.to_string_lossy().into(),
+        );
+        let exit_code = run_script(work, &command.script, env, limit, &log_file, processes).await;
+
+        let command_state = match exit_code {
+            Some(0) => TaskState::Succeeded,
+            _ => processes.stopped(&work.task).unwrap_or(TaskState::Failed),
+        };
+        update_task(tasks, &work.task, |t| {
+            t.commands[index].state = command_state;
+            t.commands[index].exit_code = exit_code;
+        })
+        .await;
+
+        if command_state != TaskState::Succeeded {
+            info!(
+                "Command {} of task {} ended as {:?} with {:?}, skipping the rest",
+                index, work.task, command_state, exit_code
+            );
+            state = command_state;
+            break;
+        }
+    }
+
+    if state == TaskState::Cancelled {
+        mark(&log_file, "The task was cancelled");
+    }
+    processes.finished(&work.task);
+    update_task(tasks, &work.task, |t| {
+        t.state = state;
+        t.finished_at = Some(chrono::Utc::now());
+    })
+    .await
+}
+
+/*
+ * Run the script in the workspace of the Work, stopping it once the limit has passed
+ */
+async fn run_script(
+    work: &Work,
+    script: &str,
+    env: HashMap<String, String>,
+    limit: Option<Duration>,
+    log_file: &std::fs::File,
+    processes: &Processes,
+) -> Option<i32> {
+    /*
+     * Running the script blocks on the pipe and the child, so it is kept off the async
+     * executor to let the APIs keep serving while the script executes
+     */
+    let script = script.to_string();
+    let workspace = work.workspace.clone();
+    let script_log = log_file
+        .try_clone()
+        .expect("Failed to clone the log file handle");
+    let task = work.task;
+    let script_processes = processes.clone();
+    let mut handle = async_std::task::spawn_blocking(move || {
+        run_command(
+            task,
+            &script,
+            &env,
+            &workspace,
+            script_log,
+            &script_processes,
+        )
+    });
+
+    match limit {
+        Some(limit) => match async_std::future::timeout(limit, &mut handle).await {
+            Ok(exit_code) => exit_code,
+            Err(_) => {
+                info!("Task {} timed out", work.task);
+                mark(
+                    log_file,
+                    &format!("Timed out after {} seconds", limit.as_secs()),
+                );
+                processes.stop(work.task, TaskState::TimedOut);
+                handle.await
+            }
+        },
+        None => handle.await,
+    }
+}
+
+/*
+ * Check out the exact commit of the source into the workspace before any commands are
+ * executed. Persistent workspaces already hold a clone, so the commit is fetched into it
+ */
+async fn checkout(
+    work: &Work,
+    source: &synchronik::Source,
+    log_file: &std::fs::File,
+    processes: &Processes,
+) -> TaskState {
+    use crate::caps::Capability;
+
+    let git = match caps::Git::has_capability() {
+        Some(git) => git,
+        None => {
+            error!("Task {} needs git to check out its source", work.task);
+            mark(log_file, "Unable to check out the source without git");
+            return TaskState::Failed;
+        }
+    };
+
+    info!(
+        "Checking out {} of {} for {}",
+        source.sha, source.url, work.task
+    );
+    let mut env = HashMap::from([
+        ("SYNCHRONIK_GIT".into(), git.path().to_string_lossy().into()),
+        ("SYNCHRONIK_SOURCE_URL".into(), source.url.clone()),
+        ("SYNCHRONIK_SOURCE_SHA".into(), source.sha.clone()),
+    ]);
+    /*
+     * The token is handed to git by a credential helper reading it from the environment,
+     * which keeps it out of the traced commands in the log and out of the git config of
+     * persistent workspaces
+     */
+    if let Some(token) = &source.token {
+        env.extend([
+            ("SYNCHRONIK_SOURCE_TOKEN".into(), token.clone()),
+            ("GIT_CONFIG_COUNT".into(), "1".into()),
+            ("GIT_CONFIG_KEY_0".into(), "credential.helper".into()),
+            ("GIT_CONFIG_VALUE_0".into(), CREDENTIAL_HELPER.into()),
+        ]);
+    }
+    let limit = work.command.timeout.map(Duration::from_secs);
+
+    match run_script(work, CHECKOUT_SCRIPT, env, limit, log_file, processes).await {
+        Some(0) => TaskState::Succeeded,
+        _ => {
+            mark(log_file, "Failed to check out the source");
+            processes.stopped(&work.task).unwrap_or(TaskState::Failed)
+        }
+    }
+}
+
+const CREDENTIAL_HELPER: &str =
+    r#"!f() { echo username=x-access-token; echo "password=$SYNCHRONIK_SOURCE_TOKEN"; }; f"#;
+
+/*
+ * Fetching the single commit is much cheaper, but not every server allows asking for a
+ * commit which is not the tip of a ref, in which case everything is fetched
+ */
+const CHECKOUT_SCRIPT: &str = r#"
+"$SYNCHRONIK_GIT" init --quiet
+"$SYNCHRONIK_GIT" remote add origin "$SYNCHRONIK_SOURCE_URL" 2>/dev/null || "$SYNCHRONIK_GIT" remote set-url origin "$SYNCHRONIK_SOURCE_URL"
+"$SYNCHRONIK_GIT" fetch --quiet --depth 1 origin "$SYNCHRONIK_SOURCE_SHA" || "$SYNCHRONIK_GIT" fetch --quiet origin
+"$SYNCHRONIK_GIT" -c advice.detachedHead=false checkout --quiet --force "$SYNCHRONIK_SOURCE_SHA"
+"#;
+
+/*
+ * Write a marker into the console log so that readers of the log can tell why the task
+ * ended
+ */
+fn mark(mut log_file: &std::fs::File, message: &str) {
+    use std::io::Write;
+
+    if let Err(e) = writeln!(log_file, "\n*** {} ***", message) {
+        error!("Failed to write to the console log: {:?}", e);
+    }
+}
+
+/*
+ * Push the final status of the task to the callback URL provided with the request
+ */
+async fn report(callback: &url::Url, status: &TaskStatus) {
+    debug!("Reporting {:?} to {}", status, callback);
+    let client = reqwest::Client::new();
+    match client.put(callback.clone()).json(status).send().await {
+        Ok(res) if res.status().is_success() => {}
+        Ok(res) => warn!("Callback {} responded with {}", callback, res.status()),
+        Err(e) => error!("Failed to report task status to {}: {:?}", callback, e),
+    }
+}
+
+/*
+ * The worker function just does a busy loop executing Work
+ */
+async fn worker(
+    receiver: Receiver<Work>,
+    tasks: Tasks,
+    processes: Processes,
+    workspaces: Workspaces,
+) {
+    debug!("Worker thread starting");
+
+    while let Ok(work) = receiver.recv().await {
+        let status = execute(&work, &tasks, &processes).await;
+        workspaces.clean(&work.workspace, work.command.workspace.is_some());
+
+        if let (Some(callback), Some(status)) = (&work.command.callback, status) {
+            report(callback, &status).await;
         }
     }
 }
 
+#[derive(Debug, Options)]
+struct AgentOptions {
+    #[options(help = "print help message")]
+    help: bool,
+    #[options(
+        help = "Directory to create the workspaces of tasks in",
+        default = "agent-workspaces"
+    )]
+    workspaces: PathBuf,
+    #[options(help = "Keep the workspaces of finished tasks instead of removing them")]
+    keep_workspaces: bool,
+}
+
 #[async_std::main]
 async fn main() -> Result<(), tide::Error> {
     pretty_env_logger::init();
     dotenv().ok();
+    let opts = AgentOptions::parse_args_default_or_exit();
+    debug!("Starting with options: {:?}", opts);
+
+    /*
+     * Workspaces are referred to by absolute path so that commands can rely on
+     * SYNCHRONIK_WORKSPACE wherever they change directory to
+     */
+    std::fs::create_dir_all(&opts.workspaces).expect("Failed to create workspaces directory");
+    let workspaces = Workspaces::new(opts.workspaces.canonicalize()?, opts.keep_workspaces);
+
     let (sender, receiver) = bounded(1);
-    async_std::task::spawn(worker(receiver));
+    let tasks = Tasks::default();
+    let processes = Processes::default();
+    async_std::task::spawn(worker(
+        receiver,
+        tasks.clone(),
+        processes.clone(),
+        workspaces.clone(),
+    ));
 
-    let state = State { channel: sender };
+    let state = State {
+        channel: sender,
+        tasks,
+        processes,
+        workspaces,
+    };
     let mut app = tide::with_state(state);
 
     #[cfg(not(debug_assertions))]
@@ -179,3 +756,257 @@ async fn main() -> Result<(), tide::Error> {
     app.listen("0.0.0.0:9000").await?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use synchronik::Command;
+
+    async fn execute_scripts(scripts: &[&str]) -> TaskStatus {
+        execute_with(scripts, &Processes::default(), Uuid::new_v4()).await
+    }
+
+    async fn execute_with(scripts: &[&str], processes: &Processes, task: Uuid) -> TaskStatus {
+        let commands: Vec<Command> = scripts.iter().map(|s| Command::with_script(s)).collect();
+        execute_commands(commands, None, processes, task).await
+    }
+
+    async fn execute_commands(
+        commands: Vec<Command>,
+        timeout: Option<u64>,
+        processes: &Processes,
+        task: Uuid,
+    ) -> TaskStatus {
+        let request = CommandRequest {
+            commands,
+            callback: None,
+            timeout,
+            env: HashMap::from([("GREETING".to_string(), "hello".to_string())]),
+            workspace: None,
+            source: None,
+        };
+        execute_request(request, processes, task).await
+    }
+
+    async fn execute_request(
+        request: CommandRequest,
+        processes: &Processes,
+        task: Uuid,
+    ) -> TaskStatus {
+        let tasks = Tasks::default();
+        track_task(
+            &mut *tasks.write().await,
+            TaskStatus::new(task, &request.commands),
+        );
+
+        let work = Work {
+            task,
+            log_file: std::env::temp_dir().join(format!("{}.log", task)),
+            workspace: std::env::temp_dir().join(format!("workspace-{}", task)),
+            command: request,
+        };
+        let status = execute(&work, &tasks, processes)
+            .await
+            .expect("No status for the task");
+        let _ = std::fs::remove_file(&work.log_file);
+        let _ = std::fs::remove_dir_all(&work.workspace);
+        status
+    }
+
+    #[async_std::test]
+    async fn test_execute_success() {
+        let status = execute_scripts(&["true", "echo hello"]).await;
+        assert_eq!(TaskState::Succeeded, status.state);
+        assert!(status.started_at.is_some());
+        assert!(status.finished_at >= status.started_at);
+        assert!(status
+            .commands
+            .iter()
+            .all(|c| c.state == TaskState::Succeeded && c.exit_code == Some(0)));
+    }
+
+    #[async_std::test]
+    async fn test_execute_env() {
+        let commands = vec![
+            Command::with_script(r#"test "$GREETING" = "hello""#),
+            Command {
+                env: HashMap::from([("GREETING".to_string(), "bonjour".to_string())]),
+                ..Command::with_script(r#"test "$GREETING" = "bonjour""#)
+            },
+        ];
+        let status = execute_commands(commands, None, &Processes::default(), Uuid::new_v4()).await;
+        assert_eq!(TaskState::Succeeded, status.state);
+    }
+
+    #[async_std::test]
+    async fn test_execute_in_workspace() {
+        let status = execute_scripts(&[
+            "touch created-in-workspace",
+            r#"test "$(pwd)" = "$SYNCHRONIK_WORKSPACE""#,
+            "test -f $SYNCHRONIK_WORKSPACE/created-in-workspace",
+        ])
+        .await;
+        assert_eq!(TaskState::Succeeded, status.state);
+    }
+
+    #[async_std::test]
+    async fn test_execute_stops_at_first_failure() {
+        let status = execute_scripts(&["true", "exit 3", "echo unreachable"]).await;
+        assert_eq!(TaskState::Failed, status.state);
+        assert_eq!(TaskState::Succeeded, status.commands[0].state);
+        assert_eq!(TaskState::Failed, status.commands[1].state);
+        assert_eq!(Some(3), status.commands[1].exit_code);
+        assert_eq!(TaskState::Pending, status.commands[2].state);
+        assert_eq!(None, status.commands[2].exit_code);
+    }
+
+    #[async_std::test]
+    async fn test_execute_cancelled_before_start() {
+        let task = Uuid::new_v4();
+        let processes = Processes::default();
+        processes.stop(task, TaskState::Cancelled);
+
+        let status = execute_with(&["echo unreachable"], &processes, task).await;
+        assert_eq!(TaskState::Cancelled, status.state);
+        assert_eq!(TaskState::Pending, status.commands[0].state);
+        assert_eq!(None, processes.stopped(&task));
+    }
+
+    #[async_std::test]
+    async fn test_execute_cancel_running_command() {
+        let task = Uuid::new_v4();
+        let processes = Processes::default();
+
+        let canceller = processes.clone();
+        async_std::task::spawn(async move {
+            async_std::task::sleep(std::time::Duration::from_millis(500)).await;
+            canceller.stop(task, TaskState::Cancelled);
+        });
+
+        let started = std::time::Instant::now();
+        let status = execute_with(
+            &["sleep 30 & sleep 30", "echo unreachable"],
+            &processes,
+            task,
+        )
+        .await;
+        assert!(started.elapsed() < std::time::Duration::from_secs(10));
+        assert_eq!(TaskState::Cancelled, status.state);
+        assert_eq!(TaskState::Cancelled, status.commands[0].state);
+        assert_eq!(None, status.commands[0].exit_code);
+        assert_eq!(TaskState::Pending, status.commands[1].state);
+    }
+
+    #[async_std::test]
+    async fn test_execute_command_timeout() {
+        let commands = vec![
+            Command {
+                timeout: Some(1),
+                ..Command::with_script("sleep 30")
+            },
+            Command::with_script("echo unreachable"),
+        ];
+
+        let started = std::time::Instant::now();
+        let status = execute_commands(commands, None, &Processes::default(), Uuid::new_v4()).await;
+        assert!(started.elapsed() < Duration::from_secs(10));
+        assert_eq!(TaskState::TimedOut, status.state);
+        assert_eq!(TaskState::TimedOut, status.commands[0].state);
+        assert_eq!(TaskState::Pending, status.commands[1].state);
+    }
+
+    #[async_std::test]
+    async fn test_execute_task_timeout() {
+        let commands = vec![
+            Command::with_script("true"),
+            Command::with_script("sleep 30"),
+        ];
+
+        let started = std::time::Instant::now();
+        let status =
+            execute_commands(commands, Some(1), &Processes::default(), Uuid::new_v4()).await;
+        assert!(started.elapsed() < Duration::from_secs(10));
+        assert_eq!(TaskState::TimedOut, status.state);
+        assert_eq!(TaskState::Succeeded, status.commands[0].state);
+        assert_eq!(TaskState::TimedOut, status.commands[1].state);
+    }
+
+    #[test]
+    fn test_take_complete_utf8() {
+        let text = "größer → 🦀";
+        let bytes = text.as_bytes();
+
+        /*
+         * However the log is split between reads, the characters come out whole
+         */
+        for split in 0..=bytes.len() {
+            let mut buf = bytes[..split].to_vec();
+            let mut streamed = take_complete_utf8(&mut buf);
+            buf.extend_from_slice(&bytes[split..]);
+            streamed.push_str(&take_complete_utf8(&mut buf));
+            assert_eq!(text, streamed, "split at {}", split);
+            assert!(buf.is_empty());
+        }
+
+        let mut buf = b"ok \xFF\xF0\x9F".to_vec();
+        assert_eq!("ok \u{FFFD}", take_complete_utf8(&mut buf));
+        assert_eq!(b"\xF0\x9F".to_vec(), buf);
+    }
+
+    #[test]
+    fn test_track_task_discards_oldest_finished() {
+        let mut tasks = HashMap::new();
+        let mut first = None;
+
+        for _ in 0..MAX_TASKS {
+            let mut status = TaskStatus::new(Uuid::new_v4(), &[]);
+            status.state = TaskState::Succeeded;
+            first.get_or_insert(status.uuid);
+            track_task(&mut tasks, status);
+        }
+        assert_eq!(MAX_TASKS, tasks.len());
+
+        let pending = TaskStatus::new(Uuid::new_v4(), &[]);
+        let pending_uuid = pending.uuid;
+        track_task(&mut tasks, pending);
+
+        assert_eq!(MAX_TASKS, tasks.len());
+        assert!(tasks.contains_key(&pending_uuid));
+        assert!(!tasks.contains_key(&first.unwrap()));
+    }
+
+    #[async_std::test]
+    async fn test_execute_checks_out_source() {
+        use crate::caps::Capability;
+
+        if crate::caps::Git::has_capability().is_none() {
+            return;
+        }
+        let repo = crate::testing::repository("source");
+        let first = crate::testing::commit(&repo, "content", "first");
+        crate::testing::commit(&repo, "content", "second");
+
+        let request = CommandRequest {
+            commands: vec![Command::with_script("test \"$(cat content)\" = first")],
+            callback: None,
+            timeout: None,
+            env: HashMap::new(),
+            workspace: None,
+            source: Some(synchronik::Source {
+                url: format!("file://{}", repo.display()),
+                sha: first,
+                token: None,
+            }),
+        };
+        let status = execute_request(request.clone(), &Processes::default(), Uuid::new_v4()).await;
+        assert_eq!(TaskState::Succeeded, status.state);
+
+        let mut missing = request;
+        missing.source.as_mut().unwrap().sha = "0".repeat(40);
+        let status = execute_request(missing, &Processes::default(), Uuid::new_v4()).await;
+        assert_eq!(TaskState::Failed, status.state);
+        assert_eq!(TaskState::Pending, status.commands[0].state);
+
+        let _ = std::fs::remove_dir_all(&repo);
+    }
+}
@@ -0,0 +1,124 @@
+/*
+ * The processes module keeps track of the commands which are currently executing so
+ * that their tasks can be cancelled or timed out
+ */
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use log::*;
+use synchronik::TaskState;
+use uuid::Uuid;
+
+/*
+ * How long a stopped command is given to exit after SIGTERM before it is sent SIGKILL
+ */
+pub const GRACE_PERIOD: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Default)]
+struct Inner {
+    // Process group of the command currently executing for each task
+    running: HashMap<Uuid, u32>,
+    // Tasks which have been asked to stop, along with the state they should end up in
+    stopped: HashMap<Uuid, TaskState>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Processes {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl Processes {
+    fn lock(&self) -> std::sync::MutexGuard<'_, Inner> {
+        self.inner.lock().expect("Failed to lock the processes")
+    }
+
+    /*
+     * Record the process group of the command which has started for the task. If the
+     * task was stopped while the command was being spawned it is terminated right away
+     */
+    pub fn started(&self, task: Uuid, pgid: u32) {
+        let stopped = {
+            let mut inner = self.lock();
+            inner.running.insert(task, pgid);
+            inner.stopped.contains_key(&task)
+        };
+
+        if stopped {
+            self.terminate(task, pgid);
+        }
+    }
+
+    /*
+     * Forget the command of the task once it has exited
+     */
+    pub fn exited(&self, task: &Uuid) {
+        self.lock().running.remove(task);
+    }
+
+    /*
+     * Ask the task to stop, terminating the command it is currently executing. The state
+     * records why the task was stopped, the first reason given wins
+     */
+    pub fn stop(&self, task: Uuid, state: TaskState) {
+        let running = {
+            let mut inner = self.lock();
+            inner.stopped.entry(task).or_insert(state);
+            inner.running.get(&task).copied()
+        };
+
+        if let Some(pgid) = running {
+            self.terminate(task, pgid);
+        }
+    }
+
+    /*
+     * The state the task should end up in if it has been asked to stop
+     */
+    pub fn stopped(&self, task: &Uuid) -> Option<TaskState> {
+        self.lock().stopped.get(task).copied()
+    }
+
+    /*
+     * Stop tracking the task entirely once it has finished
+     */
+    pub fn finished(&self, task: &Uuid) {
+        let mut inner = self.lock();
+        inner.running.remove(task);
+        inner.stopped.remove(task);
+    }
+
+    /*
+     * Send SIGTERM to the whole process group, following up with SIGKILL if the command
+     * is still running once the grace period has passed
+     */
+    fn terminate(&self, task: Uuid, pgid: u32) {
+        info!("Terminating process group {} for task {}", pgid, task);
+        signal(pgid, libc::SIGTERM);
+
+        let processes = self.clone();
+        async_std::task::spawn(async move {
+            async_std::task::sleep(GRACE_PERIOD).await;
+            /*
+             * Only signal the group if it still belongs to the same command, otherwise the
+             * process group id may have been reused
+             */
+            if processes.lock().running.get(&task) == Some(&pgid) {
+                warn!("Process group {} did not exit, killing it", pgid);
+                signal(pgid, libc::SIGKILL);
+            }
+        });
+    }
+}
+
+fn signal(pgid: u32, signal: libc::c_int) {
+    // A negative pid signals every process in the group
+    let result = unsafe { libc::kill(-(pgid as libc::pid_t), signal) };
+    if result != 0 {
+        debug!(
+            "Failed to signal process group {}: {:?}",
+            pgid,
+            std::io::Error::last_os_error()
+        );
+    }
+}
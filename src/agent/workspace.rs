@@ -0,0 +1,118 @@
+/*
+ * The workspace module manages the directories which tasks execute their commands in
+ */
+use std::path::{Path, PathBuf};
+
+use log::*;
+use uuid::Uuid;
+
+#[derive(Clone, Debug)]
+pub struct Workspaces {
+    root: PathBuf,
+    // Keep the workspaces of finished tasks around rather than removing them
+    keep: bool,
+}
+
+impl Workspaces {
+    pub fn new(root: PathBuf, keep: bool) -> Self {
+        Self { root, keep }
+    }
+
+    /*
+     * Compute the workspace for the task. Persistent workspaces are shared by every task
+     * which names them, which allows incremental builds
+     */
+    pub fn path_for(&self, task: &Uuid, persistent: Option<&str>) -> PathBuf {
+        match persistent {
+            Some(name) => self.root.join("persistent").join(sanitize(name)),
+            None => self.root.join(task.hyphenated().to_string()),
+        }
+    }
+
+    /*
+     * Remove the workspace of a finished task, unless it is persistent or workspaces are
+     * being kept
+     */
+    pub fn clean(&self, path: &Path, persistent: bool) {
+        if persistent || self.keep {
+            debug!("Keeping the workspace {:?}", path);
+            return;
+        }
+
+        debug!("Removing the workspace {:?}", path);
+        if let Err(e) = std::fs::remove_dir_all(path) {
+            error!("Failed to remove the workspace {:?}: {:?}", path, e);
+        }
+    }
+}
+
+/*
+ * Make the name of a persistent workspace safe to use as a single directory name
+ */
+fn sanitize(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' | '.' => c,
+            _ => '_',
+        })
+        .collect();
+
+    match name.trim_matches('.') {
+        "" => "_".into(),
+        _ => name,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_path_for_task() {
+        let workspaces = Workspaces::new(PathBuf::from("/work"), false);
+        let task = Uuid::new_v4();
+        assert_eq!(
+            PathBuf::from(format!("/work/{}", task)),
+            workspaces.path_for(&task, None)
+        );
+    }
+
+    #[test]
+    fn test_path_for_persistent() {
+        let workspaces = Workspaces::new(PathBuf::from("/work"), false);
+        let task = Uuid::new_v4();
+        assert_eq!(
+            PathBuf::from("/work/persistent/synchronik"),
+            workspaces.path_for(&task, Some("synchronik"))
+        );
+        assert_eq!(
+            PathBuf::from("/work/persistent/.._etc"),
+            workspaces.path_for(&task, Some("../etc"))
+        );
+        assert_eq!(
+            PathBuf::from("/work/persistent/_"),
+            workspaces.path_for(&task, Some(".."))
+        );
+    }
+
+    #[test]
+    fn test_clean() {
+        let root = std::env::temp_dir().join(format!("workspaces-{}", Uuid::new_v4()));
+        let task = Uuid::new_v4();
+
+        let kept = Workspaces::new(root.clone(), true);
+        let path = kept.path_for(&task, None);
+        std::fs::create_dir_all(&path).unwrap();
+        kept.clean(&path, false);
+        assert!(path.exists());
+
+        let workspaces = Workspaces::new(root.clone(), false);
+        workspaces.clean(&path, true);
+        assert!(path.exists());
+        workspaces.clean(&path, false);
+        assert!(!path.exists());
+
+        let _ = std::fs::remove_dir_all(&root);
+    }
+}
@@ -1,5 +1,7 @@
+use std::collections::HashMap;
 use std::path::PathBuf;
 
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use url::Url;
 use uuid::Uuid;
@@ -22,7 +24,7 @@ impl Capability {
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
-struct CapsRequest {}
+pub struct CapsRequest {}
 
 #[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
 pub struct CapsResponse {
@@ -32,12 +34,20 @@ pub struct CapsResponse {
 #[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
 pub struct Command {
     pub script: String,
+    // Number of seconds the command may take before the agent stops it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<u64>,
+    // Environment variables for this command, taking precedence over those of the request
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub env: HashMap<String, String>,
 }
 
 impl Command {
     pub fn with_script(script: &str) -> Self {
         Self {
             script: script.into(),
+            timeout: None,
+            env: HashMap::new(),
         }
     }
 }
@@ -45,6 +55,53 @@ impl Command {
 #[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
 pub struct CommandRequest {
     pub commands: Vec<Command>,
+    /*
+     * URL which the agent should PUT the final TaskStatus to once the commands have
+     * finished executing
+     */
+    #[serde(default)]
+    pub callback: Option<Url>,
+    // Number of seconds all of the commands together may take before the agent stops them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<u64>,
+    // Environment variables for every command in the request
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub env: HashMap<String, String>,
+    /*
+     * Name of a persistent workspace which is kept between the tasks naming it, without
+     * one the commands execute in a fresh workspace
+     */
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub workspace: Option<String>,
+    /*
+     * Repository to check out into the workspace before the commands are executed
+     */
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<Source>,
+}
+
+#[derive(Clone, Deserialize, Serialize, PartialEq, Eq)]
+pub struct Source {
+    // URL which the agent can clone the repository from
+    pub url: String,
+    // Exact commit which should be checked out
+    pub sha: String,
+    // Token granting read access to the repository over HTTPS
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+}
+
+/*
+ * Requests are logged, so the token is kept out of the debug representation
+ */
+impl std::fmt::Debug for Source {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Source")
+            .field("url", &self.url)
+            .field("sha", &self.sha)
+            .field("token", &self.token.as_ref().map(|_| "<redacted>"))
+            .finish()
+    }
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
@@ -55,5 +112,364 @@ pub struct CommandResponse {
     pub log: Url,
 }
 
+/*
+ * The TaskState represents the lifecycle of a task, or any individual command within it
+ */
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TaskState {
+    Pending,
+    Running,
+    Succeeded,
+    Failed,
+    Cancelled,
+    #[serde(rename = "timed_out")]
+    TimedOut,
+}
+
+impl TaskState {
+    /*
+     * Whether the state is terminal and will no longer change
+     */
+    pub fn is_finished(&self) -> bool {
+        matches!(
+            self,
+            TaskState::Succeeded | TaskState::Failed | TaskState::Cancelled | TaskState::TimedOut
+        )
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct CommandStatus {
+    pub script: String,
+    pub state: TaskState,
+    // Exit code of the command, None until it has completed or if it was killed by a signal
+    pub exit_code: Option<i32>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct TaskStatus {
+    pub uuid: Uuid,
+    pub state: TaskState,
+    pub commands: Vec<CommandStatus>,
+    // URL to the raw log of the task run
+    pub log: Option<Url>,
+    pub created_at: DateTime<Utc>,
+    pub started_at: Option<DateTime<Utc>>,
+    pub finished_at: Option<DateTime<Utc>>,
+}
+
+impl TaskStatus {
+    /*
+     * Create a pending TaskStatus for the given commands
+     */
+    pub fn new(uuid: Uuid, commands: &[Command]) -> Self {
+        Self {
+            uuid,
+            state: TaskState::Pending,
+            commands: commands
+                .iter()
+                .map(|c| CommandStatus {
+                    script: c.script.clone(),
+                    state: TaskState::Pending,
+                    exit_code: None,
+                })
+                .collect(),
+            log: None,
+            created_at: Utc::now(),
+            started_at: None,
+            finished_at: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct TasksResponse {
+    pub tasks: Vec<TaskStatus>,
+}
+
+/*
+ * The RunStatus represents the lifecycle of a Run on the server
+ */
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RunStatus {
+    // The Run is waiting for an agent to become available
+    Queued,
+    // The Run has been accepted by an agent which has not yet started it
+    Pending,
+    // An agent has accepted the Run and is executing it
+    Running,
+    Succeeded,
+    Failed,
+    // The Run was cancelled before it finished
+    Cancelled,
+    // The Run took longer than its timeout allowed
+    #[serde(rename = "timed_out")]
+    TimedOut,
+    // The condition of a step of the Run was not met, so it was never executed
+    Skipped,
+}
+
+impl RunStatus {
+    /*
+     * Whether the status is terminal and the Run will no longer change
+     */
+    pub fn is_finished(&self) -> bool {
+        matches!(
+            self,
+            RunStatus::Succeeded
+                | RunStatus::Failed
+                | RunStatus::Cancelled
+                | RunStatus::TimedOut
+                | RunStatus::Skipped
+        )
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RunStatus::Queued => "queued",
+            RunStatus::Pending => "pending",
+            RunStatus::Running => "running",
+            RunStatus::Succeeded => "succeeded",
+            RunStatus::Failed => "failed",
+            RunStatus::Cancelled => "cancelled",
+            RunStatus::TimedOut => "timed_out",
+            RunStatus::Skipped => "skipped",
+        }
+    }
+}
+
+impl From<TaskState> for RunStatus {
+    fn from(state: TaskState) -> Self {
+        match state {
+            TaskState::Pending => RunStatus::Pending,
+            TaskState::Running => RunStatus::Running,
+            TaskState::Succeeded => RunStatus::Succeeded,
+            TaskState::Failed => RunStatus::Failed,
+            TaskState::Cancelled => RunStatus::Cancelled,
+            TaskState::TimedOut => RunStatus::TimedOut,
+        }
+    }
+}
+
+impl std::fmt::Display for RunStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl std::str::FromStr for RunStatus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "queued" => Ok(RunStatus::Queued),
+            "pending" => Ok(RunStatus::Pending),
+            "running" => Ok(RunStatus::Running),
+            "succeeded" => Ok(RunStatus::Succeeded),
+            "failed" => Ok(RunStatus::Failed),
+            "cancelled" => Ok(RunStatus::Cancelled),
+            "timed_out" => Ok(RunStatus::TimedOut),
+            "skipped" => Ok(RunStatus::Skipped),
+            other => Err(format!("Unknown run status: {}", other)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct ProjectResponse {
+    pub name: String,
+    pub description: String,
+    pub labels: Vec<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct ProjectsResponse {
+    pub projects: Vec<ProjectResponse>,
+}
+
+/*
+ * The source control information a Run was created from
+ */
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct RunScm {
+    pub git_url: String,
+    pub r#ref: String,
+    // Commit the ref resolved to when the Run was created
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct RunResponse {
+    pub uuid: Uuid,
+    // Number of the Run within its project
+    pub num: i64,
+    // Name of the project the Run belongs to
+    pub project: String,
+    pub status: RunStatus,
+    // What caused the Run to be created
+    pub trigger: String,
+    // Who caused the Run to be created, when that is known
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sender: Option<String>,
+    // Name of the agent the Run was dispatched to
+    pub agent: Option<String>,
+    pub scm: RunScm,
+    // The Yml definition the Run was created with
+    pub definition: String,
+    // URL to the raw log of the Run, known once an agent has accepted it
+    pub log: Option<Url>,
+    pub created_at: DateTime<Utc>,
+    pub started_at: Option<DateTime<Utc>>,
+    pub finished_at: Option<DateTime<Utc>>,
+    // Values of the parameters the Run was triggered with
+    pub parameters: HashMap<String, String>,
+    // The steps of the Run in the order they are defined in, empty until it is dispatched
+    #[serde(default)]
+    pub steps: Vec<StepResponse>,
+}
+
+/*
+ * The part of a Run which an agent executes, of which a Run has one per step of its stages
+ */
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct StepResponse {
+    pub uuid: Uuid,
+    // Name of the stage the step belongs to
+    pub stage: String,
+    pub name: String,
+    pub status: RunStatus,
+    // Name of the agent the step was dispatched to
+    pub agent: Option<String>,
+    // URL to the raw log of the step, known once an agent has accepted it
+    pub log: Option<Url>,
+    pub started_at: Option<DateTime<Utc>>,
+    pub finished_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct RunsResponse {
+    pub runs: Vec<RunResponse>,
+}
+
+/*
+ * Returned when a Run has been triggered, giving the caller a handle on the Run
+ */
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct TriggerResponse {
+    pub uuid: Uuid,
+    pub num: i64,
+    pub project: String,
+    pub status: RunStatus,
+    // Name of the agent the Run was dispatched to, None while it is still queued
+    pub agent: Option<String>,
+    // URL to the Run in the API
+    pub run: Url,
+    // URL to the Run's page in the web UI
+    pub page: Url,
+    // URL to the raw log of the Run, known once an agent has accepted it
+    pub log: Option<Url>,
+    /*
+     * WebSockets URL for streaming the log of the Run through the server, None when the
+     * Run has more than one step as each step is streamed on its own from
+     * /api/v1/runs/{uuid}/steps/{step}/stream
+     */
+    #[serde(default)]
+    pub stream: Option<Url>,
+}
+
+/*
+ * The projects a webhook matched, whose Runs are enqueued once their definitions have been
+ * resolved
+ */
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct HookResponse {
+    pub projects: Vec<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct AgentResponse {
+    pub name: String,
+    pub url: Url,
+    pub capabilities: Vec<Capability>,
+    pub labels: Vec<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct AgentsResponse {
+    pub agents: Vec<AgentResponse>,
+}
+
 #[cfg(test)]
-mod tests {}
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_task_status_new() {
+        let commands = vec![Command::with_script("true"), Command::with_script("false")];
+        let status = TaskStatus::new(Uuid::new_v4(), &commands);
+        assert_eq!(TaskState::Pending, status.state);
+        assert_eq!(2, status.commands.len());
+        assert!(status
+            .commands
+            .iter()
+            .all(|c| c.state == TaskState::Pending && c.exit_code.is_none()));
+        assert!(status.started_at.is_none());
+        assert!(status.finished_at.is_none());
+    }
+
+    #[test]
+    fn test_source_debug_redacts_token() {
+        let source = Source {
+            url: "https://github.com/rtyler/synchronik.git".into(),
+            sha: "0123abcd".into(),
+            token: Some("ghp_secret".into()),
+        };
+        assert!(!format!("{:?}", source).contains("ghp_secret"));
+    }
+
+    #[test]
+    fn test_task_state_is_finished() {
+        assert!(!TaskState::Pending.is_finished());
+        assert!(!TaskState::Running.is_finished());
+        assert!(TaskState::Succeeded.is_finished());
+        assert!(TaskState::Failed.is_finished());
+        assert!(TaskState::Cancelled.is_finished());
+        assert!(TaskState::TimedOut.is_finished());
+    }
+
+    #[test]
+    fn test_run_status_round_trip() {
+        for status in [
+            RunStatus::Queued,
+            RunStatus::Pending,
+            RunStatus::Running,
+            RunStatus::Succeeded,
+            RunStatus::Failed,
+            RunStatus::Cancelled,
+            RunStatus::TimedOut,
+            RunStatus::Skipped,
+        ] {
+            assert_eq!(Ok(status), status.as_str().parse());
+            assert_eq!(
+                format!("\"{}\"", status),
+                serde_json::to_string(&status).unwrap()
+            );
+        }
+        assert!("bogus".parse::<RunStatus>().is_err());
+    }
+
+    #[test]
+    fn test_command_request_without_callback() {
+        let request: CommandRequest =
+            serde_json::from_str(r#"{"commands":[{"script":"whoami"}]}"#).unwrap();
+        assert_eq!(None, request.callback);
+        assert_eq!(None, request.timeout);
+        assert_eq!(None, request.commands[0].timeout);
+        assert!(request.env.is_empty());
+        assert!(request.commands[0].env.is_empty());
+    }
+}
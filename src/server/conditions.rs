@@ -0,0 +1,576 @@
+/**
+ * The conditions module evaluates the `if` expressions which decide whether a step is
+ * executed. Expressions compare the ref, trigger, parameters and environment of a Run with
+ * strings or globs, and combine the results with `&&`, `||`, `!` and parentheses:
+ *
+ *   branch =~ 'release-*' && trigger != 'schedule'
+ *
+ * The status functions success(), failure() and always() let steps such as cleanups and
+ * notifications execute once another step of the Run has failed, or a step they depend on
+ * was skipped. A step whose condition doesn't call any of them is only executed while the
+ * Run is succeeding and none of the steps it depends on were skipped.
+ */
+use std::collections::HashMap;
+
+/*
+ * What a condition is evaluated against
+ */
+pub struct Context<'a> {
+    // The ref the Run was created from, such as refs/heads/main or just main
+    pub r#ref: &'a str,
+    // What created the Run: manual, push, pull_request, webhook, poll or schedule
+    pub trigger: &'a str,
+    pub parameters: &'a HashMap<String, String>,
+    // The environment the step would be executed with
+    pub env: &'a HashMap<String, String>,
+    // Whether another step of the Run has failed
+    pub failed: bool,
+    // Whether a step this one depends on was skipped
+    pub skipped: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Expression {
+    Literal(String),
+    Variable(Variable),
+    Status(Status),
+    Not(Box<Expression>),
+    And(Box<Expression>, Box<Expression>),
+    Or(Box<Expression>, Box<Expression>),
+    Compare(Box<Expression>, Operator, Box<Expression>),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Variable {
+    Ref,
+    // The ref without refs/heads/, empty for tags and pull requests
+    Branch,
+    // The ref without refs/tags/, empty for anything else
+    Tag,
+    Trigger,
+    Parameter(String),
+    Env(String),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Status {
+    // No step of the Run has failed
+    Success,
+    // A step of the Run has failed
+    Failure,
+    Always,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Operator {
+    Equal,
+    NotEqual,
+    // The left side matches the glob on the right
+    Matches,
+    NotMatches,
+}
+
+impl Expression {
+    /*
+     * Whether the expression holds, any non-empty value other than false being true
+     */
+    pub fn evaluate(&self, context: &Context) -> bool {
+        truthy(&self.value(context))
+    }
+
+    /*
+     * Whether the expression calls one of the status functions
+     */
+    pub fn checks_status(&self) -> bool {
+        match self {
+            Expression::Status(_) => true,
+            Expression::Literal(_) | Expression::Variable(_) => false,
+            Expression::Not(e) => e.checks_status(),
+            Expression::And(l, r) | Expression::Or(l, r) | Expression::Compare(l, _, r) => {
+                l.checks_status() || r.checks_status()
+            }
+        }
+    }
+
+    fn value(&self, context: &Context) -> String {
+        match self {
+            Expression::Literal(text) => text.clone(),
+            Expression::Variable(variable) => variable.value(context),
+            Expression::Status(status) => {
+                let holds = match status {
+                    Status::Success => !context.failed,
+                    Status::Failure => context.failed,
+                    Status::Always => true,
+                };
+                holds.to_string()
+            }
+            Expression::Not(e) => (!e.evaluate(context)).to_string(),
+            Expression::And(l, r) => (l.evaluate(context) && r.evaluate(context)).to_string(),
+            Expression::Or(l, r) => (l.evaluate(context) || r.evaluate(context)).to_string(),
+            Expression::Compare(l, operator, r) => {
+                let (l, r) = (l.value(context), r.value(context));
+                let holds = match operator {
+                    Operator::Equal => l == r,
+                    Operator::NotEqual => l != r,
+                    Operator::Matches => glob(&r, &l),
+                    Operator::NotMatches => !glob(&r, &l),
+                };
+                holds.to_string()
+            }
+        }
+    }
+}
+
+impl Variable {
+    fn value(&self, context: &Context) -> String {
+        let r#ref = context.r#ref;
+        match self {
+            Variable::Ref => r#ref.into(),
+            Variable::Branch => match r#ref.strip_prefix("refs/heads/") {
+                Some(branch) => branch.into(),
+                None if !r#ref.starts_with("refs/") => r#ref.into(),
+                None => "".into(),
+            },
+            Variable::Tag => r#ref.strip_prefix("refs/tags/").unwrap_or("").into(),
+            Variable::Trigger => context.trigger.into(),
+            Variable::Parameter(name) => context.parameters.get(name).cloned().unwrap_or_default(),
+            Variable::Env(name) => context.env.get(name).cloned().unwrap_or_default(),
+        }
+    }
+}
+
+fn truthy(value: &str) -> bool {
+    !value.is_empty() && value != "false"
+}
+
+/*
+ * Whether a step with the given condition should be executed, a condition without a
+ * status function only applying while the Run is succeeding and the steps it depends on
+ * have not been skipped
+ */
+pub fn should_run(condition: Option<&Expression>, context: &Context) -> bool {
+    match condition {
+        Some(condition) if condition.checks_status() => condition.evaluate(context),
+        Some(condition) => !context.failed && !context.skipped && condition.evaluate(context),
+        None => !context.failed && !context.skipped,
+    }
+}
+
+/*
+ * Match the text against the glob, where * matches any number of characters, including
+ * slashes, and ? matches exactly one
+ */
+pub fn glob(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    /*
+     * Positions just after the last * and in the text it was tried at, to resume from
+     * with the * matching one more character when the rest of the pattern fails
+     */
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                p += 1;
+                backtrack = Some((p, t));
+            }
+            Some(c) if *c == '?' || *c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                Some((after, at)) => {
+                    p = after;
+                    t = at + 1;
+                    backtrack = Some((after, at + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Token {
+    Name(String),
+    Text(String),
+    Open,
+    Close,
+    Not,
+    And,
+    Or,
+    Operator(Operator),
+}
+
+impl std::fmt::Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Token::Name(name) => write!(f, "{}", name),
+            Token::Text(text) => write!(f, "'{}'", text),
+            Token::Open => write!(f, "("),
+            Token::Close => write!(f, ")"),
+            Token::Not => write!(f, "!"),
+            Token::And => write!(f, "&&"),
+            Token::Or => write!(f, "||"),
+            Token::Operator(Operator::Equal) => write!(f, "=="),
+            Token::Operator(Operator::NotEqual) => write!(f, "!="),
+            Token::Operator(Operator::Matches) => write!(f, "=~"),
+            Token::Operator(Operator::NotMatches) => write!(f, "!~"),
+        }
+    }
+}
+
+fn tokenize(expression: &str) -> anyhow::Result<Vec<Token>> {
+    let mut tokens = vec![];
+    let mut chars = expression.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        let token = match c {
+            c if c.is_whitespace() => continue,
+            '(' => Token::Open,
+            ')' => Token::Close,
+            '\'' | '"' => {
+                let mut text = String::new();
+                loop {
+                    match chars.next() {
+                        Some(next) if next == c => break,
+                        Some(next) => text.push(next),
+                        None => {
+                            return Err(anyhow::anyhow!("The string {}{} is not closed", c, text))
+                        }
+                    }
+                }
+                Token::Text(text)
+            }
+            '&' | '|' if chars.peek() == Some(&c) => {
+                chars.next();
+                match c {
+                    '&' => Token::And,
+                    _ => Token::Or,
+                }
+            }
+            '=' | '!' => match (c, chars.peek()) {
+                (_, Some('=')) | (_, Some('~')) => {
+                    let equal = chars.next() == Some('=');
+                    Token::Operator(match (c, equal) {
+                        ('=', true) => Operator::Equal,
+                        ('=', false) => Operator::Matches,
+                        (_, true) => Operator::NotEqual,
+                        (_, false) => Operator::NotMatches,
+                    })
+                }
+                ('!', _) => Token::Not,
+                _ => return Err(anyhow::anyhow!("Expected == or =~ rather than =")),
+            },
+            c if c.is_ascii_alphanumeric() || c == '_' => {
+                let mut name = c.to_string();
+                while let Some(next) =
+                    chars.next_if(|n| n.is_ascii_alphanumeric() || *n == '_' || *n == '.')
+                {
+                    name.push(next);
+                }
+                Token::Name(name)
+            }
+            other => return Err(anyhow::anyhow!("Unexpected character {}", other)),
+        };
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+
+/*
+ * Parse the expression of a condition, from lowest to highest precedence:
+ *
+ *   or         = and ( '||' and )*
+ *   and        = not ( '&&' not )*
+ *   not        = '!' not | comparison
+ *   comparison = operand ( ( '==' | '!=' | '=~' | '!~' ) operand )?
+ *   operand    = string | variable | function '()' | '(' or ')'
+ */
+pub fn parse(expression: &str) -> anyhow::Result<Expression> {
+    let mut parser = Parser {
+        tokens: tokenize(expression)?,
+        position: 0,
+    };
+    if parser.tokens.is_empty() {
+        return Err(anyhow::anyhow!("The condition is empty"));
+    }
+    let parsed = parser.or()?;
+    match parser.next() {
+        Some(token) => Err(anyhow::anyhow!("Unexpected {} in the condition", token)),
+        None => Ok(parsed),
+    }
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn or(&mut self) -> anyhow::Result<Expression> {
+        let mut left = self.and()?;
+        while self.peek() == Some(&Token::Or) {
+            self.next();
+            left = Expression::Or(Box::new(left), Box::new(self.and()?));
+        }
+        Ok(left)
+    }
+
+    fn and(&mut self) -> anyhow::Result<Expression> {
+        let mut left = self.not()?;
+        while self.peek() == Some(&Token::And) {
+            self.next();
+            left = Expression::And(Box::new(left), Box::new(self.not()?));
+        }
+        Ok(left)
+    }
+
+    fn not(&mut self) -> anyhow::Result<Expression> {
+        if self.peek() == Some(&Token::Not) {
+            self.next();
+            return Ok(Expression::Not(Box::new(self.not()?)));
+        }
+        self.comparison()
+    }
+
+    fn comparison(&mut self) -> anyhow::Result<Expression> {
+        let left = self.operand()?;
+        let operator = match self.peek() {
+            Some(Token::Operator(operator)) => *operator,
+            _ => return Ok(left),
+        };
+        self.next();
+        let right = self.operand()?;
+        Ok(Expression::Compare(
+            Box::new(left),
+            operator,
+            Box::new(right),
+        ))
+    }
+
+    fn operand(&mut self) -> anyhow::Result<Expression> {
+        match self.next() {
+            Some(Token::Text(text)) => Ok(Expression::Literal(text)),
+            Some(Token::Open) => {
+                let inner = self.or()?;
+                match self.next() {
+                    Some(Token::Close) => Ok(inner),
+                    _ => Err(anyhow::anyhow!(
+                        "A parenthesis in the condition is not closed"
+                    )),
+                }
+            }
+            Some(Token::Name(name)) if self.peek() == Some(&Token::Open) => {
+                self.next();
+                if self.next() != Some(Token::Close) {
+                    return Err(anyhow::anyhow!(
+                        "The function {}() takes no arguments",
+                        name
+                    ));
+                }
+                let status = match name.as_str() {
+                    "success" => Status::Success,
+                    "failure" => Status::Failure,
+                    "always" => Status::Always,
+                    _ => return Err(anyhow::anyhow!("Unknown function {}()", name)),
+                };
+                Ok(Expression::Status(status))
+            }
+            Some(Token::Name(name)) => variable(&name).map(Expression::Variable),
+            Some(token) => Err(anyhow::anyhow!("Unexpected {} in the condition", token)),
+            None => Err(anyhow::anyhow!("The condition ends unexpectedly")),
+        }
+    }
+}
+
+fn variable(name: &str) -> anyhow::Result<Variable> {
+    let variable = match name {
+        "ref" => Variable::Ref,
+        "branch" => Variable::Branch,
+        "tag" => Variable::Tag,
+        "trigger" => Variable::Trigger,
+        _ => match name.split_once('.') {
+            Some(("params", parameter)) if !parameter.is_empty() => {
+                Variable::Parameter(parameter.into())
+            }
+            Some(("env", env)) if !env.is_empty() => Variable::Env(env.into()),
+            _ => return Err(anyhow::anyhow!("Unknown variable {}", name)),
+        },
+    };
+    Ok(variable)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn evaluate(expression: &str, r#ref: &str, trigger: &str, failed: bool) -> bool {
+        let parameters = HashMap::from([("DEPLOY".to_string(), "true".to_string())]);
+        let env = HashMap::from([("TARGET".to_string(), "production".to_string())]);
+        let context = Context {
+            r#ref,
+            trigger,
+            parameters: &parameters,
+            env: &env,
+            failed,
+            skipped: false,
+        };
+        parse(expression)
+            .expect("Failed to parse the condition")
+            .evaluate(&context)
+    }
+
+    #[test]
+    fn test_glob() {
+        assert!(glob("main", "main"));
+        assert!(!glob("main", "mainline"));
+        assert!(glob("release/*", "release/1.0"));
+        assert!(glob("release/*", "release/"));
+        assert!(!glob("release/*", "releases/1.0"));
+        assert!(glob("*-hotfix", "1.0-hotfix"));
+        assert!(glob("v?.*.*", "v1.2.3"));
+        assert!(!glob("v?.*", "v10.2"));
+        assert!(glob("*a*b", "xaxxab"));
+        assert!(glob("*", ""));
+    }
+
+    #[test]
+    fn test_variables() {
+        assert!(evaluate(
+            "branch == 'main'",
+            "refs/heads/main",
+            "push",
+            false
+        ));
+        assert!(evaluate("branch == 'main'", "main", "manual", false));
+        assert!(evaluate("branch == ''", "refs/tags/v1.0", "push", false));
+        assert!(evaluate("tag =~ 'v*'", "refs/tags/v1.0", "push", false));
+        assert!(evaluate(
+            "ref =~ 'refs/pull/*'",
+            "refs/pull/1/head",
+            "pull_request",
+            false
+        ));
+        assert!(evaluate("trigger == 'schedule'", "main", "schedule", false));
+        assert!(evaluate("params.DEPLOY", "main", "manual", false));
+        assert!(!evaluate("params.MISSING", "main", "manual", false));
+        assert!(evaluate("env.TARGET != 'staging'", "main", "manual", false));
+    }
+
+    #[test]
+    fn test_operators() {
+        assert!(evaluate(
+            "branch == 'main' && trigger == 'push'",
+            "main",
+            "push",
+            false
+        ));
+        assert!(!evaluate(
+            "branch == 'main' && trigger == 'push'",
+            "main",
+            "poll",
+            false
+        ));
+        assert!(evaluate(
+            "trigger == 'poll' || trigger == \"push\"",
+            "main",
+            "push",
+            false
+        ));
+        assert!(evaluate(
+            "!(branch !~ 'release/*')",
+            "release/2",
+            "push",
+            false
+        ));
+        assert!(
+            evaluate(
+                "trigger == 'x' || trigger == 'push' && branch == 'main'",
+                "main",
+                "push",
+                false
+            ),
+            "&& binds tighter than ||"
+        );
+        assert!(evaluate("!!params.DEPLOY", "main", "manual", false));
+    }
+
+    #[test]
+    fn test_status_functions() {
+        assert!(evaluate("failure()", "main", "push", true));
+        assert!(!evaluate("failure()", "main", "push", false));
+        assert!(evaluate("success()", "main", "push", false));
+        assert!(evaluate("always()", "main", "push", true));
+        assert!(parse("always() && branch == 'main'")
+            .unwrap()
+            .checks_status());
+        assert!(!parse("branch == 'main'").unwrap().checks_status());
+    }
+
+    #[test]
+    fn test_should_run() {
+        let empty = HashMap::new();
+        let context = |failed, skipped| Context {
+            r#ref: "refs/heads/main",
+            trigger: "push",
+            parameters: &empty,
+            env: &empty,
+            failed,
+            skipped,
+        };
+        let on_main = parse("branch == 'main'").unwrap();
+        let on_failure = parse("failure()").unwrap();
+        let always = parse("always()").unwrap();
+
+        assert!(should_run(None, &context(false, false)));
+        assert!(!should_run(None, &context(true, false)));
+        assert!(!should_run(None, &context(false, true)));
+        assert!(should_run(Some(&on_main), &context(false, false)));
+        assert!(
+            !should_run(Some(&on_main), &context(true, false)),
+            "Conditions without a status function require the run to be succeeding"
+        );
+        assert!(
+            !should_run(Some(&on_main), &context(false, true)),
+            "Conditions without a status function require their dependencies to execute"
+        );
+        assert!(!should_run(Some(&on_failure), &context(false, false)));
+        assert!(should_run(Some(&on_failure), &context(true, false)));
+        assert!(should_run(Some(&always), &context(false, true)));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        for invalid in [
+            "",
+            "branch ==",
+            "branch = 'main'",
+            "branch & trigger",
+            "(branch == 'main'",
+            "branch == 'main')",
+            "branch == 'main",
+            "unknown == 'main'",
+            "params. == 'x'",
+            "deploy()",
+            "failure(branch)",
+            "branch == 'main' trigger",
+            "branch == #",
+        ] {
+            assert!(parse(invalid).is_err(), "{} should be invalid", invalid);
+        }
+    }
+}
@@ -1,10 +1,12 @@
-use std::collections::HashMap;
-use std::path::PathBuf;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
 
 use log::*;
 use serde::{Deserialize, Serialize};
 use url::Url;
 
+use crate::conditions::{self, Expression};
+use crate::parameters::Parameter;
 use crate::AppState;
 
 /*
@@ -12,8 +14,757 @@ use crate::AppState;
  */
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Yml {
+    /*
+     * Capabilities an agent must have, steps which don't declare their own needs
+     * inherit these
+     */
+    #[serde(default)]
     pub needs: Vec<String>,
-    pub commands: Vec<String>,
+    /*
+     * Commands executed serially on one agent, the flat alternative to stages
+     */
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub commands: Vec<YmlCommand>,
+    /*
+     * Stages executed one after the other, each of which may run its steps in parallel
+     */
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub stages: Vec<YmlStage>,
+    /*
+     * Jobs which are executed as soon as the jobs they depend on have succeeded, the
+     * alternative to stages for pipelines which form a graph
+     */
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub jobs: Vec<YmlJob>,
+    /*
+     * Combinations of values the commands of the flat format are executed with, each in
+     * a step of its own
+     */
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub matrix: Option<YmlMatrix>,
+    /*
+     * Number of seconds the whole run may take before it is stopped
+     */
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<u64>,
+    /*
+     * Environment variables for every command
+     */
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub env: HashMap<String, String>,
+    /*
+     * Inputs which can be provided when the run is triggered manually
+     */
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub parameters: Vec<Parameter>,
+}
+
+/*
+ * Name given to the stage and step of the flat format
+ */
+pub const DEFAULT_STEP: &str = "default";
+
+/*
+ * Name of the single stage which holds the jobs of a Yml
+ */
+pub const JOBS_STAGE: &str = "jobs";
+
+impl Yml {
+    /*
+     * The stages of the Yml, the flat format being a single stage with a single step and
+     * jobs being the steps of a single stage
+     */
+    pub fn stages(&self) -> Vec<YmlStage> {
+        if !self.stages.is_empty() {
+            return self
+                .stages
+                .iter()
+                .map(|stage| YmlStage {
+                    name: stage.name.clone(),
+                    steps: stage.steps.iter().flat_map(|s| self.expand(s)).collect(),
+                })
+                .collect();
+        }
+        if !self.jobs.is_empty() {
+            return vec![YmlStage {
+                name: JOBS_STAGE.into(),
+                steps: self
+                    .jobs
+                    .iter()
+                    .flat_map(|job| self.expand(&job.step))
+                    .collect(),
+            }];
+        }
+        /*
+         * The needs of the Yml are carried by the step of a matrix so that they may name
+         * its axes
+         */
+        let step = YmlStep {
+            name: DEFAULT_STEP.into(),
+            needs: None,
+            commands: self.commands.clone(),
+            timeout: None,
+            env: HashMap::new(),
+            matrix: self.matrix.clone(),
+            condition: None,
+            expanded_from: None,
+        };
+        vec![YmlStage {
+            name: DEFAULT_STEP.into(),
+            steps: self.expand(&step),
+        }]
+    }
+
+    /*
+     * Expand the matrix of the step. The needs of the Yml are carried by the steps of a
+     * matrix which doesn't declare its own, so that they may name its axes
+     */
+    fn expand(&self, step: &YmlStep) -> Vec<YmlStep> {
+        match (&step.matrix, &step.needs) {
+            (Some(_), None) => YmlStep {
+                needs: Some(self.needs.clone()),
+                ..step.clone()
+            }
+            .expand(),
+            _ => step.expand(),
+        }
+    }
+
+    /*
+     * Look up the step at the given position of the given stage
+     */
+    pub fn step(&self, stage: usize, position: usize) -> Option<YmlStep> {
+        self.stages()
+            .get(stage)
+            .and_then(|s| s.steps.get(position).cloned())
+    }
+
+    /*
+     * The steps each step depends on, with steps numbered in the order of their stages.
+     *
+     * Every step of a stage depends on all of the steps of the stage before it, while jobs
+     * depend on the jobs they name
+     */
+    pub fn dependencies(&self) -> Vec<Vec<usize>> {
+        if !self.jobs.is_empty() {
+            /*
+             * Depending on a job with a matrix is depending on every step it expands into
+             */
+            let mut expanded: Vec<Vec<usize>> = vec![];
+            for job in self.jobs.iter() {
+                let start = expanded.iter().map(Vec::len).sum();
+                expanded.push((start..start + job.step.expand().len()).collect());
+            }
+            return self
+                .job_dependencies()
+                .iter()
+                .zip(expanded.iter())
+                .flat_map(|(depends_on, steps)| {
+                    let depends_on: Vec<usize> = depends_on
+                        .iter()
+                        .flat_map(|job| expanded[*job].clone())
+                        .collect();
+                    steps.iter().map(move |_| depends_on.clone())
+                })
+                .collect();
+        }
+
+        let mut dependencies = vec![];
+        let mut previous: Vec<usize> = vec![];
+        for stage in self.stages() {
+            let start = dependencies.len();
+            for _ in stage.steps.iter() {
+                dependencies.push(previous.clone());
+            }
+            previous = (start..dependencies.len()).collect();
+        }
+        dependencies
+    }
+
+    /*
+     * The jobs each job depends on, by their position in the Yml
+     */
+    fn job_dependencies(&self) -> Vec<Vec<usize>> {
+        let index: HashMap<&String, usize> = self
+            .jobs
+            .iter()
+            .enumerate()
+            .map(|(i, job)| (&job.step.name, i))
+            .collect();
+        self.jobs
+            .iter()
+            .map(|job| {
+                job.depends_on
+                    .iter()
+                    .filter_map(|name| index.get(name).copied())
+                    .collect()
+            })
+            .collect()
+    }
+
+    /*
+     * Check the parts of the Yml which cannot be expressed by its types alone
+     */
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let formats = [
+            !self.commands.is_empty(),
+            !self.stages.is_empty(),
+            !self.jobs.is_empty(),
+        ];
+        if formats.iter().filter(|defined| **defined).count() > 1 {
+            return Err(anyhow::anyhow!(
+                "Only one of commands, stages or jobs may be defined"
+            ));
+        }
+        crate::parameters::validate(&self.parameters).map_err(|e| anyhow::anyhow!(e))?;
+        if self.matrix.is_some() && self.commands.is_empty() {
+            return Err(anyhow::anyhow!(
+                "A matrix for the whole Yml requires commands, stages and jobs have their own"
+            ));
+        }
+        let steps: Vec<&YmlStep> = self
+            .stages
+            .iter()
+            .flat_map(|stage| stage.steps.iter())
+            .chain(self.jobs.iter().map(|job| &job.step))
+            .collect();
+        for step in steps.iter() {
+            step.condition()
+                .map_err(|e| anyhow::anyhow!("The condition of {} is invalid: {}", step.name, e))?;
+            if let Some(matrix) = &step.matrix {
+                matrix.validate().map_err(|e| {
+                    anyhow::anyhow!("The matrix of {} is invalid: {}", step.name, e)
+                })?;
+            }
+        }
+        if let Some(matrix) = &self.matrix {
+            matrix.validate()?;
+        }
+        self.validate_jobs()?;
+
+        /*
+         * The axes named in needs are only substituted for the steps of a matrix with
+         * those axes, anywhere else they would be left in the needs no agent can meet
+         */
+        let mut needs: Vec<(&str, &Vec<String>, Option<&YmlMatrix>)> = steps
+            .iter()
+            .map(|step| {
+                (
+                    step.name.as_str(),
+                    step.needs.as_ref().unwrap_or(&self.needs),
+                    step.matrix.as_ref(),
+                )
+            })
+            .collect();
+        if !self.commands.is_empty() {
+            needs.push((DEFAULT_STEP, &self.needs, self.matrix.as_ref()));
+        }
+        for (name, needs, matrix) in needs {
+            for axis in needs.iter().flat_map(|need| matrix_references(need)) {
+                if !matrix.is_some_and(|m| m.axes.contains_key(axis)) {
+                    return Err(anyhow::anyhow!(
+                        "The needs of {} use ${{matrix.{}}} but it has no such axis",
+                        name,
+                        axis
+                    ));
+                }
+            }
+        }
+
+        let mut stages = std::collections::HashSet::new();
+        for stage in self.stages().iter() {
+            if !stages.insert(&stage.name) {
+                return Err(anyhow::anyhow!("The stage {} is defined twice", stage.name));
+            }
+            if stage.steps.is_empty() {
+                return Err(anyhow::anyhow!("The stage {} has no steps", stage.name));
+            }
+            let mut steps = std::collections::HashSet::new();
+            for step in stage.steps.iter() {
+                if !steps.insert(&step.name) {
+                    return Err(anyhow::anyhow!(
+                        "The step {} is defined twice in the stage {}",
+                        step.name,
+                        stage.name
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /*
+     * Jobs must have unique names and only depend on other jobs, without any cycles
+     * which would leave them waiting on each other forever
+     */
+    fn validate_jobs(&self) -> anyhow::Result<()> {
+        let mut names = std::collections::HashSet::new();
+        for job in self.jobs.iter() {
+            if !names.insert(&job.step.name) {
+                return Err(anyhow::anyhow!(
+                    "The job {} is defined twice",
+                    job.step.name
+                ));
+            }
+        }
+        for job in self.jobs.iter() {
+            if let Some(missing) = job.depends_on.iter().find(|n| !names.contains(n)) {
+                return Err(anyhow::anyhow!(
+                    "The job {} depends on {} which is not defined",
+                    job.step.name,
+                    missing
+                ));
+            }
+        }
+
+        /*
+         * Repeatedly remove the jobs whose dependencies have all been removed, whatever
+         * remains once nothing more can be removed is part of a cycle
+         */
+        let dependencies = self.job_dependencies();
+        let mut removed = vec![false; self.jobs.len()];
+        loop {
+            let next: Vec<usize> = (0..self.jobs.len())
+                .filter(|i| !removed[*i] && dependencies[*i].iter().all(|d| removed[*d]))
+                .collect();
+            if next.is_empty() {
+                break;
+            }
+            for i in next {
+                removed[i] = true;
+            }
+        }
+        let cycle: Vec<&str> = self
+            .jobs
+            .iter()
+            .zip(removed)
+            .filter(|(_, removed)| !removed)
+            .map(|(job, _)| job.step.name.as_str())
+            .collect();
+        if !cycle.is_empty() {
+            return Err(anyhow::anyhow!(
+                "The jobs {} depend on each other in a cycle",
+                cycle.join(", ")
+            ));
+        }
+        Ok(())
+    }
+}
+
+/*
+ * A step which is executed once the jobs it depends on have succeeded
+ */
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct YmlJob {
+    #[serde(flatten)]
+    pub step: YmlStep,
+    // Names of the jobs which must succeed before this one is executed
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub depends_on: Vec<String>,
+}
+
+/*
+ * A named group of steps, all of which must succeed before the next stage starts
+ */
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct YmlStage {
+    pub name: String,
+    pub steps: Vec<YmlStep>,
+}
+
+/*
+ * Commands executed serially on one agent, in parallel with the other steps of the stage
+ */
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct YmlStep {
+    pub name: String,
+    /*
+     * Capabilities the agent executing this step must have, instead of those of the Yml
+     */
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub needs: Option<Vec<String>>,
+    pub commands: Vec<YmlCommand>,
+    /*
+     * Number of seconds the step may take, instead of the timeout of the Yml
+     */
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<u64>,
+    /*
+     * Environment variables for the commands of this step, taking precedence over those
+     * of the Yml
+     */
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub env: HashMap<String, String>,
+    /*
+     * Combinations of values the step is executed with, each in a step of its own
+     */
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub matrix: Option<YmlMatrix>,
+    /*
+     * Expression deciding whether the step is executed, see the conditions module
+     */
+    #[serde(default, rename = "if", skip_serializing_if = "Option::is_none")]
+    pub condition: Option<String>,
+    /*
+     * Name of the step a combination of a matrix was expanded from, and whether its matrix
+     * is fail-fast
+     */
+    #[serde(skip)]
+    pub expanded_from: Option<(String, bool)>,
+}
+
+impl YmlStep {
+    /*
+     * Parse the condition of the step, if it has one
+     */
+    pub fn condition(&self) -> anyhow::Result<Option<Expression>> {
+        self.condition.as_deref().map(conditions::parse).transpose()
+    }
+
+    /*
+     * Whether the step may be executed after another step of the Run has failed, which
+     * steps cleaning up or sending notifications declare with a status function
+     */
+    pub fn is_cleanup(&self) -> bool {
+        matches!(self.condition(), Ok(Some(condition)) if condition.checks_status())
+    }
+
+    /*
+     * The steps the matrix of this step expands into, or just the step without one.
+     *
+     * Each of the steps is named after the values of its combination, which are available
+     * to its commands as SYNCHRONIK_MATRIX_ variables and may be used in its needs
+     */
+    pub fn expand(&self) -> Vec<YmlStep> {
+        let matrix = match &self.matrix {
+            Some(matrix) => matrix,
+            None => return vec![self.clone()],
+        };
+
+        matrix
+            .combinations()
+            .into_iter()
+            .map(|combination| {
+                let values: Vec<&str> = matrix
+                    .axes
+                    .names()
+                    .filter_map(|axis| combination.get(axis).map(String::as_str))
+                    .collect();
+                let mut env = self.env.clone();
+                for (axis, value) in combination.iter() {
+                    env.insert(matrix_variable(axis), value.clone());
+                }
+                let needs = self.needs.as_ref().map(|needs| {
+                    needs
+                        .iter()
+                        .map(|need| {
+                            combination
+                                .iter()
+                                .fold(need.clone(), |need, (axis, value)| {
+                                    need.replace(&format!("${{matrix.{}}}", axis), value)
+                                })
+                        })
+                        .collect()
+                });
+                YmlStep {
+                    name: format!("{} ({})", self.name, values.join(", ")),
+                    needs,
+                    env,
+                    matrix: None,
+                    expanded_from: Some((self.name.clone(), matrix.fail_fast)),
+                    ..self.clone()
+                }
+            })
+            .collect()
+    }
+}
+
+/*
+ * Name of the environment variable holding the value of an axis of a matrix
+ */
+fn matrix_variable(axis: &str) -> String {
+    let axis: String = axis
+        .chars()
+        .map(|c| match c.is_ascii_alphanumeric() {
+            true => c.to_ascii_uppercase(),
+            false => '_',
+        })
+        .collect();
+    format!("SYNCHRONIK_MATRIX_{}", axis)
+}
+
+/*
+ * The axes a need refers to with ${matrix.axis}
+ */
+fn matrix_references(need: &str) -> Vec<&str> {
+    need.split("${matrix.")
+        .skip(1)
+        .filter_map(|rest| rest.split_once('}').map(|(axis, _)| axis))
+        .collect()
+}
+
+/*
+ * The axes of a matrix in the order they are declared, which is the order the values of
+ * a combination are named in
+ */
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct YmlAxes(Vec<(String, Vec<String>)>);
+
+impl YmlAxes {
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &Vec<String>)> {
+        self.0.iter().map(|(axis, values)| (axis, values))
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &String> {
+        self.0.iter().map(|(axis, _)| axis)
+    }
+
+    pub fn contains_key(&self, axis: &str) -> bool {
+        self.0.iter().any(|(name, _)| name == axis)
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl Serialize for YmlAxes {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeMap;
+
+        let mut map = serializer.serialize_map(Some(self.0.len()))?;
+        for (axis, values) in self.0.iter() {
+            map.serialize_entry(axis, values)?;
+        }
+        map.end()
+    }
+}
+
+/*
+ * The axes of a matrix each have a list of values, a step is executed for every
+ * combination of them apart from those excluded, along with any extra combinations which
+ * are included
+ */
+#[derive(Clone, Debug, Serialize, PartialEq, Eq)]
+pub struct YmlMatrix {
+    #[serde(flatten)]
+    pub axes: YmlAxes,
+    /*
+     * Whether a combination failing stops the others, which otherwise keep executing so
+     * that every combination which fails is known
+     */
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub fail_fast: bool,
+    /*
+     * Combinations to leave out, each matching every combination with the values it lists
+     */
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub exclude: Vec<BTreeMap<String, String>>,
+    /*
+     * Complete combinations to execute in addition to those of the axes
+     */
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub include: Vec<BTreeMap<String, String>>,
+}
+
+/*
+ * A value of a matrix, which is written as any scalar such as `node: [16, 18]` but is
+ * always handled as the text the environment variable holds. A float is formatted as a
+ * number, so `1.70` becomes 1.7 unless it is quoted
+ */
+struct MatrixValue(String);
+
+impl<'de> Deserialize<'de> for MatrixValue {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct ValueVisitor;
+
+        impl serde::de::Visitor<'_> for ValueVisitor {
+            type Value = MatrixValue;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("a string, number or boolean")
+            }
+
+            fn visit_str<E: serde::de::Error>(self, v: &str) -> Result<Self::Value, E> {
+                Ok(MatrixValue(v.into()))
+            }
+
+            fn visit_bool<E: serde::de::Error>(self, v: bool) -> Result<Self::Value, E> {
+                Ok(MatrixValue(v.to_string()))
+            }
+
+            fn visit_i64<E: serde::de::Error>(self, v: i64) -> Result<Self::Value, E> {
+                Ok(MatrixValue(v.to_string()))
+            }
+
+            fn visit_u64<E: serde::de::Error>(self, v: u64) -> Result<Self::Value, E> {
+                Ok(MatrixValue(v.to_string()))
+            }
+
+            fn visit_f64<E: serde::de::Error>(self, v: f64) -> Result<Self::Value, E> {
+                Ok(MatrixValue(v.to_string()))
+            }
+        }
+
+        deserializer.deserialize_any(ValueVisitor)
+    }
+}
+
+/*
+ * The axes of the matrix are every key apart from the settings, in the order they are
+ * declared
+ */
+impl<'de> Deserialize<'de> for YmlMatrix {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct MatrixVisitor;
+
+        fn combinations(
+            combinations: Vec<BTreeMap<String, MatrixValue>>,
+        ) -> Vec<BTreeMap<String, String>> {
+            combinations
+                .into_iter()
+                .map(|c| c.into_iter().map(|(axis, value)| (axis, value.0)).collect())
+                .collect()
+        }
+
+        impl<'de> serde::de::Visitor<'de> for MatrixVisitor {
+            type Value = YmlMatrix;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("a list of values for every axis")
+            }
+
+            fn visit_map<A: serde::de::MapAccess<'de>>(
+                self,
+                mut map: A,
+            ) -> Result<Self::Value, A::Error> {
+                let mut matrix = YmlMatrix {
+                    axes: YmlAxes::default(),
+                    fail_fast: false,
+                    exclude: vec![],
+                    include: vec![],
+                };
+                while let Some(key) = map.next_key::<String>()? {
+                    match key.as_str() {
+                        "fail_fast" => matrix.fail_fast = map.next_value()?,
+                        "exclude" => matrix.exclude = combinations(map.next_value()?),
+                        "include" => matrix.include = combinations(map.next_value()?),
+                        _ => {
+                            let values: Vec<MatrixValue> = map.next_value()?;
+                            let values = values.into_iter().map(|v| v.0).collect();
+                            matrix.axes.0.push((key, values));
+                        }
+                    }
+                }
+                Ok(matrix)
+            }
+        }
+
+        deserializer.deserialize_map(MatrixVisitor)
+    }
+}
+
+impl YmlMatrix {
+    /*
+     * Every combination of values in the order of the axes
+     */
+    pub fn combinations(&self) -> Vec<BTreeMap<String, String>> {
+        let mut combinations = vec![BTreeMap::new()];
+        for (axis, values) in self.axes.iter() {
+            combinations = combinations
+                .into_iter()
+                .flat_map(|combination| {
+                    values.iter().map(move |value| {
+                        let mut combination = combination.clone();
+                        combination.insert(axis.clone(), value.clone());
+                        combination
+                    })
+                })
+                .collect();
+        }
+
+        combinations.retain(|combination| {
+            !self.exclude.iter().any(|exclude| {
+                exclude
+                    .iter()
+                    .all(|(axis, value)| combination.get(axis) == Some(value))
+            })
+        });
+        for include in self.include.iter() {
+            if !combinations.contains(include) {
+                combinations.push(include.clone());
+            }
+        }
+        combinations
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.axes.is_empty() {
+            return Err(anyhow::anyhow!("A matrix needs at least one axis"));
+        }
+        if let Some((axis, _)) = self.axes.iter().find(|(_, values)| values.is_empty()) {
+            return Err(anyhow::anyhow!("The axis {} has no values", axis));
+        }
+        if let Some(include) = self.include.iter().find(|i| i.len() != self.axes.len()) {
+            return Err(anyhow::anyhow!(
+                "The included combination {:?} needs a value for every axis",
+                include
+            ));
+        }
+        for combination in self.exclude.iter().chain(self.include.iter()) {
+            if let Some(axis) = combination.keys().find(|a| !self.axes.contains_key(a)) {
+                return Err(anyhow::anyhow!("{} is not an axis of the matrix", axis));
+            }
+        }
+        if self.combinations().is_empty() {
+            return Err(anyhow::anyhow!("Every combination is excluded"));
+        }
+        Ok(())
+    }
+}
+
+/*
+ * A command is either just the script to execute, or the script along with settings
+ * which only apply to that command
+ */
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum YmlCommand {
+    Script(String),
+    Detailed {
+        script: String,
+        /*
+         * Number of seconds the command may take before it is stopped
+         */
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        timeout: Option<u64>,
+        /*
+         * Environment variables which only apply to this command
+         */
+        #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+        env: HashMap<String, String>,
+    },
+}
+
+impl From<&YmlCommand> for synchronik::Command {
+    fn from(command: &YmlCommand) -> Self {
+        match command {
+            YmlCommand::Script(script) => synchronik::Command::with_script(script),
+            YmlCommand::Detailed {
+                script,
+                timeout,
+                env,
+            } => synchronik::Command {
+                timeout: *timeout,
+                env: env.clone(),
+                ..synchronik::Command::with_script(script)
+            },
+        }
+    }
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -29,13 +780,44 @@ pub enum Scm {
         repo: String,
         #[serde(rename = "ref")]
         scm_ref: String,
+        /*
+         * Name of the GitHub configuration in the ServerConfig to read the repository with
+         */
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        credentials: Option<String>,
+    },
+    /*
+     * Any repository which git can fetch from, including file:// URLs
+     */
+    Git {
+        url: String,
+        #[serde(rename = "ref")]
+        scm_ref: String,
+    },
+    /*
+     * A project on a GitLab server, addressed by its full path such as group/project
+     */
+    GitLab {
+        url: Url,
+        project: String,
+        #[serde(rename = "ref")]
+        scm_ref: String,
+    },
+    /*
+     * A repository on a Gitea or Forgejo server, addressed as owner/repo
+     */
+    Gitea {
+        url: Url,
+        project: String,
+        #[serde(rename = "ref")]
+        scm_ref: String,
     },
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 #[serde(rename_all = "lowercase")]
 pub struct Project {
-    description: String,
+    pub description: String,
     /*
      * Used for optionally defining an inline Yml configuration
      */
@@ -43,6 +825,127 @@ pub struct Project {
     pub filename: Option<String>,
     #[serde(default = "default_scm", with = "serde_yaml::with::singleton_map")]
     pub scm: Scm,
+    /*
+     * Agent labels which this project prefers when using the label-affinity strategy
+     */
+    #[serde(default)]
+    pub labels: Vec<String>,
+    /*
+     * Inputs which can be provided when the project is triggered manually
+     */
+    #[serde(default)]
+    pub parameters: Vec<Parameter>,
+    #[serde(default)]
+    pub workspace: WorkspaceMode,
+    /*
+     * Number of seconds between checks of the scm for new commits, which are then built
+     */
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub poll: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub schedule: Option<Schedule>,
+}
+
+/*
+ * When runs of a project are created on a timer
+ */
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct Schedule {
+    /*
+     * Cron expression of either the usual five fields, or with seconds as the first field
+     */
+    pub cron: String,
+    // Name of the time zone the expression is evaluated in, such as Europe/Berlin
+    #[serde(default = "default_timezone")]
+    pub timezone: String,
+}
+
+impl Schedule {
+    fn parse(&self) -> anyhow::Result<(cron::Schedule, chrono_tz::Tz)> {
+        use std::str::FromStr;
+
+        // The cron crate always expects the seconds field
+        let expression = match self.cron.split_whitespace().count() {
+            5 => format!("0 {}", self.cron),
+            _ => self.cron.clone(),
+        };
+        let cron = cron::Schedule::from_str(&expression)
+            .map_err(|e| anyhow::anyhow!("Invalid cron expression {}: {}", self.cron, e))?;
+        let timezone = self
+            .timezone
+            .parse()
+            .map_err(|e| anyhow::anyhow!("Invalid time zone {}: {}", self.timezone, e))?;
+        Ok((cron, timezone))
+    }
+
+    /*
+     * Check that the expression and the time zone can be understood
+     */
+    pub fn validate(&self) -> anyhow::Result<()> {
+        self.parse().map(|_| ())
+    }
+
+    /*
+     * The first time the schedule fires after the given time, in the time zone of the
+     * schedule
+     */
+    pub fn next_after(
+        &self,
+        after: chrono::DateTime<chrono::Utc>,
+    ) -> anyhow::Result<Option<chrono::DateTime<chrono_tz::Tz>>> {
+        let (cron, timezone) = self.parse()?;
+        Ok(cron.after(&after.with_timezone(&timezone)).next())
+    }
+
+    /*
+     * The most recent time the schedule fired after the given time, up to and including
+     * the time until
+     */
+    pub fn last_between(
+        &self,
+        after: chrono::DateTime<chrono::Utc>,
+        until: chrono::DateTime<chrono::Utc>,
+    ) -> anyhow::Result<Option<chrono::DateTime<chrono::Utc>>> {
+        let (cron, timezone) = self.parse()?;
+        Ok(cron
+            .after(&after.with_timezone(&timezone))
+            .map(|time| time.with_timezone(&chrono::Utc))
+            .take_while(|time| *time <= until)
+            .last())
+    }
+}
+
+fn default_timezone() -> String {
+    "UTC".into()
+}
+
+/*
+ * How the agent prepares the directory the commands of a run are executed in
+ */
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum WorkspaceMode {
+    // Every run gets a new, empty, workspace
+    #[default]
+    Fresh,
+    // Runs of the project share a workspace which is kept for incremental builds
+    Persistent,
+}
+
+impl Project {
+    /*
+     * The parameters declared by the project along with those of its Yml, the project's
+     * declaration wins when both declare the same name
+     */
+    pub fn parameters(&self, yml: Option<&Yml>) -> Vec<Parameter> {
+        let mut parameters = self.parameters.clone();
+        for parameter in yml.iter().flat_map(|y| y.parameters.iter()) {
+            if !parameters.iter().any(|p| p.name == parameter.name) {
+                parameters.push(parameter.clone());
+            }
+        }
+        parameters
+    }
 }
 
 /*
@@ -63,6 +966,7 @@ pub struct Agent {
     pub name: String,
     pub url: Url,
     pub capabilities: Vec<synchronik::Capability>,
+    pub labels: Vec<String>,
 }
 
 impl Default for Agent {
@@ -71,16 +975,23 @@ impl Default for Agent {
             name: "default-agent".into(),
             url: Url::parse("http://example.com").unwrap(),
             capabilities: vec![],
+            labels: vec![],
         }
     }
 }
 
 impl Agent {
-    pub fn new(name: String, url: Url, capabilities: Vec<synchronik::Capability>) -> Self {
+    pub fn new(
+        name: String,
+        url: Url,
+        capabilities: Vec<synchronik::Capability>,
+        labels: Vec<String>,
+    ) -> Self {
         Self {
             name,
             url,
             capabilities,
+            labels,
         }
     }
 
@@ -103,7 +1014,7 @@ impl Agent {
             .collect();
 
         for need in needs {
-            if !capabilities.contains(&need) {
+            if !capabilities.contains(need) {
                 return false;
             }
         }
@@ -111,15 +1022,126 @@ impl Agent {
     }
 }
 
+/*
+ * How the server reads repositories from GitHub or a GitHub Enterprise server, without
+ * a token or an app only public repositories can be read
+ */
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct GitHubConfig {
+    /*
+     * Base URL of the API, which for GitHub Enterprise is https://<host>/api/v3/
+     */
+    pub url: Option<Url>,
+    // Personal access token
+    pub token: Option<String>,
+    pub app: Option<GitHubApp>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct GitHubApp {
+    pub id: u64,
+    /*
+     * Path to the PEM encoded private key of the app, the app must be installed on the
+     * repositories it is used for
+     */
+    pub key: PathBuf,
+}
+
+/*
+ * The configuration is logged on startup, so the token is kept out of the debug
+ * representation
+ */
+impl std::fmt::Debug for GitHubConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("GitHubConfig")
+            .field("url", &self.url)
+            .field("token", &self.token.as_ref().map(|_| "<redacted>"))
+            .field("app", &self.app)
+            .finish()
+    }
+}
+
+impl GitHubConfig {
+    /*
+     * URL which the repository can be cloned from, on the same host as the API
+     */
+    pub fn clone_url(&self, owner: &str, repo: &str) -> String {
+        let host = match &self.url {
+            Some(url) => {
+                let origin = url.origin().ascii_serialization();
+                origin.replacen("://api.", "://", 1)
+            }
+            None => "https://github.com".into(),
+        };
+        format!("{}/{}/{}.git", host, owner, repo)
+    }
+}
+
+/*
+ * Secrets which webhooks must be signed with, each webhook is disabled until its secret
+ * is configured
+ */
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct HooksConfig {
+    // Secret of the webhooks sent by GitHub
+    pub github: Option<String>,
+    // Secret for the generic webhook which any other system can send
+    pub generic: Option<String>,
+}
+
+impl std::fmt::Debug for HooksConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let redact = |secret: &Option<String>| secret.as_ref().map(|_| "<redacted>");
+        f.debug_struct("HooksConfig")
+            .field("github", &redact(&self.github))
+            .field("generic", &redact(&self.generic))
+            .finish()
+    }
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct AgentConfig {
     pub url: Url,
+    #[serde(default)]
+    pub labels: Vec<String>,
+}
+
+/*
+ * The strategy used for selecting which of the capable agents a run is dispatched to
+ */
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum SelectionStrategy {
+    // Always prefer agents in the order they are configured
+    #[default]
+    FirstAvailable,
+    // Rotate through the agents
+    RoundRobin,
+    // Prefer the agent which has gone the longest without being handed a run
+    LeastRecentlyUsed,
+    // Prefer the agents sharing the most labels with the project
+    LabelAffinity,
 }
 
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct ServerConfig {
+    /*
+     * Externally reachable URL of the server, used by agents to report back. When not
+     * configured it is derived from the listen address
+     */
+    pub url: Option<Url>,
+    #[serde(default)]
+    pub strategy: SelectionStrategy,
     pub agents: HashMap<String, AgentConfig>,
     pub projects: HashMap<String, Project>,
+    /*
+     * Named configurations for reading repositories from GitHub, projects which do not
+     * name one use the configuration named default
+     */
+    #[serde(default)]
+    pub github: HashMap<String, GitHubConfig>,
+    #[serde(default)]
+    pub hooks: HooksConfig,
 }
 
 impl ServerConfig {
@@ -127,10 +1149,25 @@ impl ServerConfig {
         self.projects.contains_key(name)
     }
 
+    /*
+     * Find the GitHub configuration with the given name, falling back to anonymous access
+     * to github.com when no name is given and there is no default configuration
+     */
+    pub fn github(&self, name: Option<&str>) -> anyhow::Result<GitHubConfig> {
+        match name {
+            Some(name) => self
+                .github
+                .get(name)
+                .cloned()
+                .ok_or_else(|| anyhow::anyhow!("No GitHub configuration named {}", name)),
+            None => Ok(self.github.get("default").cloned().unwrap_or_default()),
+        }
+    }
+
     /*
      * Load the ServerConfig from the given file.
      */
-    fn from_filepath(path: &PathBuf) -> anyhow::Result<Self> {
+    fn from_filepath(path: &Path) -> anyhow::Result<Self> {
         let config_file = std::fs::File::open(path).expect("Failed to open config file");
         serde_yaml::from_reader(config_file).map_err(anyhow::Error::from)
     }
@@ -138,11 +1175,11 @@ impl ServerConfig {
     /*
      * Load the ServerConfig from an amalgamation of yaml in the given directory
      */
-    fn from_dirpath(path: &PathBuf) -> anyhow::Result<Self> {
+    fn from_dirpath(path: &Path) -> anyhow::Result<Self> {
         use glob::glob;
         use std::fs::File;
 
-        let pattern = format!("{}/**/*.yml", path.as_path().to_string_lossy());
+        let pattern = format!("{}/**/*.yml", path.to_string_lossy());
         debug!("Loading config from directory with pattern: {}", pattern);
 
         let mut values: Vec<serde_yaml::Value> = vec![];
@@ -180,10 +1217,30 @@ impl ServerConfig {
             return Err(std::io::Error::from(std::io::ErrorKind::NotFound).into());
         }
 
-        match path.is_file() {
-            true => Self::from_filepath(&path),
-            false => Self::from_dirpath(&path),
+        let config = match path.is_file() {
+            true => Self::from_filepath(path)?,
+            false => Self::from_dirpath(path)?,
+        };
+
+        /*
+         * Inline definitions and schedules are checked up front rather than when a run is
+         * triggered
+         */
+        for (name, project) in config.projects.iter() {
+            crate::parameters::validate(&project.parameters)
+                .map_err(|e| anyhow::anyhow!("The parameters of {} are invalid: {}", name, e))?;
+            if let Some(schedule) = &project.schedule {
+                schedule
+                    .validate()
+                    .map_err(|e| anyhow::anyhow!("The schedule of {} is invalid: {}", name, e))?;
+            }
+            if let Some(inline) = &project.inline {
+                inline.validate().map_err(|e| {
+                    anyhow::anyhow!("The inline definition of {} is invalid: {}", name, e)
+                })?;
+            }
         }
+        Ok(config)
     }
 }
 
@@ -213,6 +1270,11 @@ fn merge_yaml(a: &mut serde_yaml::Value, b: serde_yaml::Value) {
 }
 
 #[cfg(test)]
+#[allow(
+    clippy::assertions_on_constants,
+    clippy::bool_assert_comparison,
+    clippy::needless_borrow
+)]
 mod tests {
     use super::*;
     use std::path::PathBuf;
@@ -289,6 +1351,181 @@ projects:
 "#;
         let value: ServerConfig = serde_yaml::from_str(&conf).expect("Failed to parse");
         assert_eq!(value.agents.len(), 1);
+        assert_eq!(SelectionStrategy::FirstAvailable, value.strategy);
+    }
+
+    #[test]
+    fn parse_config_with_git_scm() {
+        let conf = r#"
+---
+agents: {}
+projects:
+  'internal':
+    description: 'Hosted on an internal git server'
+    filename: 'ci/synchronik.yml'
+    scm:
+      git:
+        url: 'https://git.example.com/internal.git'
+        ref: 'develop'
+"#;
+        let value: ServerConfig = serde_yaml::from_str(conf).expect("Failed to parse");
+        let project = &value.projects["internal"];
+        assert_eq!(Some("ci/synchronik.yml".into()), project.filename);
+        match &project.scm {
+            Scm::Git { url, scm_ref } => {
+                assert_eq!("https://git.example.com/internal.git", url);
+                assert_eq!("develop", scm_ref);
+            }
+            scm => panic!("Unexpected scm: {:?}", scm),
+        }
+    }
+
+    #[test]
+    fn parse_config_with_github() {
+        let conf = r#"
+---
+agents: {}
+github:
+  'default':
+    token: 'ghp_secret'
+  'enterprise':
+    url: 'https://github.example.com/api/v3/'
+    app:
+      id: 1234
+      key: '/etc/synchronik/app.pem'
+projects:
+  'private':
+    description: 'Private repository on GitHub Enterprise'
+    scm:
+      github:
+        owner: 'rtyler'
+        repo: 'private'
+        ref: 'main'
+        credentials: 'enterprise'
+"#;
+        let value: ServerConfig = serde_yaml::from_str(conf).expect("Failed to parse");
+        assert_eq!(
+            Some("ghp_secret".to_string()),
+            value.github(None).unwrap().token
+        );
+        let enterprise = value.github(Some("enterprise")).unwrap();
+        assert_eq!(1234, enterprise.app.as_ref().unwrap().id);
+        assert_eq!(
+            "https://github.example.com/rtyler/private.git",
+            enterprise.clone_url("rtyler", "private")
+        );
+        assert!(value.github(Some("missing")).is_err());
+
+        match &value.projects["private"].scm {
+            Scm::GitHub { credentials, .. } => {
+                assert_eq!(Some("enterprise".to_string()), *credentials)
+            }
+            scm => panic!("Unexpected scm: {:?}", scm),
+        }
+    }
+
+    #[test]
+    fn test_github_clone_url() {
+        let config = GitHubConfig::default();
+        assert_eq!(
+            "https://github.com/rtyler/synchronik.git",
+            config.clone_url("rtyler", "synchronik")
+        );
+        let config = GitHubConfig {
+            url: Some(Url::parse("https://api.github.com").unwrap()),
+            ..Default::default()
+        };
+        assert_eq!(
+            "https://github.com/rtyler/synchronik.git",
+            config.clone_url("rtyler", "synchronik")
+        );
+    }
+
+    #[test]
+    fn test_serverconfig_invalid_schedule() {
+        for (cron, valid) in [("30 2 * * *", true), ("61 * * * *", false)] {
+            let path = std::env::temp_dir().join(format!("server-{}.yml", uuid::Uuid::new_v4()));
+            std::fs::write(
+                &path,
+                format!("agents: {{}}\nprojects:\n  'nightly':\n    description: 'nightly'\n    schedule:\n      cron: '{}'\n", cron),
+            )
+            .unwrap();
+            let config = ServerConfig::from_path(&path);
+            let _ = std::fs::remove_file(&path);
+            assert_eq!(valid, config.is_ok(), "{}", cron);
+        }
+    }
+
+    #[test]
+    fn parse_config_with_schedule() {
+        use chrono::{TimeZone, Utc};
+
+        let project: Project = serde_yaml::from_str(
+            "description: 'nightly'\nschedule:\n  cron: '30 2 * * *'\n  timezone: 'Europe/Berlin'\n",
+        )
+        .expect("Failed to parse");
+        let schedule = project.schedule.expect("No schedule");
+        let after = Utc.with_ymd_and_hms(2023, 4, 30, 12, 0, 0).unwrap();
+        let next = schedule.next_after(after).unwrap().unwrap();
+        assert_eq!(
+            Utc.with_ymd_and_hms(2023, 5, 1, 0, 30, 0).unwrap(),
+            next.with_timezone(&Utc)
+        );
+
+        let hourly = Schedule {
+            cron: "0 0 * * * *".into(),
+            timezone: default_timezone(),
+        };
+        assert_eq!(
+            Utc.with_ymd_and_hms(2023, 4, 30, 13, 0, 0).unwrap(),
+            hourly.next_after(after).unwrap().unwrap()
+        );
+        assert_eq!(
+            Some(Utc.with_ymd_and_hms(2023, 4, 30, 15, 0, 0).unwrap()),
+            hourly
+                .last_between(after, Utc.with_ymd_and_hms(2023, 4, 30, 15, 0, 0).unwrap())
+                .unwrap()
+        );
+        assert_eq!(
+            None,
+            hourly
+                .last_between(after, Utc.with_ymd_and_hms(2023, 4, 30, 12, 59, 0).unwrap())
+                .unwrap()
+        );
+
+        for invalid in [("61 * * * *", "UTC"), ("0 2 * * *", "Mars/Olympus")] {
+            let schedule = Schedule {
+                cron: invalid.0.into(),
+                timezone: invalid.1.into(),
+            };
+            assert!(schedule.validate().is_err(), "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn parse_config_with_strategy() {
+        let conf = r#"
+---
+strategy: 'label-affinity'
+agents:
+  'Local':
+    url: 'http://localhost:9000'
+    labels:
+      - 'fast'
+projects:
+  'synchronik':
+    description: |
+      Self-hosted project
+    labels:
+      - 'fast'
+"#;
+        let value: ServerConfig = serde_yaml::from_str(conf).expect("Failed to parse");
+        assert_eq!(SelectionStrategy::LabelAffinity, value.strategy);
+        assert_eq!(vec!["fast".to_string()], value.agents["Local"].labels);
+        assert_eq!(
+            vec!["fast".to_string()],
+            value.projects["synchronik"].labels
+        );
     }
 
     #[test]
@@ -315,7 +1552,9 @@ projects:
         let project = value.projects.get("synchronik").unwrap();
         match &project.inline {
             Some(yml) => {
-                assert!(yml.commands.contains(&"whoami".to_string()));
+                assert!(yml
+                    .commands
+                    .contains(&YmlCommand::Script("whoami".to_string())));
             }
             None => {
                 assert!(false);
@@ -323,6 +1562,434 @@ projects:
         }
     }
 
+    #[test]
+    fn parse_yml_with_timeouts() {
+        let conf = r#"
+---
+needs: []
+timeout: 3600
+commands:
+  - 'make'
+  - script: 'make check'
+    timeout: 600
+"#;
+        let yml: Yml = serde_yaml::from_str(conf).expect("Failed to parse");
+        assert_eq!(Some(3600), yml.timeout);
+
+        let commands: Vec<synchronik::Command> =
+            yml.commands.iter().map(synchronik::Command::from).collect();
+        assert_eq!(synchronik::Command::with_script("make"), commands[0]);
+        assert_eq!("make check", commands[1].script);
+        assert_eq!(Some(600), commands[1].timeout);
+    }
+
+    #[test]
+    fn parse_yml_with_env() {
+        let conf = r#"
+---
+needs: []
+env:
+  RUST_LOG: 'debug'
+commands:
+  - 'make'
+  - script: 'make check'
+    env:
+      RUST_BACKTRACE: '1'
+"#;
+        let yml: Yml = serde_yaml::from_str(conf).expect("Failed to parse");
+        assert_eq!(Some(&"debug".to_string()), yml.env.get("RUST_LOG"));
+
+        let command = synchronik::Command::from(&yml.commands[1]);
+        assert_eq!(Some(&"1".to_string()), command.env.get("RUST_BACKTRACE"));
+    }
+
+    #[test]
+    fn parse_yml_with_stages() {
+        let conf = r#"
+---
+needs: ['git']
+stages:
+  - name: 'build'
+    steps:
+      - name: 'compile'
+        commands:
+          - 'make'
+  - name: 'test'
+    steps:
+      - name: 'unit'
+        commands:
+          - 'make check'
+      - name: 'lint'
+        needs: ['cargo']
+        timeout: 60
+        env:
+          RUST_LOG: 'warn'
+        commands:
+          - 'cargo clippy'
+"#;
+        let yml: Yml = serde_yaml::from_str(conf).expect("Failed to parse");
+        yml.validate().expect("The stages should be valid");
+        let stages = yml.stages();
+        assert_eq!(2, stages.len());
+        assert_eq!(vec!["unit", "lint"], {
+            let names: Vec<&str> = stages[1].steps.iter().map(|s| s.name.as_str()).collect();
+            names
+        });
+        let lint = yml.step(1, 1).expect("No lint step");
+        assert_eq!(Some(vec!["cargo".to_string()]), lint.needs);
+        assert_eq!(Some(60), lint.timeout);
+        assert!(yml.step(2, 0).is_none());
+    }
+
+    #[test]
+    fn parse_yml_with_jobs() {
+        let conf = r#"
+---
+needs: ['cargo']
+jobs:
+  - name: 'core'
+    commands: ['cargo build -p core']
+  - name: 'test-a'
+    depends_on: ['core']
+    commands: ['cargo test -p a']
+  - name: 'test-b'
+    depends_on: ['core']
+    needs: ['cargo', 'docker']
+    commands: ['cargo test -p b']
+  - name: 'package'
+    depends_on: ['test-a', 'test-b']
+    commands: ['make dist']
+"#;
+        let yml: Yml = serde_yaml::from_str(conf).expect("Failed to parse");
+        yml.validate().expect("The jobs should be valid");
+        assert_eq!(
+            vec![vec![], vec![0], vec![0], vec![1, 2]],
+            yml.dependencies()
+        );
+        let stages = yml.stages();
+        assert_eq!(1, stages.len());
+        assert_eq!(JOBS_STAGE, stages[0].name);
+        assert_eq!(
+            Some(vec!["cargo".to_string(), "docker".to_string()]),
+            yml.step(0, 2).unwrap().needs
+        );
+
+        let round_trip: Yml = serde_yaml::from_str(&serde_yaml::to_string(&yml).unwrap()).unwrap();
+        assert_eq!(yml.jobs, round_trip.jobs);
+    }
+
+    #[test]
+    fn stage_dependencies() {
+        let yml: Yml = serde_yaml::from_str(
+            "stages:\n  - name: 'a'\n    steps:\n      - name: '1'\n        commands: []\n      - name: '2'\n        commands: []\n  - name: 'b'\n    steps:\n      - name: '3'\n        commands: []\n",
+        )
+        .unwrap();
+        assert_eq!(vec![vec![], vec![], vec![0, 1]], yml.dependencies());
+
+        let flat: Yml = serde_yaml::from_str("needs: []\ncommands: []\n").unwrap();
+        assert_eq!(vec![Vec::<usize>::new()], flat.dependencies());
+    }
+
+    #[test]
+    fn invalid_jobs() {
+        for (conf, message) in [
+            (
+                "jobs:\n  - name: 'a'\n    commands: []\n  - name: 'a'\n    commands: []\n",
+                "defined twice",
+            ),
+            (
+                "jobs:\n  - name: 'a'\n    depends_on: ['b']\n    commands: []\n",
+                "not defined",
+            ),
+            (
+                "jobs:\n  - name: 'a'\n    depends_on: ['a']\n    commands: []\n",
+                "cycle",
+            ),
+            (
+                "jobs:\n  - name: 'root'\n    commands: []\n  - name: 'a'\n    depends_on: ['root', 'c']\n    commands: []\n  - name: 'b'\n    depends_on: ['a']\n    commands: []\n  - name: 'c'\n    depends_on: ['b']\n    commands: []\n",
+                "The jobs a, b, c depend on each other in a cycle",
+            ),
+            (
+                "commands: ['make']\njobs:\n  - name: 'a'\n    commands: []\n",
+                "Only one of",
+            ),
+        ] {
+            let yml: Yml = serde_yaml::from_str(conf).expect("Failed to parse");
+            let error = yml.validate().expect_err(conf).to_string();
+            assert!(error.contains(message), "{}: {}", conf, error);
+        }
+    }
+
+    #[test]
+    fn parse_yml_with_matrix() {
+        let conf = r#"
+---
+jobs:
+  - name: 'test'
+    needs: ['cargo', 'rust-${matrix.rust}']
+    env:
+      RUST_LOG: 'debug'
+    matrix:
+      rust: ['stable', 'beta']
+      features: ['default', 'full']
+      exclude:
+        - rust: 'beta'
+          features: 'full'
+      include:
+        - rust: 'nightly'
+          features: 'full'
+    commands: ['cargo test --features $SYNCHRONIK_MATRIX_FEATURES']
+  - name: 'package'
+    depends_on: ['test']
+    commands: ['make dist']
+"#;
+        let yml: Yml = serde_yaml::from_str(conf).expect("Failed to parse");
+        yml.validate().expect("The matrix should be valid");
+
+        let steps = &yml.stages()[0].steps;
+        let names: Vec<&str> = steps.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(
+            vec![
+                "test (stable, default)",
+                "test (stable, full)",
+                "test (beta, default)",
+                "test (nightly, full)",
+                "package",
+            ],
+            names
+        );
+        assert_eq!("stable", steps[0].env["SYNCHRONIK_MATRIX_RUST"]);
+        assert_eq!("default", steps[0].env["SYNCHRONIK_MATRIX_FEATURES"]);
+        assert_eq!("debug", steps[0].env["RUST_LOG"]);
+        assert_eq!(
+            Some(vec!["cargo".to_string(), "rust-nightly".to_string()]),
+            steps[3].needs
+        );
+        assert!(steps[3].matrix.is_none());
+        assert_eq!(
+            vec![vec![], vec![], vec![], vec![], vec![0, 1, 2, 3]],
+            yml.dependencies()
+        );
+    }
+
+    #[test]
+    fn flat_yml_with_matrix() {
+        let yml: Yml = serde_yaml::from_str(
+            "needs: []\nmatrix:\n  os: ['linux', 'freebsd']\ncommands: ['make']\n",
+        )
+        .unwrap();
+        yml.validate().unwrap();
+        let steps = &yml.stages()[0].steps;
+        assert_eq!(2, steps.len());
+        assert_eq!("default (linux)", steps[0].name);
+        assert_eq!(vec![Vec::<usize>::new(); 2], yml.dependencies());
+    }
+
+    #[test]
+    fn parse_matrix_with_scalars() {
+        let yml: Yml = serde_yaml::from_str(
+            r#"
+needs: []
+jobs:
+  - name: 'test'
+    matrix:
+      node: [16, 18]
+      python: [3.5]
+      debug: [true]
+      exclude:
+        - node: 18
+          debug: true
+      include:
+        - node: 20
+          python: 3.5
+          debug: false
+    commands: ['npm test']
+"#,
+        )
+        .expect("Failed to parse");
+        yml.validate().unwrap();
+
+        let steps = &yml.stages()[0].steps;
+        let names: Vec<&str> = steps.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(vec!["test (16, 3.5, true)", "test (20, 3.5, false)"], names);
+        assert_eq!("16", steps[0].env["SYNCHRONIK_MATRIX_NODE"]);
+    }
+
+    #[test]
+    fn stages_with_matrix_inherit_needs() {
+        let yml: Yml = serde_yaml::from_str(
+            r#"
+needs: ['rust-${matrix.rust}']
+stages:
+  - name: 'test'
+    steps:
+      - name: 'unit'
+        matrix:
+          rust: ['stable', 'beta']
+          os: ['linux']
+          fail_fast: true
+        commands: ['cargo test']
+"#,
+        )
+        .unwrap();
+        yml.validate().unwrap();
+        let steps = &yml.stages()[0].steps;
+        assert_eq!("unit (stable, linux)", steps[0].name);
+        assert_eq!(Some(vec!["rust-stable".to_string()]), steps[0].needs);
+        assert_eq!(Some(vec!["rust-beta".to_string()]), steps[1].needs);
+        assert_eq!(Some(("unit".to_string(), true)), steps[1].expanded_from);
+    }
+
+    #[test]
+    fn invalid_matrix() {
+        for (conf, message) in [
+            ("matrix:\n  os: []\ncommands: ['make']\n", "no values"),
+            (
+                "matrix:\n  os: ['linux']\n  exclude:\n    - os: 'linux'\ncommands: ['make']\n",
+                "Every combination",
+            ),
+            (
+                "matrix:\n  os: ['linux']\n  exclude:\n    - arch: 'arm'\ncommands: ['make']\n",
+                "not an axis",
+            ),
+            (
+                "matrix:\n  os: ['linux']\n  arch: ['x86']\n  include:\n    - os: 'freebsd'\ncommands: ['make']\n",
+                "every axis",
+            ),
+            (
+                "matrix:\n  os: ['linux']\njobs:\n  - name: 'a'\n    commands: []\n",
+                "requires commands",
+            ),
+            (
+                "jobs:\n  - name: 'a'\n    matrix: {}\n    commands: []\n",
+                "The matrix of a is invalid",
+            ),
+            (
+                "needs: ['rust-${matrix.rust}']\njobs:\n  - name: 'a'\n    commands: []\n",
+                "The needs of a use ${matrix.rust}",
+            ),
+            (
+                "needs: ['rust-${matrix.rust}']\nmatrix:\n  os: ['linux']\ncommands: ['make']\n",
+                "The needs of default use ${matrix.rust}",
+            ),
+        ] {
+            let yml: Yml = serde_yaml::from_str(conf).expect("Failed to parse");
+            let error = yml.validate().expect_err(conf).to_string();
+            assert!(error.contains(message), "{}: {}", conf, error);
+        }
+    }
+
+    #[test]
+    fn parse_yml_with_conditions() {
+        let conf = r#"
+jobs:
+  - name: 'test'
+    commands: ['make check']
+  - name: 'deploy'
+    if: "branch == 'main' && trigger != 'pull_request'"
+    depends_on: ['test']
+    commands: ['make deploy']
+  - name: 'notify'
+    if: 'failure()'
+    depends_on: ['deploy']
+    commands: ['./notify']
+"#;
+        let yml: Yml = serde_yaml::from_str(conf).expect("Failed to parse");
+        yml.validate().unwrap();
+        let steps = &yml.stages()[0].steps;
+        assert_eq!(None, steps[0].condition().unwrap());
+        assert!(steps[1].condition().unwrap().is_some());
+        assert!(!steps[1].is_cleanup());
+        assert!(steps[2].is_cleanup());
+
+        let yml: Yml = serde_yaml::from_str(
+            "jobs:\n  - name: 'a'\n    if: 'branch = main'\n    commands: []\n",
+        )
+        .unwrap();
+        let error = yml.validate().expect_err("Invalid condition").to_string();
+        assert!(error.contains("The condition of a is invalid"), "{}", error);
+    }
+
+    #[test]
+    fn flat_yml_is_a_single_step() {
+        let yml: Yml = serde_yaml::from_str("needs: []\ncommands:\n  - 'make'\n").unwrap();
+        yml.validate().unwrap();
+        let stages = yml.stages();
+        assert_eq!(1, stages.len());
+        assert_eq!(DEFAULT_STEP, stages[0].name);
+        assert_eq!(
+            vec![YmlCommand::Script("make".into())],
+            stages[0].steps[0].commands
+        );
+    }
+
+    #[test]
+    fn invalid_stages() {
+        for conf in [
+            "commands: ['make']\nstages:\n  - name: 'build'\n    steps:\n      - name: 'a'\n        commands: []\n",
+            "stages:\n  - name: 'build'\n    steps: []\n",
+            "stages:\n  - name: 'build'\n    steps:\n      - name: 'a'\n        commands: []\n  - name: 'build'\n    steps:\n      - name: 'b'\n        commands: []\n",
+            "stages:\n  - name: 'build'\n    steps:\n      - name: 'a'\n        commands: []\n      - name: 'a'\n        commands: []\n",
+        ] {
+            let yml: Yml = serde_yaml::from_str(conf).expect("Failed to parse");
+            assert!(yml.validate().is_err(), "{}", conf);
+        }
+    }
+
+    #[test]
+    fn parse_config_with_workspace() {
+        let conf = r#"
+---
+agents: {}
+projects:
+  'fresh':
+    description: 'Fresh'
+  'incremental':
+    description: 'Incremental'
+    workspace: 'persistent'
+"#;
+        let value: ServerConfig = serde_yaml::from_str(conf).expect("Failed to parse");
+        assert_eq!(WorkspaceMode::Fresh, value.projects["fresh"].workspace);
+        assert_eq!(
+            WorkspaceMode::Persistent,
+            value.projects["incremental"].workspace
+        );
+    }
+
+    #[test]
+    fn parse_config_with_parameters() {
+        let conf = r#"
+---
+agents: {}
+projects:
+  'synchronik':
+    description: 'Parameterized'
+    parameters:
+      - name: 'TARGET'
+        type: 'choice'
+        choices: ['debug', 'release']
+    inline:
+      needs: []
+      parameters:
+        - name: 'TARGET'
+          type: 'string'
+        - name: 'VERBOSE'
+          type: 'boolean'
+          default: true
+      commands:
+        - 'make'
+"#;
+        let value: ServerConfig = serde_yaml::from_str(conf).expect("Failed to parse");
+        let project = &value.projects["synchronik"];
+        let parameters = project.parameters(project.inline.as_ref());
+        let names: Vec<&str> = parameters.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(vec!["TARGET", "VERBOSE"], names);
+        assert!(matches!(
+            parameters[0].kind,
+            crate::parameters::ParameterKind::Choice { .. }
+        ));
+    }
+
     #[test]
     fn agent_can_meet_false() {
         let needs: Vec<String> = vec!["rspec".into(), "git".into(), "dotnet".into()];
@@ -331,6 +1998,7 @@ projects:
             name: "test".into(),
             url: Url::parse("http://localhost").unwrap(),
             capabilities,
+            labels: vec![],
         };
         assert_eq!(false, agent.can_meet(&needs));
     }
@@ -343,6 +2011,7 @@ projects:
             name: "test".into(),
             url: Url::parse("http://localhost").unwrap(),
             capabilities,
+            labels: vec![],
         };
         assert!(agent.can_meet(&needs));
     }
@@ -355,6 +2024,7 @@ projects:
             name: "test".into(),
             url: Url::parse("http://localhost").unwrap(),
             capabilities,
+            labels: vec![],
         };
         assert_eq!(false, agent.can_meet(&needs));
     }
@@ -370,6 +2040,7 @@ projects:
             name: "test".into(),
             url: Url::parse("http://localhost").unwrap(),
             capabilities,
+            labels: vec![],
         };
         assert!(agent.can_meet(&needs));
     }
@@ -386,6 +2057,7 @@ projects:
             name: "test".into(),
             url: Url::parse("http://localhost").unwrap(),
             capabilities,
+            labels: vec![],
         };
         assert!(agent.can_meet(&needs));
     }
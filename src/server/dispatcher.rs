@@ -0,0 +1,1281 @@
+/**
+ * The dispatcher is responsible for handing queued Runs to agents which are able to
+ * execute them.
+ *
+ * Runs are persisted in the queued state before they are dispatched, which allows them
+ * to wait for a busy agent and to survive a restart of the server. Each step of a Run is
+ * dispatched on its own once the steps it depends on have finished, which for stages are
+ * all of the steps of the stage before it, unless its condition leaves it skipped.
+ */
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+use log::*;
+use url::Url;
+
+use crate::conditions::{self, Context};
+use crate::config::{Agent, Scm, WorkspaceMode, Yml, YmlStep};
+use crate::models::{Run, RunStatus, Step};
+use crate::strategy::Selector;
+use crate::AppState;
+
+/*
+ * How often the queue is checked for runs which could not previously be dispatched
+ */
+const DISPATCH_INTERVAL: Duration = Duration::from_secs(5);
+/*
+ * How long an agent is given to acknowledge that it should stop a task
+ */
+const CANCEL_TIMEOUT: Duration = Duration::from_secs(10);
+
+/*
+ * Loop forever dispatching queued Runs
+ */
+pub async fn run(state: AppState<'_>) {
+    debug!("Dispatcher starting");
+    if let Err(e) = Step::requeue_pending(&state.db).await {
+        error!("Failed to queue the pending steps again: {:?}", e);
+    }
+
+    loop {
+        if let Err(e) = dispatch_queued(&state).await {
+            error!("Failed to dispatch queued runs: {:?}", e);
+        }
+        state.dispatcher_woken(DISPATCH_INTERVAL).await;
+    }
+}
+
+/*
+ * Attempt to dispatch the ready steps of every unfinished Run, oldest first. Steps whose
+ * condition is not met are skipped, while those which no agent accepts remain queued for
+ * the next attempt
+ */
+pub async fn dispatch_queued(state: &AppState<'_>) -> Result<(), sqlx::Error> {
+    /*
+     * The steps are claimed while holding the dispatch lock, so that the same step is not
+     * handed to more than one agent, but the agents are only asked to execute them once
+     * it has been released
+     */
+    let guard = state.dispatch_lock.lock().await;
+    // Tasks of the steps stopped by failures, which the agents are asked to stop afterwards
+    let mut stopped = vec![];
+    let mut claims = vec![];
+
+    'runs: for mut run in Run::active(&state.db).await? {
+        let queued = run.run.status == RunStatus::Queued.as_str();
+        /*
+         * Runs dispatched before they had steps are executed by a single agent, which
+         * reports their outcome through the status of the Run itself
+         */
+        if run.steps.is_empty() && !queued {
+            continue;
+        }
+
+        let config = match serde_yaml::from_str::<Yml>(&run.definition.definition)
+            .map_err(anyhow::Error::from)
+            .and_then(|config| config.validate().map(|_| config))
+        {
+            Ok(config) => config,
+            Err(e) => {
+                error!("Run {} has an invalid definition: {:?}", run.run.uuid, e);
+                /*
+                 * A Run which agents are already executing is left for them to finish
+                 */
+                if queued {
+                    Run::update_status(&run.run.uuid, RunStatus::Failed, &state.db).await?;
+                }
+                continue;
+            }
+        };
+
+        if run.steps.is_empty() {
+            run.steps = plan(&run, &config);
+            Step::create_all(&run.steps, &state.db).await?;
+        }
+
+        let dependencies = config.dependencies();
+        let parameters = run.run.parameters();
+
+        /*
+         * Skipping a step can leave the steps which depend on it ready, so the ready steps
+         * are checked again until no more of them are skipped
+         */
+        let mut attempted = HashSet::new();
+        loop {
+            let ready: Vec<Step> = ready(&run.steps, &dependencies)
+                .into_iter()
+                .filter(|step| !attempted.contains(&step.uuid))
+                .cloned()
+                .collect();
+            let mut skipped = false;
+
+            for step in ready.iter() {
+                attempted.insert(step.uuid.clone());
+                let yml_step = match config.step(step.stage as usize, step.position as usize) {
+                    Some(yml_step) => yml_step,
+                    None => {
+                        error!("Step {} is missing from run {}", step.name, run.run.uuid);
+                        Step::update_status(&step.uuid, RunStatus::Failed, &state.db).await?;
+                        stopped.extend(settle(state, &run.run.uuid).await?);
+                        continue 'runs;
+                    }
+                };
+
+                let env = environment(&run, &config, step, &yml_step);
+                let skipped_dependency = run
+                    .steps
+                    .iter()
+                    .position(|s| s.uuid == step.uuid)
+                    .map(|i| {
+                        dependencies[i]
+                            .iter()
+                            .any(|d| run.steps[*d].status() == RunStatus::Skipped)
+                    })
+                    .unwrap_or(false);
+                let failed = failure(
+                    run.steps
+                        .iter()
+                        .filter(|failed| stops(Some(&config), failed, step)),
+                )
+                .is_some();
+                let context = Context {
+                    r#ref: &run.scm_info.r#ref,
+                    trigger: &run.run.trigger,
+                    parameters: &parameters,
+                    env: &env,
+                    failed,
+                    skipped: skipped_dependency,
+                };
+                /*
+                 * The condition has been validated along with the rest of the Yml
+                 */
+                let condition = yml_step.condition().unwrap_or_default();
+                if !conditions::should_run(condition.as_ref(), &context) {
+                    info!(
+                        "Skipping step {} of run {}, its condition is not met",
+                        step.name, run.run.uuid
+                    );
+                    Step::update_status(&step.uuid, RunStatus::Skipped, &state.db).await?;
+                    skipped = true;
+                    continue;
+                }
+
+                claims.extend(claim(state, &run, &config, step, &yml_step).await?);
+            }
+
+            if !skipped {
+                break;
+            }
+            stopped.extend(settle(state, &run.run.uuid).await?);
+            run.steps = Step::list_for(&run.run.uuid, &state.db).await?;
+        }
+    }
+
+    drop(guard);
+    cancel_tasks(&state.http, stopped).await;
+
+    for claim in claims {
+        if let Err(e) = dispatch_step(state, &claim).await {
+            error!(
+                "Failed to dispatch step {} of run {}: {:?}",
+                claim.step.name, claim.run.run.uuid, e
+            );
+        }
+    }
+    Ok(())
+}
+
+/*
+ * A step which has been marked pending while holding the dispatch lock, along with the
+ * agents able to execute it in the order they are offered it
+ */
+struct Claim {
+    run: Run,
+    config: Yml,
+    step: Step,
+    yml_step: YmlStep,
+    agents: Vec<Agent>,
+}
+
+/*
+ * Claim the step for dispatching when an agent can meet its needs, the first agent in
+ * order is counted as having been picked so that the next step is offered to another
+ */
+async fn claim(
+    state: &AppState<'_>,
+    run: &Run,
+    config: &Yml,
+    step: &Step,
+    yml_step: &YmlStep,
+) -> Result<Option<Claim>, sqlx::Error> {
+    let project = state.config.projects.get(&run.project.name);
+    let labels = project.map(|p| p.labels.clone()).unwrap_or_default();
+    let needs = yml_step.needs.as_ref().unwrap_or(&config.needs);
+    let capable: Vec<&Agent> = state.agents.iter().filter(|a| a.can_meet(needs)).collect();
+    let agents: Vec<Agent> = state
+        .selector
+        .order(capable, &labels)
+        .into_iter()
+        .cloned()
+        .collect();
+
+    match agents.first() {
+        Some(agent) => state.selector.accepted(agent),
+        None => {
+            debug!(
+                "No agent can meet the needs of step {} of run {}, leaving it queued",
+                step.name, run.run.uuid
+            );
+            return Ok(None);
+        }
+    }
+
+    Step::update_status(&step.uuid, RunStatus::Pending, &state.db).await?;
+    Ok(Some(Claim {
+        run: run.clone(),
+        config: config.clone(),
+        step: step.clone(),
+        yml_step: yml_step.clone(),
+        agents,
+    }))
+}
+
+/*
+ * Hand one claimed step of the Run to an agent, putting it back in the queue when no
+ * agent accepts it. The outcome is recorded while holding the dispatch lock, a step which
+ * was cancelled in the meantime has its task stopped again
+ */
+async fn dispatch_step(state: &AppState<'_>, claim: &Claim) -> Result<(), sqlx::Error> {
+    let Claim {
+        run,
+        config,
+        step,
+        yml_step,
+        agents,
+    } = claim;
+    let project = state.config.projects.get(&run.project.name);
+    let mut callback = state
+        .url
+        .join(&format!(
+            "/api/v1/runs/{}/steps/{}/status",
+            run.run.uuid, step.uuid
+        ))
+        .expect("Failed to join the callback URL");
+    if let Some(token) = &run.run.token {
+        callback.query_pairs_mut().append_pair("token", token);
+    }
+
+    let mut request = command_request(run, project, config, step, yml_step, &callback);
+    if let (Some(source), Some(project)) = (request.source.as_mut(), project) {
+        source.token = source_token(project, state).await;
+    }
+    let dispatched = dispatch(&state.http, &request, agents, state.selector.as_ref()).await;
+
+    let guard = state.dispatch_lock.lock().await;
+    let (agent, response) = match dispatched {
+        Some(dispatched) => dispatched,
+        None => {
+            debug!(
+                "No agent accepted step {} of run {}, leaving it queued",
+                step.name, run.run.uuid
+            );
+            Step::requeue(&step.uuid, &state.db).await?;
+            return Ok(());
+        }
+    };
+    info!(
+        "Step {} of run {} dispatched to {} as task {}",
+        step.name, run.run.uuid, agent.name, response.uuid
+    );
+    let log = response.log.as_str();
+    let stream = response.stream.as_ref().map(|s| s.as_str());
+    let task = response.task.as_ref().map(|t| t.as_str());
+    let current = Step::find_by(&step.uuid, &state.db).await?;
+    Step::started(&step.uuid, &agent.name, log, stream, task, &state.db).await?;
+    /*
+     * A Run with a single step is executed entirely by one agent, so the agent and
+     * its logs are recorded on the Run itself as well
+     */
+    match run.steps.len() {
+        1 => Run::started(&run.run.uuid, &agent.name, log, stream, task, &state.db).await?,
+        _ => Run::running(&run.run.uuid, &state.db).await?,
+    }
+    drop(guard);
+
+    if matches!(current.status(), RunStatus::Cancelled | RunStatus::Skipped) {
+        info!(
+            "Step {} of run {} was stopped while it was being dispatched",
+            step.name, run.run.uuid
+        );
+        cancel_tasks(&state.http, task.map(String::from).into_iter().collect()).await;
+    }
+    Ok(())
+}
+
+/*
+ * Create the steps of the Run from the stages of its definition
+ */
+fn plan(run: &Run, config: &Yml) -> Vec<Step> {
+    config
+        .stages()
+        .iter()
+        .enumerate()
+        .flat_map(|(stage, yml_stage)| {
+            yml_stage
+                .steps
+                .iter()
+                .enumerate()
+                .map(move |(position, yml_step)| {
+                    Step::new(
+                        &run.run.uuid,
+                        stage,
+                        position,
+                        &yml_stage.name,
+                        &yml_step.name,
+                    )
+                })
+        })
+        .collect()
+}
+
+/*
+ * The queued steps whose dependencies have all finished, the steps being in the same
+ * order as the dependencies of the Yml. Whether a ready step is executed or skipped is up
+ * to its condition
+ */
+fn ready<'a>(steps: &'a [Step], dependencies: &[Vec<usize>]) -> Vec<&'a Step> {
+    if steps.len() != dependencies.len() {
+        error!("The steps of the run no longer match its definition");
+        return vec![];
+    }
+    steps
+        .iter()
+        .zip(dependencies)
+        .filter(|(step, depends_on)| {
+            step.status() == RunStatus::Queued
+                && depends_on.iter().all(|d| steps[*d].status().is_finished())
+        })
+        .map(|(step, _)| step)
+        .collect()
+}
+
+/*
+ * The status a Run with the given steps fails with, if any of them have failed. A step
+ * failing on its own decides it over a step which was cancelled
+ */
+fn failure<'a>(steps: impl IntoIterator<Item = &'a Step>) -> Option<RunStatus> {
+    let statuses: Vec<RunStatus> = steps.into_iter().map(Step::status).collect();
+    statuses
+        .iter()
+        .find(|s| matches!(s, RunStatus::Failed | RunStatus::TimedOut))
+        .or_else(|| statuses.iter().find(|s| **s == RunStatus::Cancelled))
+        .copied()
+}
+
+/*
+ * Whether the failure of one step stops another. The other combinations of a matrix keep
+ * executing when one of them fails, unless the matrix is fail-fast
+ */
+fn stops(config: Option<&Yml>, failed: &Step, step: &Step) -> bool {
+    let origin = |s: &Step| {
+        config
+            .and_then(|c| c.step(s.stage as usize, s.position as usize))
+            .and_then(|yml_step| yml_step.expanded_from)
+    };
+    match (origin(failed), origin(step)) {
+        (Some((matrix, false)), Some((other, _))) => failed.stage != step.stage || matrix != other,
+        _ => true,
+    }
+}
+
+/*
+ * Bring the status of the Run in line with its steps after some of them have finished.
+ *
+ * Once a step has failed, the steps still executing are cancelled and those yet to execute
+ * are skipped, apart from the cleanup steps which may still execute after the failure and
+ * the other combinations of a matrix which isn't fail-fast.
+ * The Run finishes once all of its steps have.
+ *
+ * The tasks of the cancelled steps are returned for the caller to stop with cancel_tasks
+ * once it has released the dispatch lock
+ */
+pub async fn settle(state: &AppState<'_>, uuid: &str) -> Result<Vec<String>, sqlx::Error> {
+    let run = Run::find_by(uuid, &state.db).await?;
+    let config = serde_yaml::from_str::<Yml>(&run.definition.definition).ok();
+    let mut stopped = vec![];
+
+    for step in run.steps.iter().filter(|s| !s.status().is_finished()) {
+        let status = match failure(
+            run.steps
+                .iter()
+                .filter(|failed| stops(config.as_ref(), failed, step)),
+        ) {
+            Some(status) => status,
+            None => continue,
+        };
+        let cleanup = config
+            .as_ref()
+            .and_then(|c| c.step(step.stage as usize, step.position as usize))
+            .map(|yml_step| yml_step.is_cleanup())
+            .unwrap_or(false);
+        if cleanup {
+            continue;
+        }
+        if step.status() == RunStatus::Queued {
+            Step::update_status(&step.uuid, RunStatus::Skipped, &state.db).await?;
+            continue;
+        }
+
+        info!("Run {} has {}, stopping step {}", uuid, status, step.name);
+        Step::update_status(&step.uuid, RunStatus::Cancelled, &state.db).await?;
+        stopped.extend(step.task_url.clone());
+    }
+
+    let steps = Step::list_for(uuid, &state.db).await?;
+    if !steps.is_empty() && steps.iter().all(|s| s.status().is_finished()) {
+        let status = failure(&steps).unwrap_or(RunStatus::Succeeded);
+        Run::update_status(uuid, status, &state.db).await?;
+    }
+    Ok(stopped)
+}
+
+/*
+ * Ask the agents to stop the tasks of steps which have already been cancelled, an agent
+ * which cannot be reached is only logged as there is nothing else to undo
+ */
+pub async fn cancel_tasks(client: &reqwest::Client, tasks: Vec<String>) {
+    for task_url in tasks {
+        if let Err(e) = cancel_task(client, &task_url).await {
+            warn!("Failed to stop the task {}: {}", task_url, e);
+        }
+    }
+}
+
+/*
+ * Ask the agent to stop the task at the given URL. The agent no longer knowing about the
+ * task, or it having already finished, leaves nothing to stop and is not an error
+ */
+pub async fn cancel_task(client: &reqwest::Client, task_url: &str) -> Result<(), String> {
+    let cancel = format!("{}/cancel", task_url);
+    debug!("Cancelling the task with {}", cancel);
+    match client.post(&cancel).timeout(CANCEL_TIMEOUT).send().await {
+        Ok(res)
+            if res.status().is_success()
+                || res.status() == reqwest::StatusCode::NOT_FOUND
+                || res.status() == reqwest::StatusCode::CONFLICT =>
+        {
+            Ok(())
+        }
+        Ok(res) => Err(format!("the agent refused with {}", res.status())),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/*
+ * Token the agent needs to clone the source of the project, which only private GitHub
+ * repositories require
+ */
+async fn source_token(project: &crate::config::Project, state: &AppState<'_>) -> Option<String> {
+    match &project.scm {
+        Scm::GitHub {
+            owner,
+            repo,
+            credentials,
+            ..
+        } => {
+            let token = match state.config.github(credentials.as_deref()) {
+                Ok(github) => crate::scm::github_token(&github, owner, repo, &state.tokens).await,
+                Err(e) => Err(e),
+            };
+            token.unwrap_or_else(|e| {
+                error!("Failed to create a token for {}/{}: {:?}", owner, repo, e);
+                None
+            })
+        }
+        _ => None,
+    }
+}
+
+/*
+ * The environment variables one step of the Run is executed with.
+ *
+ * The environment of the step takes precedence over that of the Yml, the parameters of
+ * the Run over both, and the built-in variables describing the Run over all of them
+ */
+fn environment(
+    run: &Run,
+    config: &Yml,
+    step: &Step,
+    yml_step: &YmlStep,
+) -> HashMap<String, String> {
+    let mut env = config.env.clone();
+    env.extend(yml_step.env.clone());
+    env.extend(run.run.parameters());
+    env.insert("SYNCHRONIK_RUN_UUID".into(), run.run.uuid.clone());
+    env.insert("SYNCHRONIK_RUN_NUMBER".into(), run.run.num.to_string());
+    env.insert("SYNCHRONIK_PROJECT".into(), run.project.name.clone());
+    env.insert("SYNCHRONIK_REF".into(), run.scm_info.r#ref.clone());
+    env.insert("SYNCHRONIK_STAGE".into(), step.stage_name.clone());
+    env.insert("SYNCHRONIK_STEP".into(), step.name.clone());
+    env
+}
+
+/*
+ * Build the request for an agent to execute the commands of one step of the Run
+ */
+fn command_request(
+    run: &Run,
+    project: Option<&crate::config::Project>,
+    config: &Yml,
+    step: &Step,
+    yml_step: &YmlStep,
+    callback: &Url,
+) -> synchronik::CommandRequest {
+    let commands: Vec<synchronik::Command> = yml_step
+        .commands
+        .iter()
+        .map(synchronik::Command::from)
+        .collect();
+    let env = environment(run, config, step, yml_step);
+
+    /*
+     * Persistent workspaces are named after the project so that its runs share one
+     */
+    let workspace = match project.map(|p| &p.workspace) {
+        Some(WorkspaceMode::Persistent) => Some(run.project.name.clone()),
+        _ => None,
+    };
+
+    /*
+     * Runs created from source control have their commit pinned so that the agent checks
+     * out exactly what the definition was read from
+     */
+    let source = match &run.scm_info.sha {
+        Some(sha) if !run.scm_info.git_url.is_empty() => Some(synchronik::Source {
+            url: run.scm_info.git_url.clone(),
+            sha: sha.clone(),
+            token: None,
+        }),
+        _ => None,
+    };
+
+    synchronik::CommandRequest {
+        commands,
+        callback: Some(callback.clone()),
+        timeout: yml_step.timeout.or(config.timeout),
+        env,
+        workspace,
+        source,
+    }
+}
+
+/*
+ * Send the commands to exactly one of the agents, trying them in the order preferred by
+ * the Selector until one accepts
+ */
+async fn dispatch<'a>(
+    client: &reqwest::Client,
+    commands: &synchronik::CommandRequest,
+    agents: &'a [Agent],
+    selector: &dyn Selector,
+) -> Option<(&'a Agent, synchronik::CommandResponse)> {
+    debug!("working {:?}", commands);
+
+    for agent in agents {
+        debug!("agent: {:?} can meet our needs", agent);
+
+        let res = client
+            .put(
+                agent
+                    .url
+                    .join("/api/v1/execute")
+                    .expect("Failed to join execute URL"),
+            )
+            .json(commands)
+            .send()
+            .await;
+
+        match res {
+            Ok(res) if res.status() == reqwest::StatusCode::CREATED => {
+                match res.json::<synchronik::CommandResponse>().await {
+                    Ok(response) => {
+                        selector.accepted(agent);
+                        return Some((agent, response));
+                    }
+                    Err(e) => error!("Failed to parse response from {}: {:?}", agent.name, e),
+                }
+            }
+            Ok(res) => debug!("Agent {} did not accept work: {}", agent.name, res.status()),
+            Err(e) => error!("Failed to send work to {}: {:?}", agent.name, e),
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ServerConfig;
+    use crate::models::{Project, RunDefinition, ScmInfo};
+    use sqlx::SqlitePool;
+    use tide::StatusCode;
+
+    async fn setup_state(agents: Vec<Agent>) -> AppState<'static> {
+        let pool = SqlitePool::connect(":memory:")
+            .await
+            .expect("Failed to setup_database()");
+        sqlx::migrate!()
+            .run(&pool)
+            .await
+            .expect("Failed to run migrations in a test");
+        let mut state = AppState::new(pool, ServerConfig::default());
+        state.agents = agents;
+        state
+    }
+
+    /*
+     * Start a fake agent which responds to every execute request with the given status
+     */
+    async fn fake_agent(status: StatusCode) -> Agent {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = Url::parse(&format!("http://{}", listener.local_addr().unwrap())).unwrap();
+
+        let mut app = tide::new();
+        app.at("/api/v1/execute").put(move |_| async move {
+            let mut response = tide::Response::new(status);
+            if status == StatusCode::Created {
+                response.set_body(tide::Body::from_json(&json!({
+                    "uuid" : uuid::Uuid::new_v4(),
+                    "stream" : null,
+                    "task" : null,
+                    "log" : "http://localhost/console.log",
+                }))?);
+            }
+            Ok(response)
+        });
+        async_std::task::spawn(app.listen(listener));
+        Agent::new(format!("fake-{}", status), url, vec![], vec![])
+    }
+
+    async fn queued_run(state: &AppState<'_>) -> Run {
+        let project = Project::new("test");
+        Project::create(&project, &state.db).await.unwrap();
+        let definition = RunDefinition::new("needs: []\ncommands:\n  - 'whoami'\n");
+        let run = Run::new(project, ScmInfo::default(), definition);
+        Run::create(&run, &state.db).await.unwrap()
+    }
+
+    fn first_step(run: &Run, config: &Yml) -> (Step, YmlStep) {
+        (plan(run, config).remove(0), config.step(0, 0).unwrap())
+    }
+
+    const STAGES: &str = r#"
+needs: []
+env:
+  LEVEL: 'yml'
+stages:
+  - name: 'build'
+    steps:
+      - name: 'compile'
+        commands: ['make']
+  - name: 'test'
+    steps:
+      - name: 'unit'
+        timeout: 60
+        env:
+          LEVEL: 'step'
+        commands: ['make check']
+      - name: 'lint'
+        commands: ['make lint']
+  - name: 'package'
+    steps:
+      - name: 'tarball'
+        commands: ['make dist']
+"#;
+
+    async fn statuses(state: &AppState<'_>, run: &Run) -> Vec<String> {
+        Run::find_by(&run.run.uuid, &state.db)
+            .await
+            .unwrap()
+            .steps
+            .into_iter()
+            .map(|step| step.status)
+            .collect()
+    }
+
+    async fn finish(state: &AppState<'_>, run: &Run, name: &str, status: RunStatus) {
+        let run = Run::find_by(&run.run.uuid, &state.db).await.unwrap();
+        let step = run.steps.iter().find(|s| s.name == name).unwrap();
+        Step::update_status(&step.uuid, status, &state.db)
+            .await
+            .unwrap();
+        let stopped = settle(state, &run.run.uuid).await.unwrap();
+        cancel_tasks(&state.http, stopped).await;
+    }
+
+    #[async_std::test]
+    async fn test_dispatch_stages() {
+        let state = setup_state(vec![fake_agent(StatusCode::Created).await]).await;
+        let project = Project::new("test");
+        Project::create(&project, &state.db).await.unwrap();
+        let run = Run::new(project, ScmInfo::default(), RunDefinition::new(STAGES));
+        let run = Run::create(&run, &state.db).await.unwrap();
+
+        dispatch_queued(&state).await.unwrap();
+        assert_eq!(
+            vec!["running", "queued", "queued", "queued"],
+            statuses(&state, &run).await
+        );
+        let fetched = Run::find_by(&run.run.uuid, &state.db).await.unwrap();
+        assert_eq!(RunStatus::Running.as_str(), fetched.run.status);
+        assert_eq!(None, fetched.run.agent, "Only steps record their agent");
+        assert_eq!(Some("fake-201".to_string()), fetched.steps[0].agent);
+
+        finish(&state, &run, "compile", RunStatus::Succeeded).await;
+        dispatch_queued(&state).await.unwrap();
+        assert_eq!(
+            vec!["succeeded", "running", "running", "queued"],
+            statuses(&state, &run).await
+        );
+
+        finish(&state, &run, "unit", RunStatus::Succeeded).await;
+        dispatch_queued(&state).await.unwrap();
+        assert_eq!(
+            vec!["succeeded", "succeeded", "running", "queued"],
+            statuses(&state, &run).await,
+            "The next stage waits for every step of the stage"
+        );
+
+        finish(&state, &run, "lint", RunStatus::Succeeded).await;
+        dispatch_queued(&state).await.unwrap();
+        finish(&state, &run, "tarball", RunStatus::Succeeded).await;
+        let fetched = Run::find_by(&run.run.uuid, &state.db).await.unwrap();
+        assert_eq!(RunStatus::Succeeded.as_str(), fetched.run.status);
+    }
+
+    #[async_std::test]
+    async fn test_dispatch_stages_fail_fast() {
+        let state = setup_state(vec![fake_agent(StatusCode::Created).await]).await;
+        let project = Project::new("test");
+        Project::create(&project, &state.db).await.unwrap();
+        let run = Run::new(project, ScmInfo::default(), RunDefinition::new(STAGES));
+        let run = Run::create(&run, &state.db).await.unwrap();
+
+        dispatch_queued(&state).await.unwrap();
+        finish(&state, &run, "compile", RunStatus::Succeeded).await;
+        dispatch_queued(&state).await.unwrap();
+        finish(&state, &run, "unit", RunStatus::Failed).await;
+
+        assert_eq!(
+            vec!["succeeded", "failed", "cancelled", "skipped"],
+            statuses(&state, &run).await
+        );
+        let fetched = Run::find_by(&run.run.uuid, &state.db).await.unwrap();
+        assert_eq!(RunStatus::Failed.as_str(), fetched.run.status);
+        assert!(fetched.run.finished_at.is_some());
+
+        dispatch_queued(&state).await.unwrap();
+        assert_eq!(
+            vec!["succeeded", "failed", "cancelled", "skipped"],
+            statuses(&state, &run).await
+        );
+    }
+
+    const CONDITIONS: &str = r#"
+needs: []
+jobs:
+  - name: 'test'
+    commands: ['make check']
+  - name: 'deploy'
+    if: "branch == 'release'"
+    depends_on: ['test']
+    commands: ['make deploy']
+  - name: 'publish'
+    depends_on: ['deploy']
+    commands: ['make publish']
+  - name: 'notify'
+    if: 'failure()'
+    depends_on: ['publish']
+    commands: ['./notify']
+  - name: 'cleanup'
+    if: 'always()'
+    depends_on: ['test']
+    commands: ['make clean']
+  - name: 'report'
+    if: 'always()'
+    depends_on: ['publish']
+    commands: ['./report']
+"#;
+
+    #[async_std::test]
+    async fn test_dispatch_conditions() {
+        let state = setup_state(vec![fake_agent(StatusCode::Created).await]).await;
+        let project = Project::new("test");
+        Project::create(&project, &state.db).await.unwrap();
+        let run = Run::new(project, ScmInfo::default(), RunDefinition::new(CONDITIONS));
+        let run = Run::create(&run, &state.db).await.unwrap();
+
+        dispatch_queued(&state).await.unwrap();
+        finish(&state, &run, "test", RunStatus::Succeeded).await;
+        dispatch_queued(&state).await.unwrap();
+        assert_eq!(
+            vec![
+                "succeeded",
+                "skipped",
+                "skipped",
+                "skipped",
+                "running",
+                "running"
+            ],
+            statuses(&state, &run).await,
+            "Steps depending on a skipped step are skipped unless they check the status"
+        );
+
+        finish(&state, &run, "cleanup", RunStatus::Succeeded).await;
+        finish(&state, &run, "report", RunStatus::Succeeded).await;
+        let fetched = Run::find_by(&run.run.uuid, &state.db).await.unwrap();
+        assert_eq!(RunStatus::Succeeded.as_str(), fetched.run.status);
+    }
+
+    #[async_std::test]
+    async fn test_dispatch_conditions_after_failure() {
+        let state = setup_state(vec![fake_agent(StatusCode::Created).await]).await;
+        let project = Project::new("test");
+        Project::create(&project, &state.db).await.unwrap();
+        let mut run = Run::new(project, ScmInfo::default(), RunDefinition::new(CONDITIONS));
+        run.scm_info.r#ref = "refs/heads/release".into();
+        let run = Run::create(&run, &state.db).await.unwrap();
+
+        dispatch_queued(&state).await.unwrap();
+        finish(&state, &run, "test", RunStatus::Failed).await;
+        assert_eq!(
+            vec!["failed", "skipped", "skipped", "queued", "queued", "queued"],
+            statuses(&state, &run).await
+        );
+        let fetched = Run::find_by(&run.run.uuid, &state.db).await.unwrap();
+        assert!(
+            fetched.run.finished_at.is_none(),
+            "The run waits for its cleanup steps"
+        );
+
+        dispatch_queued(&state).await.unwrap();
+        assert_eq!(
+            vec!["failed", "skipped", "skipped", "running", "running", "running"],
+            statuses(&state, &run).await
+        );
+
+        finish(&state, &run, "notify", RunStatus::Succeeded).await;
+        finish(&state, &run, "cleanup", RunStatus::Succeeded).await;
+        finish(&state, &run, "report", RunStatus::Succeeded).await;
+        let fetched = Run::find_by(&run.run.uuid, &state.db).await.unwrap();
+        assert_eq!(RunStatus::Failed.as_str(), fetched.run.status);
+        assert!(fetched.run.finished_at.is_some());
+    }
+
+    #[async_std::test]
+    async fn test_dispatch_jobs() {
+        let state = setup_state(vec![fake_agent(StatusCode::Created).await]).await;
+        let project = Project::new("test");
+        Project::create(&project, &state.db).await.unwrap();
+        let definition = r#"
+needs: []
+jobs:
+  - name: 'core'
+    commands: ['make core']
+  - name: 'slow'
+    depends_on: ['core']
+    commands: ['make slow']
+  - name: 'fast'
+    depends_on: ['core']
+    commands: ['make fast']
+  - name: 'docs'
+    depends_on: ['fast']
+    commands: ['make docs']
+  - name: 'package'
+    depends_on: ['slow', 'docs']
+    commands: ['make dist']
+"#;
+        let run = Run::new(project, ScmInfo::default(), RunDefinition::new(definition));
+        let run = Run::create(&run, &state.db).await.unwrap();
+
+        dispatch_queued(&state).await.unwrap();
+        assert_eq!(
+            vec!["running", "queued", "queued", "queued", "queued"],
+            statuses(&state, &run).await
+        );
+
+        finish(&state, &run, "core", RunStatus::Succeeded).await;
+        dispatch_queued(&state).await.unwrap();
+        assert_eq!(
+            vec!["succeeded", "running", "running", "queued", "queued"],
+            statuses(&state, &run).await
+        );
+
+        finish(&state, &run, "fast", RunStatus::Succeeded).await;
+        dispatch_queued(&state).await.unwrap();
+        assert_eq!(
+            vec!["succeeded", "running", "succeeded", "running", "queued"],
+            statuses(&state, &run).await,
+            "Jobs start as soon as their own dependencies have succeeded"
+        );
+
+        finish(&state, &run, "docs", RunStatus::Succeeded).await;
+        dispatch_queued(&state).await.unwrap();
+        assert_eq!("queued", statuses(&state, &run).await[4]);
+
+        finish(&state, &run, "slow", RunStatus::Succeeded).await;
+        dispatch_queued(&state).await.unwrap();
+        finish(&state, &run, "package", RunStatus::Succeeded).await;
+        let fetched = Run::find_by(&run.run.uuid, &state.db).await.unwrap();
+        assert_eq!(RunStatus::Succeeded.as_str(), fetched.run.status);
+    }
+
+    #[async_std::test]
+    async fn test_dispatch_matrix() {
+        let mut stable = fake_agent(StatusCode::Created).await;
+        stable.name = "stable".into();
+        stable.capabilities = vec![synchronik::Capability::with_name("rust-stable")];
+        let mut beta = fake_agent(StatusCode::Created).await;
+        beta.name = "beta".into();
+        beta.capabilities = vec![synchronik::Capability::with_name("rust-beta")];
+        let state = setup_state(vec![stable, beta]).await;
+        let project = Project::new("test");
+        Project::create(&project, &state.db).await.unwrap();
+        let definition = r#"
+needs: ['rust-${matrix.rust}']
+matrix:
+  rust: ['stable', 'beta']
+commands: ['cargo test']
+"#;
+        let run = Run::new(project, ScmInfo::default(), RunDefinition::new(definition));
+        let run = Run::create(&run, &state.db).await.unwrap();
+
+        dispatch_queued(&state).await.unwrap();
+        let fetched = Run::find_by(&run.run.uuid, &state.db).await.unwrap();
+        let agents: Vec<Option<&str>> = fetched.steps.iter().map(|s| s.agent.as_deref()).collect();
+        assert_eq!(vec![Some("stable"), Some("beta")], agents);
+        assert_eq!(None, fetched.run.agent);
+
+        finish(&state, &run, "default (stable)", RunStatus::Succeeded).await;
+        let fetched = Run::find_by(&run.run.uuid, &state.db).await.unwrap();
+        assert_eq!(RunStatus::Running.as_str(), fetched.run.status);
+        finish(&state, &run, "default (beta)", RunStatus::Succeeded).await;
+        let fetched = Run::find_by(&run.run.uuid, &state.db).await.unwrap();
+        assert_eq!(RunStatus::Succeeded.as_str(), fetched.run.status);
+    }
+
+    #[async_std::test]
+    async fn test_dispatch_matrix_failure() {
+        for (fail_fast, siblings) in [(false, "running"), (true, "cancelled")] {
+            let state = setup_state(vec![fake_agent(StatusCode::Created).await]).await;
+            let project = Project::new("test");
+            Project::create(&project, &state.db).await.unwrap();
+            let definition = format!(
+                r#"
+needs: []
+jobs:
+  - name: 'test'
+    matrix:
+      os: ['linux', 'freebsd', 'macos']
+      fail_fast: {}
+    commands: ['make check']
+  - name: 'package'
+    depends_on: ['test']
+    commands: ['make dist']
+"#,
+                fail_fast
+            );
+            let run = Run::new(project, ScmInfo::default(), RunDefinition::new(&definition));
+            let run = Run::create(&run, &state.db).await.unwrap();
+
+            dispatch_queued(&state).await.unwrap();
+            finish(&state, &run, "test (linux)", RunStatus::Failed).await;
+            dispatch_queued(&state).await.unwrap();
+            assert_eq!(
+                vec!["failed", siblings, siblings, "skipped"],
+                statuses(&state, &run).await,
+                "fail_fast: {}",
+                fail_fast
+            );
+            if fail_fast {
+                continue;
+            }
+
+            /*
+             * The Run fails once the other combinations have finished
+             */
+            finish(&state, &run, "test (freebsd)", RunStatus::Succeeded).await;
+            let fetched = Run::find_by(&run.run.uuid, &state.db).await.unwrap();
+            assert_eq!(RunStatus::Running.as_str(), fetched.run.status);
+            finish(&state, &run, "test (macos)", RunStatus::Succeeded).await;
+            let fetched = Run::find_by(&run.run.uuid, &state.db).await.unwrap();
+            assert_eq!(RunStatus::Failed.as_str(), fetched.run.status);
+        }
+    }
+
+    #[async_std::test]
+    async fn test_command_request_step() {
+        let state = setup_state(vec![]).await;
+        let run = queued_run(&state).await;
+        let config: Yml = serde_yaml::from_str(STAGES).unwrap();
+        let callback = Url::parse("http://localhost/callback").unwrap();
+        let steps = plan(&run, &config);
+        assert_eq!(4, steps.len());
+
+        let request = command_request(
+            &run,
+            None,
+            &config,
+            &steps[1],
+            &config.step(1, 0).unwrap(),
+            &callback,
+        );
+        assert_eq!(
+            vec![synchronik::Command::with_script("make check")],
+            request.commands
+        );
+        assert_eq!(Some(60), request.timeout);
+        assert_eq!("step", request.env["LEVEL"]);
+        assert_eq!("test", request.env["SYNCHRONIK_STAGE"]);
+        assert_eq!("unit", request.env["SYNCHRONIK_STEP"]);
+
+        let request = command_request(
+            &run,
+            None,
+            &config,
+            &steps[2],
+            &config.step(1, 1).unwrap(),
+            &callback,
+        );
+        assert_eq!("yml", request.env["LEVEL"]);
+    }
+
+    #[async_std::test]
+    async fn test_dispatch_without_agents() {
+        let state = setup_state(vec![]).await;
+        let run = queued_run(&state).await;
+
+        dispatch_queued(&state).await.unwrap();
+        let run = Run::find_by(&run.run.uuid, &state.db).await.unwrap();
+        assert_eq!(RunStatus::Queued.as_str(), run.run.status);
+    }
+
+    #[async_std::test]
+    async fn test_dispatch_busy_agent() {
+        let state = setup_state(vec![fake_agent(StatusCode::Conflict).await]).await;
+        let run = queued_run(&state).await;
+
+        dispatch_queued(&state).await.unwrap();
+        let run = Run::find_by(&run.run.uuid, &state.db).await.unwrap();
+        assert_eq!(RunStatus::Queued.as_str(), run.run.status);
+        assert_eq!(
+            vec!["queued"],
+            statuses(&state, &run).await,
+            "The step should be back in the queue"
+        );
+    }
+
+    #[async_std::test]
+    async fn test_dispatch_outside_lock() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::sync::Arc;
+
+        #[derive(Clone)]
+        struct Agent {
+            lock: Arc<async_std::sync::Mutex<()>>,
+            db: SqlitePool,
+            url: Url,
+            locked: Arc<AtomicBool>,
+            stopped: Arc<AtomicBool>,
+        }
+
+        /*
+         * The agent checks the lock and the step while it is being asked to execute it,
+         * and cancels the run before accepting
+         */
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = Url::parse(&format!("http://{}", listener.local_addr().unwrap())).unwrap();
+        let mut state = setup_state(vec![]).await;
+        let agent = Agent {
+            lock: state.dispatch_lock.clone(),
+            db: state.db.clone(),
+            url: url.clone(),
+            locked: Arc::new(AtomicBool::new(true)),
+            stopped: Arc::new(AtomicBool::new(false)),
+        };
+        let mut app = tide::with_state(agent.clone());
+        app.at("/api/v1/execute")
+            .put(|req: tide::Request<Agent>| async move {
+                let agent = req.state();
+                agent
+                    .locked
+                    .store(agent.lock.try_lock().is_none(), Ordering::SeqCst);
+                let run = Run::active(&agent.db).await?.remove(0);
+                assert_eq!(RunStatus::Pending, run.steps[0].status());
+                Step::cancel_unfinished(&run.run.uuid, &agent.db).await?;
+                Run::update_status(&run.run.uuid, RunStatus::Cancelled, &agent.db).await?;
+
+                let mut response = tide::Response::new(StatusCode::Created);
+                response.set_body(tide::Body::from_json(&json!({
+                    "uuid" : uuid::Uuid::new_v4(),
+                    "stream" : null,
+                    "task" : agent.url.join("/api/v1/tasks/1")?,
+                    "log" : "http://localhost/console.log",
+                }))?);
+                Ok(response)
+            });
+        app.at("/api/v1/tasks/1/cancel")
+            .post(|req: tide::Request<Agent>| async move {
+                req.state().stopped.store(true, Ordering::SeqCst);
+                Ok(tide::Response::new(StatusCode::Ok))
+            });
+        async_std::task::spawn(app.listen(listener));
+        state.agents = vec![crate::config::Agent::new(
+            "slow".into(),
+            url,
+            vec![],
+            vec![],
+        )];
+        let run = queued_run(&state).await;
+
+        dispatch_queued(&state).await.unwrap();
+        assert!(
+            !agent.locked.load(Ordering::SeqCst),
+            "The dispatch lock should not be held while the agent is asked"
+        );
+        assert!(
+            agent.stopped.load(Ordering::SeqCst),
+            "The task of the cancelled step should be stopped"
+        );
+        let run = Run::find_by(&run.run.uuid, &state.db).await.unwrap();
+        assert_eq!(RunStatus::Cancelled.as_str(), run.run.status);
+        assert_eq!(vec!["cancelled"], statuses(&state, &run).await);
+        assert_eq!(Some("slow".to_string()), run.steps[0].agent);
+    }
+
+    #[async_std::test]
+    async fn test_dispatch_skips_busy_agent() {
+        let state = setup_state(vec![
+            fake_agent(StatusCode::Conflict).await,
+            fake_agent(StatusCode::Created).await,
+        ])
+        .await;
+        let run = queued_run(&state).await;
+
+        dispatch_queued(&state).await.unwrap();
+        let run = Run::find_by(&run.run.uuid, &state.db).await.unwrap();
+        assert_eq!(RunStatus::Running.as_str(), run.run.status);
+        assert_eq!(Some("fake-201".to_string()), run.run.agent);
+        assert_eq!(
+            Some("http://localhost/console.log".to_string()),
+            run.run.log_url
+        );
+    }
+
+    #[async_std::test]
+    async fn test_command_request_env() {
+        let state = setup_state(vec![]).await;
+        let mut run = queued_run(&state).await;
+        run.run.parameters = r#"{"GREETING":"bonjour"}"#.into();
+        let config: Yml = serde_yaml::from_str(
+            "needs: []\nenv:\n  GREETING: 'hello'\n  NAME: 'world'\n  SYNCHRONIK_PROJECT: 'override'\ncommands: []\n",
+        )
+        .unwrap();
+        let callback = Url::parse("http://localhost/callback").unwrap();
+
+        let (step, yml_step) = first_step(&run, &config);
+        let request = command_request(&run, None, &config, &step, &yml_step, &callback);
+        assert_eq!("bonjour", request.env["GREETING"]);
+        assert_eq!("world", request.env["NAME"]);
+        assert_eq!(run.run.uuid, request.env["SYNCHRONIK_RUN_UUID"]);
+        assert_eq!("1", request.env["SYNCHRONIK_RUN_NUMBER"]);
+        assert_eq!("test", request.env["SYNCHRONIK_PROJECT"]);
+        assert_eq!("main", request.env["SYNCHRONIK_REF"]);
+        assert_eq!(None, request.workspace);
+        assert_eq!(None, request.source);
+    }
+
+    #[async_std::test]
+    async fn test_command_request_source() {
+        let state = setup_state(vec![]).await;
+        let mut run = queued_run(&state).await;
+        run.scm_info.sha = Some("0123abcd".into());
+        let config: Yml = serde_yaml::from_str("needs: []\ncommands: []\n").unwrap();
+        let callback = Url::parse("http://localhost/callback").unwrap();
+
+        let (step, yml_step) = first_step(&run, &config);
+        let request = command_request(&run, None, &config, &step, &yml_step, &callback);
+        assert_eq!(
+            Some(synchronik::Source {
+                url: run.scm_info.git_url.clone(),
+                sha: "0123abcd".into(),
+                token: None,
+            }),
+            request.source
+        );
+    }
+
+    #[async_std::test]
+    async fn test_command_request_persistent_workspace() {
+        let state = setup_state(vec![]).await;
+        let run = queued_run(&state).await;
+        let config: Yml = serde_yaml::from_str("needs: []\ncommands: []\n").unwrap();
+        let project: crate::config::Project =
+            serde_yaml::from_str("description: 'test'\nworkspace: 'persistent'\n").unwrap();
+        let callback = Url::parse("http://localhost/callback").unwrap();
+
+        let (step, yml_step) = first_step(&run, &config);
+        let request = command_request(&run, Some(&project), &config, &step, &yml_step, &callback);
+        assert_eq!(Some("test".to_string()), request.workspace);
+    }
+
+    #[async_std::test]
+    async fn test_dispatch_running_without_steps() {
+        let state = setup_state(vec![fake_agent(StatusCode::Created).await]).await;
+        let run = queued_run(&state).await;
+        Run::update_status(&run.run.uuid, RunStatus::Running, &state.db)
+            .await
+            .unwrap();
+
+        let project = Project::new("invalid");
+        Project::create(&project, &state.db).await.unwrap();
+        let invalid = Run::new(
+            project,
+            ScmInfo::default(),
+            RunDefinition::new("- not a yml"),
+        );
+        let invalid = Run::create(&invalid, &state.db).await.unwrap();
+        Run::update_status(&invalid.run.uuid, RunStatus::Running, &state.db)
+            .await
+            .unwrap();
+
+        dispatch_queued(&state).await.unwrap();
+        let fetched = Run::find_by(&run.run.uuid, &state.db).await.unwrap();
+        assert!(
+            fetched.steps.is_empty(),
+            "The run must not be dispatched again"
+        );
+        assert_eq!(None, fetched.run.agent);
+        assert_eq!(RunStatus::Running.as_str(), fetched.run.status);
+
+        let fetched = Run::find_by(&invalid.run.uuid, &state.db).await.unwrap();
+        assert_eq!(RunStatus::Running.as_str(), fetched.run.status);
+    }
+
+    #[async_std::test]
+    async fn test_dispatch_invalid_definition() {
+        let state = setup_state(vec![fake_agent(StatusCode::Created).await]).await;
+        let project = Project::new("test");
+        Project::create(&project, &state.db).await.unwrap();
+        let run = Run::new(
+            project,
+            ScmInfo::default(),
+            RunDefinition::new("- not a yml"),
+        );
+        let run = Run::create(&run, &state.db).await.unwrap();
+
+        dispatch_queued(&state).await.unwrap();
+        let run = Run::find_by(&run.run.uuid, &state.db).await.unwrap();
+        assert_eq!(RunStatus::Failed.as_str(), run.run.status);
+    }
+}
@@ -0,0 +1,188 @@
+/**
+ * The graph module lays out the steps of a Run for drawing on the run page. Each step is
+ * placed in the column after the last of the steps it depends on, with the steps of a
+ * column stacked in the order they are defined in.
+ */
+use serde::Serialize;
+
+use crate::models::{RunStatus, Step};
+
+const NODE_WIDTH: i64 = 160;
+const NODE_HEIGHT: i64 = 40;
+const COLUMN_GAP: i64 = 60;
+const ROW_GAP: i64 = 20;
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Graph {
+    pub width: i64,
+    pub height: i64,
+    pub node_width: i64,
+    pub node_height: i64,
+    pub nodes: Vec<Node>,
+    pub edges: Vec<Edge>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Node {
+    pub x: i64,
+    pub y: i64,
+    // Centre of the node, where its name is drawn
+    pub label_x: i64,
+    pub label_y: i64,
+    pub stage: String,
+    pub name: String,
+    pub status: String,
+    // Fill colour of the node, matching the Bootstrap colour of its status
+    pub color: &'static str,
+}
+
+/*
+ * A curve from the right of a step to the left of a step which depends on it
+ */
+#[derive(Clone, Debug, Serialize)]
+pub struct Edge {
+    pub path: String,
+}
+
+/*
+ * Lay out the steps, which must be in the same order as the dependencies of the Yml they
+ * were planned from
+ */
+pub fn layout(steps: &[Step], dependencies: &[Vec<usize>]) -> Option<Graph> {
+    if steps.is_empty() || steps.len() != dependencies.len() {
+        return None;
+    }
+
+    /*
+     * The dependencies have been validated to be free of cycles, so the columns settle
+     * within as many passes as there are steps
+     */
+    let mut columns = vec![0; steps.len()];
+    for _ in 0..steps.len() {
+        let mut changed = false;
+        for (i, depends_on) in dependencies.iter().enumerate() {
+            let column = depends_on
+                .iter()
+                .map(|d| columns[*d] + 1)
+                .max()
+                .unwrap_or(0);
+            if column != columns[i] {
+                columns[i] = column;
+                changed = true;
+            }
+        }
+        if !changed {
+            break;
+        }
+    }
+
+    let mut rows = vec![0; steps.len()];
+    let mut heights: Vec<i64> = vec![];
+    for (i, column) in columns.iter().enumerate() {
+        if heights.len() <= *column {
+            heights.resize(column + 1, 0);
+        }
+        rows[i] = heights[*column];
+        heights[*column] += 1;
+    }
+
+    let position = |i: usize| {
+        (
+            columns[i] as i64 * (NODE_WIDTH + COLUMN_GAP),
+            rows[i] * (NODE_HEIGHT + ROW_GAP),
+        )
+    };
+
+    let nodes = steps
+        .iter()
+        .enumerate()
+        .map(|(i, step)| {
+            let (x, y) = position(i);
+            Node {
+                x,
+                y,
+                label_x: x + NODE_WIDTH / 2,
+                label_y: y + NODE_HEIGHT / 2,
+                stage: step.stage_name.clone(),
+                name: step.name.clone(),
+                status: step.status.clone(),
+                color: color(step.status()),
+            }
+        })
+        .collect();
+
+    let mut edges = vec![];
+    for (i, depends_on) in dependencies.iter().enumerate() {
+        for d in depends_on {
+            let (x1, y1) = position(*d);
+            let (x2, y2) = position(i);
+            let (x1, y1, y2) = (x1 + NODE_WIDTH, y1 + NODE_HEIGHT / 2, y2 + NODE_HEIGHT / 2);
+            let middle = (x1 + x2) / 2;
+            edges.push(Edge {
+                path: format!(
+                    "M {} {} C {} {}, {} {}, {} {}",
+                    x1, y1, middle, y1, middle, y2, x2, y2
+                ),
+            });
+        }
+    }
+
+    let width = heights.len() as i64 * (NODE_WIDTH + COLUMN_GAP) - COLUMN_GAP;
+    let height = heights.iter().max().copied().unwrap_or(0) * (NODE_HEIGHT + ROW_GAP) - ROW_GAP;
+    Some(Graph {
+        width,
+        height,
+        node_width: NODE_WIDTH,
+        node_height: NODE_HEIGHT,
+        nodes,
+        edges,
+    })
+}
+
+fn color(status: RunStatus) -> &'static str {
+    match status {
+        RunStatus::Succeeded => "#198754",
+        RunStatus::Failed | RunStatus::TimedOut => "#dc3545",
+        RunStatus::Running | RunStatus::Pending => "#0d6efd",
+        RunStatus::Cancelled => "#6c757d",
+        RunStatus::Queued => "#adb5bd",
+        RunStatus::Skipped => "#ced4da",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_layout() {
+        let steps: Vec<Step> = ["core", "test", "package", "docs"]
+            .iter()
+            .enumerate()
+            .map(|(i, name)| Step::new("run", 0, i, "jobs", name))
+            .collect();
+        /*
+         * The package step depends on the docs step, which is defined after it
+         */
+        let dependencies = vec![vec![], vec![0], vec![3], vec![0]];
+
+        let graph = layout(&steps, &dependencies).expect("Failed to lay out the graph");
+        let columns: Vec<i64> = graph
+            .nodes
+            .iter()
+            .map(|n| n.x / (NODE_WIDTH + COLUMN_GAP))
+            .collect();
+        assert_eq!(vec![0, 1, 2, 1], columns);
+        let rows: Vec<i64> = graph
+            .nodes
+            .iter()
+            .map(|n| n.y / (NODE_HEIGHT + ROW_GAP))
+            .collect();
+        assert_eq!(vec![0, 0, 0, 1], rows);
+        assert_eq!(3, graph.edges.len());
+        assert_eq!(3 * NODE_WIDTH + 2 * COLUMN_GAP, graph.width);
+        assert_eq!(2 * NODE_HEIGHT + ROW_GAP, graph.height);
+
+        assert!(layout(&steps, &dependencies[1..]).is_none());
+    }
+}
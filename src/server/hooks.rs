@@ -0,0 +1,727 @@
+/**
+ * The hooks module receives the webhooks sent by source control systems when commits are
+ * pushed, and enqueues runs of the projects building the repository and ref they name.
+ */
+use std::collections::HashMap;
+
+use hmac::{Hmac, Mac};
+use log::*;
+use serde::Deserialize;
+use sha2::Sha256;
+use tide::{Body, Request, Response, StatusCode};
+
+use crate::config::Scm;
+use crate::routes::api::enqueue;
+use crate::AppState;
+
+/*
+ * What a webhook asks to be built
+ */
+#[derive(Clone, Debug)]
+struct Trigger {
+    // Kind of event which caused the Runs to be created
+    kind: &'static str,
+    // Ref recorded with the Runs
+    scm_ref: String,
+    // Commit the Runs are pinned to, otherwise the ref is resolved
+    sha: Option<String>,
+    sender: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct GitHubRepository {
+    full_name: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct GitHubUser {
+    login: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct GitHubPush {
+    r#ref: String,
+    after: String,
+    #[serde(default)]
+    deleted: bool,
+    repository: GitHubRepository,
+    sender: Option<GitHubUser>,
+}
+
+#[derive(Debug, Deserialize)]
+struct GitHubPullRequestEvent {
+    action: String,
+    number: u64,
+    pull_request: GitHubPullRequest,
+    repository: GitHubRepository,
+    sender: Option<GitHubUser>,
+}
+
+#[derive(Debug, Deserialize)]
+struct GitHubPullRequest {
+    head: GitHubBranch,
+    base: GitHubBranch,
+}
+
+#[derive(Debug, Deserialize)]
+struct GitHubBranch {
+    r#ref: String,
+    sha: String,
+    // Missing when the repository of a fork has been deleted
+    repo: Option<GitHubRepository>,
+}
+
+/*
+ * Body of the generic webhook, naming the repository by the URL it is cloned from
+ */
+#[derive(Debug, Deserialize)]
+struct GenericHook {
+    url: String,
+    r#ref: String,
+    sha: Option<String>,
+    sender: Option<String>,
+}
+
+/**
+ *  POST /api/v1/hooks/github
+ *
+ *  Enqueue runs for pushes to, and pull requests against, the refs of GitHub projects
+ */
+pub async fn github(mut req: Request<AppState<'static>>) -> tide::Result {
+    let body = req.body_bytes().await?;
+    let state = req.state();
+    if let Some(response) = check_signature(
+        state.config.hooks.github.as_deref(),
+        req.header("X-Hub-Signature-256").map(|h| h.as_str()),
+        &body,
+    ) {
+        return Ok(response);
+    }
+
+    let event = req
+        .header("X-GitHub-Event")
+        .map(|h| h.as_str().to_string())
+        .unwrap_or_default();
+    debug!("Received the GitHub {} event", event);
+
+    let (repository, branch, trigger) = match event.as_str() {
+        "push" => {
+            let push: GitHubPush = parse(&body)?;
+            if push.deleted {
+                debug!("Ignoring the deletion of {}", push.r#ref);
+                return ignore();
+            }
+            (
+                push.repository.full_name,
+                push.r#ref.clone(),
+                Trigger {
+                    kind: "push",
+                    scm_ref: push.r#ref,
+                    sha: Some(push.after),
+                    sender: push.sender.map(|s| s.login),
+                },
+            )
+        }
+        "pull_request" => {
+            let event: GitHubPullRequestEvent = parse(&body)?;
+            if !["opened", "synchronize", "reopened"].contains(&event.action.as_str()) {
+                debug!("Ignoring the pull request action {}", event.action);
+                return ignore();
+            }
+            /*
+             * The definition is read from the head of the pull request, so pull requests
+             * from forks would be able to run anything on the agents
+             */
+            let head = event.pull_request.head.repo.map(|r| r.full_name);
+            if head.as_deref() != Some(event.repository.full_name.as_str()) {
+                info!(
+                    "Ignoring pull request #{} from {:?} to {}",
+                    event.number, head, event.repository.full_name
+                );
+                return ignore();
+            }
+            debug!(
+                "Pull request #{} from {}",
+                event.number, event.pull_request.head.r#ref
+            );
+            (
+                event.repository.full_name,
+                event.pull_request.base.r#ref,
+                Trigger {
+                    kind: "pull_request",
+                    scm_ref: format!("refs/pull/{}/head", event.number),
+                    sha: Some(event.pull_request.head.sha),
+                    sender: event.sender.map(|s| s.login),
+                },
+            )
+        }
+        _ => {
+            debug!("Ignoring the GitHub {} event", event);
+            return ignore();
+        }
+    };
+
+    let projects: Vec<String> = state
+        .config
+        .projects
+        .iter()
+        .filter(|(_, project)| match &project.scm {
+            Scm::GitHub {
+                owner,
+                repo,
+                scm_ref,
+                ..
+            } => {
+                format!("{}/{}", owner, repo).eq_ignore_ascii_case(&repository)
+                    && same_ref(scm_ref, &branch)
+            }
+            _ => false,
+        })
+        .map(|(name, _)| name.clone())
+        .collect();
+    accept(state, projects, trigger)
+}
+
+/**
+ *  POST /api/v1/hooks/generic
+ *
+ *  Enqueue runs for the projects building the ref of the repository, for any source
+ *  control system which can send a webhook
+ */
+pub async fn generic(mut req: Request<AppState<'static>>) -> tide::Result {
+    let body = req.body_bytes().await?;
+    let state = req.state();
+    if let Some(response) = check_signature(
+        state.config.hooks.generic.as_deref(),
+        req.header("X-Synchronik-Signature-256").map(|h| h.as_str()),
+        &body,
+    ) {
+        return Ok(response);
+    }
+
+    let hook: GenericHook = parse(&body)?;
+    let projects: Vec<String> = state
+        .config
+        .projects
+        .iter()
+        .filter(|(_, project)| {
+            let scm_ref = match &project.scm {
+                Scm::Nonexistent => return false,
+                Scm::GitHub { scm_ref, .. }
+                | Scm::Git { scm_ref, .. }
+                | Scm::GitLab { scm_ref, .. }
+                | Scm::Gitea { scm_ref, .. } => scm_ref,
+            };
+            match crate::scm::git_url(&project.scm, &state.config) {
+                Ok(url) => same_repository(&url, &hook.url) && same_ref(scm_ref, &hook.r#ref),
+                Err(_) => false,
+            }
+        })
+        .map(|(name, _)| name.clone())
+        .collect();
+
+    let trigger = Trigger {
+        kind: "webhook",
+        scm_ref: hook.r#ref,
+        sha: hook.sha,
+        sender: hook.sender,
+    };
+    accept(state, projects, trigger)
+}
+
+/*
+ * Enqueue the Runs of the projects in the background and accept the webhook straight
+ * away, since resolving their definitions can take longer than source control systems
+ * wait before delivering the webhook again
+ */
+fn accept(state: &AppState<'static>, projects: Vec<String>, trigger: Trigger) -> tide::Result {
+    if projects.is_empty() {
+        return ignore();
+    }
+    let response = synchronik::HookResponse {
+        projects: projects.clone(),
+    };
+    let state = state.clone();
+    async_std::task::spawn(async move { trigger_projects(&state, &projects, &trigger).await });
+
+    let mut res = Response::new(StatusCode::Accepted);
+    res.set_body(Body::from_json(&response)?);
+    Ok(res)
+}
+
+/*
+ * Enqueue a Run of every project for the trigger. A project which cannot be resolved at
+ * the commit, has parameters without defaults, or cannot be enqueued is skipped so that it
+ * does not prevent the other projects from being built
+ */
+async fn trigger_projects(state: &AppState<'_>, projects: &[String], trigger: &Trigger) {
+    for name in projects {
+        let project = match state.config.projects.get(name) {
+            Some(project) => project,
+            None => continue,
+        };
+        let mut resolved = match crate::scm::resolve(
+            name,
+            project,
+            &state.config,
+            &state.http,
+            trigger.sha.as_deref(),
+        )
+        .await
+        {
+            Ok(resolved) => resolved,
+            Err(e) => {
+                error!("Failed to resolve the definition of {}: {:?}", name, e);
+                continue;
+            }
+        };
+        resolved.scm_info.r#ref = trigger.scm_ref.clone();
+
+        let parameters = match crate::parameters::resolve(
+            &project.parameters(Some(&resolved.config)),
+            &HashMap::new(),
+        ) {
+            Ok(parameters) => parameters,
+            Err(e) => {
+                warn!("Cannot trigger {} from a webhook: {}", name, e);
+                continue;
+            }
+        };
+
+        if let Err(e) = enqueue(
+            state,
+            name,
+            resolved,
+            &parameters,
+            trigger.kind,
+            trigger.sender.as_deref(),
+        )
+        .await
+        {
+            error!("Failed to enqueue a run of {} for a webhook: {:?}", name, e);
+        }
+    }
+}
+
+fn parse<'a, T: Deserialize<'a>>(body: &'a [u8]) -> tide::Result<T> {
+    serde_json::from_slice(body).map_err(|e| tide::Error::new(StatusCode::BadRequest, e))
+}
+
+/*
+ * Respond to a webhook which doesn't trigger any projects
+ */
+fn ignore() -> tide::Result {
+    let response = synchronik::HookResponse { projects: vec![] };
+    Ok(Body::from_json(&response)?.into())
+}
+
+/*
+ * Reject the webhook unless its secret is configured and the body carries a valid
+ * signature, returning the response to reject it with
+ */
+fn check_signature(secret: Option<&str>, signature: Option<&str>, body: &[u8]) -> Option<Response> {
+    match secret {
+        None => {
+            debug!("Received a webhook which has no secret configured");
+            Some(Response::new(StatusCode::NotFound))
+        }
+        Some(secret) if !verify(secret, signature, body) => {
+            warn!("Received a webhook with an invalid signature");
+            Some(Response::new(StatusCode::Unauthorized))
+        }
+        Some(_) => None,
+    }
+}
+
+/*
+ * Check the signature is sha256= followed by the hex encoded HMAC of the body
+ */
+fn verify(secret: &str, signature: Option<&str>, body: &[u8]) -> bool {
+    let expected = match signature
+        .and_then(|s| s.strip_prefix("sha256="))
+        .and_then(|s| hex::decode(s).ok())
+    {
+        Some(expected) => expected,
+        None => return false,
+    };
+
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(body);
+    mac.verify_slice(&expected).is_ok()
+}
+
+/*
+ * Refs match whether or not they are given in full, a ref without the refs/ prefix being
+ * the name of a branch such as main for refs/heads/main. A tag is only matched in full, so
+ * that it is not mistaken for a branch of the same name
+ */
+fn same_ref(configured: &str, received: &str) -> bool {
+    let full = |r: &str| match r.starts_with("refs/") {
+        true => r.to_string(),
+        false => format!("refs/heads/{}", r),
+    };
+    full(configured) == full(received)
+}
+
+/*
+ * Clone URLs match regardless of a trailing .git or slash
+ */
+fn same_repository(configured: &str, received: &str) -> bool {
+    let normalize = |url: &str| {
+        url.trim_end_matches('/')
+            .trim_end_matches(".git")
+            .to_lowercase()
+    };
+    normalize(configured) == normalize(received)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ServerConfig;
+    use crate::models::Run;
+    use sqlx::SqlitePool;
+    use tide::http::{Method, Url};
+
+    use crate::scm::tests::{forge, SHA};
+
+    /*
+     * Create the Project of everything configured, serving both webhooks
+     */
+    async fn setup_app(config: ServerConfig) -> tide::Server<AppState<'static>> {
+        let pool = SqlitePool::connect(":memory:")
+            .await
+            .expect("Failed to setup_database()");
+        sqlx::migrate!()
+            .run(&pool)
+            .await
+            .expect("Failed to run migrations in a test");
+        for name in config.projects.keys() {
+            crate::models::Project::create(&crate::models::Project::new(name), &pool)
+                .await
+                .unwrap();
+        }
+
+        let mut app = tide::with_state(AppState::new(pool, config));
+        app.at("/api/v1/hooks/github").post(github);
+        app.at("/api/v1/hooks/generic").post(generic);
+        app
+    }
+
+    /*
+     * The projects are read from a git repository so that the runs can be pinned to a
+     * commit without talking to GitHub
+     */
+    fn local_config(repo: &std::path::Path) -> ServerConfig {
+        serde_yaml::from_str(&format!(
+            r#"
+agents: {{}}
+hooks:
+  generic: 'generic-secret'
+projects:
+  'local':
+    description: 'A local repository'
+    scm:
+      git:
+        url: 'file://{}'
+        ref: 'main'
+  'other':
+    description: 'Another branch'
+    scm:
+      git:
+        url: 'file://{}'
+        ref: 'develop'
+  'broken':
+    description: 'Without a definition'
+    filename: 'missing.yml'
+    scm:
+      git:
+        url: 'file://{}'
+        ref: 'main'
+"#,
+            repo.display(),
+            repo.display(),
+            repo.display()
+        ))
+        .unwrap()
+    }
+
+    fn sign(secret: &str, body: &str) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(body.as_bytes());
+        format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+    }
+
+    fn hook(path: &str, header: &str, signature: &str, body: &str) -> tide::http::Request {
+        let url = Url::parse("http://localhost").unwrap().join(path).unwrap();
+        let mut req = tide::http::Request::new(Method::Post, url);
+        req.insert_header(header, signature);
+        req.set_body(body);
+        req
+    }
+
+    fn repository() -> (std::path::PathBuf, String) {
+        let repo = crate::testing::repository("hooks");
+        let sha =
+            crate::testing::commit(&repo, "synchronik.yml", "needs: []\ncommands:\n- 'true'\n");
+        (repo, sha)
+    }
+
+    /*
+     * Wait for the numbered Run of the project to be enqueued in the background
+     */
+    async fn enqueued(project: &str, num: i64, state: &AppState<'_>) -> Run {
+        let project = crate::models::Project::by_name(project, &state.db)
+            .await
+            .unwrap();
+        for _ in 0..100 {
+            if let Ok(run) = Run::find_by_num(&project, num, &state.db).await {
+                return run;
+            }
+            async_std::task::sleep(std::time::Duration::from_millis(50)).await;
+        }
+        panic!("Run {} of {} was not enqueued", num, project.name);
+    }
+
+    #[test]
+    fn test_verify() {
+        let body = r#"{"zen":"Keep it logically awesome."}"#;
+        let signature = sign("secret", body);
+        assert!(verify("secret", Some(&signature), body.as_bytes()));
+        assert!(!verify("other", Some(&signature), body.as_bytes()));
+        assert!(!verify("secret", Some(&signature), b"{}"));
+        assert!(!verify("secret", Some("sha256=zz"), body.as_bytes()));
+        assert!(!verify("secret", None, body.as_bytes()));
+    }
+
+    #[test]
+    fn test_same_ref_and_repository() {
+        assert!(same_ref("main", "refs/heads/main"));
+        assert!(same_ref("refs/heads/main", "main"));
+        assert!(same_ref("refs/tags/v1", "refs/tags/v1"));
+        assert!(!same_ref("main", "refs/heads/feature/main"));
+        assert!(!same_ref("main", "refs/tags/main"));
+        assert!(!same_ref("refs/tags/main", "main"));
+        assert!(same_repository(
+            "https://github.com/rtyler/synchronik.git",
+            "https://github.com/rtyler/synchronik"
+        ));
+        assert!(!same_repository(
+            "https://github.com/rtyler/synchronik.git",
+            "https://github.com/rtyler/jdp.git"
+        ));
+    }
+
+    #[async_std::test]
+    async fn test_hooks_require_signature() {
+        let (repo, _) = repository();
+        let app = setup_app(local_config(&repo)).await;
+
+        let body = r#"{"zen":"Design for failure."}"#;
+        let res: tide::http::Response = app
+            .respond(hook(
+                "/api/v1/hooks/github",
+                "X-Hub-Signature-256",
+                &sign("secret", body),
+                body,
+            ))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::NotFound, res.status());
+
+        let res: tide::http::Response = app
+            .respond(hook(
+                "/api/v1/hooks/generic",
+                "X-Synchronik-Signature-256",
+                &sign("wrong", body),
+                body,
+            ))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::Unauthorized, res.status());
+        let _ = std::fs::remove_dir_all(&repo);
+    }
+
+    #[async_std::test]
+    async fn test_generic_hook() {
+        let (repo, sha) = repository();
+        let app = setup_app(local_config(&repo)).await;
+
+        let body = format!(
+            r#"{{"url":"file://{}/","ref":"refs/heads/main","sha":"{}","sender":"rtyler"}}"#,
+            repo.display(),
+            sha
+        );
+        let mut res: tide::http::Response = app
+            .respond(hook(
+                "/api/v1/hooks/generic",
+                "X-Synchronik-Signature-256",
+                &sign("generic-secret", &body),
+                &body,
+            ))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::Accepted, res.status());
+        let mut hooked: synchronik::HookResponse = res.body_json().await.unwrap();
+        hooked.projects.sort();
+        assert_eq!(vec!["broken", "local"], hooked.projects);
+
+        /*
+         * The project without a definition is skipped without affecting the other
+         */
+        let state = app.state();
+        let run = enqueued("local", 1, state).await;
+        let broken = crate::models::Project::by_name("broken", &state.db)
+            .await
+            .unwrap();
+        assert!(Run::find_by_num(&broken, 1, &state.db).await.is_err());
+        assert_eq!("webhook", run.run.trigger);
+        assert_eq!(Some("rtyler".to_string()), run.run.sender);
+        assert_eq!(Some(sha), run.scm_info.sha);
+        assert_eq!("refs/heads/main", run.scm_info.r#ref);
+        let _ = std::fs::remove_dir_all(&repo);
+    }
+
+    #[async_std::test]
+    async fn test_github_ignored_events() {
+        let config: ServerConfig = serde_yaml::from_str(
+            r#"
+agents: {}
+hooks:
+  github: 'github-secret'
+projects:
+  'synchronik':
+    description: 'On GitHub'
+    scm:
+      github:
+        owner: 'rtyler'
+        repo: 'synchronik'
+        ref: 'main'
+"#,
+        )
+        .unwrap();
+        let app = setup_app(config).await;
+
+        /*
+         * None of these events match the project, so nothing is resolved from GitHub
+         */
+        for (event, body) in [
+            (
+                "ping",
+                r#"{"zen":"Half measures are as bad as nothing at all."}"#.to_string(),
+            ),
+            (
+                "push",
+                format!(
+                    r#"{{"ref":"refs/heads/main","after":"{}","deleted":true,"repository":{{"full_name":"rtyler/synchronik"}}}}"#,
+                    SHA
+                ),
+            ),
+            (
+                "push",
+                format!(
+                    r#"{{"ref":"refs/heads/other","after":"{}","repository":{{"full_name":"rtyler/synchronik"}}}}"#,
+                    SHA
+                ),
+            ),
+            (
+                "push",
+                format!(
+                    r#"{{"ref":"refs/tags/main","after":"{}","repository":{{"full_name":"rtyler/synchronik"}}}}"#,
+                    SHA
+                ),
+            ),
+            (
+                "pull_request",
+                format!(
+                    r#"{{"action":"opened","number":1,"pull_request":{{"head":{{"ref":"main","sha":"{}","repo":{{"full_name":"fork/synchronik"}}}},"base":{{"ref":"main","sha":"{}","repo":{{"full_name":"rtyler/synchronik"}}}}}},"repository":{{"full_name":"rtyler/synchronik"}}}}"#,
+                    SHA, SHA
+                ),
+            ),
+        ] {
+            let mut req = hook(
+                "/api/v1/hooks/github",
+                "X-Hub-Signature-256",
+                &sign("github-secret", &body),
+                &body,
+            );
+            req.insert_header("X-GitHub-Event", event);
+            let mut res: tide::http::Response = app.respond(req).await.unwrap();
+            assert_eq!(StatusCode::Ok, res.status(), "{}", body);
+            let hooked: synchronik::HookResponse = res.body_json().await.unwrap();
+            assert!(hooked.projects.is_empty(), "{}", body);
+        }
+    }
+
+    #[async_std::test]
+    async fn test_github_hooks() {
+        let url = forge();
+        let config: ServerConfig = serde_yaml::from_str(&format!(
+            r#"
+agents: {{}}
+hooks:
+  github: 'github-secret'
+github:
+  enterprise:
+    url: '{}api/v3'
+    token: 'ghp_test'
+projects:
+  'forged':
+    description: 'On GitHub Enterprise'
+    filename: 'ci/synchronik.yml'
+    scm:
+      github:
+        owner: 'owner'
+        repo: 'repo'
+        ref: 'main'
+        credentials: 'enterprise'
+"#,
+            url
+        ))
+        .unwrap();
+        let app = setup_app(config).await;
+
+        for (num, event, body, trigger, scm_ref) in [
+            (
+                1,
+                "push",
+                format!(
+                    r#"{{"ref":"refs/heads/main","after":"{}","repository":{{"full_name":"owner/repo"}},"sender":{{"login":"rtyler"}}}}"#,
+                    SHA
+                ),
+                "push",
+                "refs/heads/main",
+            ),
+            (
+                2,
+                "pull_request",
+                format!(
+                    r#"{{"action":"opened","number":7,"pull_request":{{"head":{{"ref":"feature","sha":"{}","repo":{{"full_name":"owner/repo"}}}},"base":{{"ref":"main","sha":"{}","repo":{{"full_name":"owner/repo"}}}}}},"repository":{{"full_name":"owner/repo"}},"sender":{{"login":"rtyler"}}}}"#,
+                    SHA, SHA
+                ),
+                "pull_request",
+                "refs/pull/7/head",
+            ),
+        ] {
+            let mut req = hook(
+                "/api/v1/hooks/github",
+                "X-Hub-Signature-256",
+                &sign("github-secret", &body),
+                &body,
+            );
+            req.insert_header("X-GitHub-Event", event);
+            let mut res: tide::http::Response = app.respond(req).await.unwrap();
+            assert_eq!(StatusCode::Accepted, res.status(), "{}", event);
+            let hooked: synchronik::HookResponse = res.body_json().await.unwrap();
+            assert_eq!(vec!["forged"], hooked.projects);
+
+            let run = enqueued("forged", num, app.state()).await;
+            assert_eq!(trigger, run.run.trigger);
+            assert_eq!(Some("rtyler".to_string()), run.run.sender);
+            assert_eq!(Some(SHA), run.scm_info.sha.as_deref());
+            assert_eq!(scm_ref, run.scm_info.r#ref);
+            assert!(run.definition.definition.contains("forged"), "{}", event);
+        }
+    }
+}
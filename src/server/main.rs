@@ -6,8 +6,10 @@
 extern crate serde_json;
 
 use std::path::PathBuf;
+use std::time::Duration;
 
-use async_std::sync::{Arc, RwLock};
+use async_std::channel::{Receiver, Sender};
+use async_std::sync::{Arc, Mutex, RwLock};
 use dotenv::dotenv;
 use gumdrop::Options;
 use handlebars::Handlebars;
@@ -15,9 +17,21 @@ use log::*;
 use sqlx::SqlitePool;
 use url::Url;
 
+mod conditions;
 mod config;
+mod dispatcher;
+mod graph;
+mod hooks;
 mod models;
+mod parameters;
+mod poller;
 mod routes;
+mod scheduler;
+mod scm;
+mod strategy;
+#[cfg(test)]
+#[path = "../testing.rs"]
+mod testing;
 
 use crate::config::*;
 use crate::models::Project;
@@ -27,9 +41,28 @@ pub struct AppState<'a> {
     pub db: SqlitePool,
     pub config: ServerConfig,
     pub agents: Vec<Agent>,
+    // Externally reachable URL of this server
+    pub url: Url,
+    // Held while queued runs are being dispatched to agents
+    pub dispatch_lock: Arc<Mutex<()>>,
+    // Chooses between the agents capable of executing a run
+    pub selector: Arc<dyn strategy::Selector>,
+    // Installation tokens of GitHub Apps, reused until they are close to expiring
+    pub tokens: Arc<scm::TokenCache>,
+    // Client for requests to agents and forges, with timeouts so that neither can stall the server
+    pub http: reqwest::Client,
+    // Wakes the dispatcher loop before its next interval
+    wake: Sender<()>,
+    woken: Receiver<()>,
     hb: Arc<RwLock<Handlebars<'a>>>,
 }
 
+/*
+ * How long a request to an agent may take to connect, and to complete
+ */
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
 impl AppState<'_> {
     fn new(db: SqlitePool, config: ServerConfig) -> Self {
         let mut hb = Handlebars::new();
@@ -37,14 +70,50 @@ impl AppState<'_> {
         #[cfg(debug_assertions)]
         hb.set_dev_mode(true);
 
+        let url = config
+            .url
+            .clone()
+            .unwrap_or_else(|| Url::parse("http://localhost:8000").unwrap());
+        let http = reqwest::Client::builder()
+            .connect_timeout(CONNECT_TIMEOUT)
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+            .expect("Failed to build the HTTP client");
+        /*
+         * A single pending wake is enough for the dispatcher to find every queued step
+         */
+        let (wake, woken) = async_std::channel::bounded(1);
+
         Self {
             db,
-            config,
             agents: vec![],
+            url,
+            dispatch_lock: Arc::new(Mutex::new(())),
+            selector: config.strategy.selector().into(),
+            tokens: Arc::new(scm::TokenCache::default()),
+            http,
+            wake,
+            woken,
             hb: Arc::new(RwLock::new(hb)),
+            config,
         }
     }
 
+    /*
+     * Have the dispatcher loop look for steps to dispatch without waiting for its next
+     * interval, rather than dispatching them while a request waits
+     */
+    pub fn wake_dispatcher(&self) {
+        let _ = self.wake.try_send(());
+    }
+
+    /*
+     * Wait until the dispatcher is woken, or the timeout has passed
+     */
+    pub async fn dispatcher_woken(&self, timeout: Duration) {
+        let _ = async_std::future::timeout(timeout, self.woken.recv()).await;
+    }
+
     pub async fn register_templates(&self) -> Result<(), handlebars::TemplateError> {
         let mut hb = self.hb.write().await;
         hb.clear_templates();
@@ -81,10 +150,14 @@ async fn main() -> Result<(), tide::Error> {
     let opts = ServerOptions::parse_args_default_or_exit();
     debug!("Starting with options: {:?}", opts);
 
-    let config = match opts.config {
+    let mut config = match opts.config {
         Some(path) => ServerConfig::from_path(&path)?,
         None => ServerConfig::default(),
     };
+    if config.url.is_none() {
+        let url = format!("http://{}", opts.listen.replace("0.0.0.0", "localhost"));
+        config.url = Some(Url::parse(&url)?);
+    }
     debug!("Starting with config: {:?}", config);
 
     let database_url = std::env::var("DATABASE_URL").unwrap_or(":memory:".to_string());
@@ -124,13 +197,21 @@ async fn main() -> Result<(), tide::Error> {
             name.to_string(),
             agent.url.clone(),
             response.caps,
+            agent.labels.clone(),
         ));
     }
+    /*
+     * Agents are kept in a stable order so that selection strategies are predictable
+     */
+    state.agents.sort_by(|a, b| a.name.cmp(&b.name));
 
     state
         .register_templates()
         .await
         .expect("Failed to register handlebars templates");
+    async_std::task::spawn(dispatcher::run(state.clone()));
+    async_std::task::spawn(poller::run(state.clone()));
+    async_std::task::spawn(scheduler::run(state.clone()));
     let mut app = tide::with_state(state);
 
     #[cfg(not(debug_assertions))]
@@ -164,10 +245,29 @@ async fn main() -> Result<(), tide::Error> {
     debug!("Configuring routes");
     app.at("/").get(routes::index);
     app.at("/project/:name").get(routes::project);
+    app.at("/project/:name/runs/:num").get(routes::run);
 
     debug!("Configuring API routes");
+    app.at("/api/v1/projects").get(routes::api::list_projects);
     app.at("/api/v1/projects/:name")
+        .get(routes::api::get_project)
         .post(routes::api::execute_project);
+    app.at("/api/v1/projects/:name/runs")
+        .get(routes::api::list_runs);
+    app.at("/api/v1/runs/:uuid").get(routes::api::get_run);
+    app.at("/api/v1/runs/:uuid/cancel")
+        .post(routes::api::cancel_run);
+    app.at("/api/v1/runs/:uuid/status")
+        .put(routes::api::update_run_status);
+    app.at("/api/v1/runs/:uuid/steps/:step/status")
+        .put(routes::api::update_step_status);
+    app.at("/api/v1/runs/:uuid/stream")
+        .get(tide_websockets::WebSocket::new(routes::api::stream_run));
+    app.at("/api/v1/runs/:uuid/steps/:step/stream")
+        .get(tide_websockets::WebSocket::new(routes::api::stream_step));
+    app.at("/api/v1/agents").get(routes::api::list_agents);
+    app.at("/api/v1/hooks/github").post(hooks::github);
+    app.at("/api/v1/hooks/generic").post(hooks::generic);
     app.listen(opts.listen).await?;
     Ok(())
 }
@@ -3,9 +3,11 @@ mod run;
 mod rundefinition;
 mod runrow;
 mod scminfo;
+mod step;
 
 pub use self::project::Project;
 pub use self::run::Run;
 pub use self::rundefinition::RunDefinition;
-pub use self::runrow::RunRow;
+pub use self::runrow::{RunRow, RunStatus};
 pub use self::scminfo::ScmInfo;
+pub use self::step::Step;
@@ -9,6 +9,8 @@ pub struct Project {
     pub uuid: String,
     pub name: String,
     pub created_at: NaiveDateTime,
+    // When the schedule of the project last fired
+    pub scheduled_at: Option<NaiveDateTime>,
 }
 
 impl Default for Project {
@@ -17,6 +19,7 @@ impl Default for Project {
             uuid: Uuid::new_v4().hyphenated().to_string(),
             name: "Default Project".into(),
             created_at: Utc::now().naive_utc(),
+            scheduled_at: None,
         }
     }
 }
@@ -27,6 +30,7 @@ impl Project {
             uuid: Uuid::new_v4().hyphenated().to_string(),
             name: name.into(),
             created_at: Utc::now().naive_utc(),
+            scheduled_at: None,
         }
     }
 
@@ -42,6 +46,23 @@ impl Project {
             .await
     }
 
+    /*
+     * Record the time the schedule of the project fired at
+     */
+    pub async fn scheduled(
+        project: &Project,
+        at: NaiveDateTime,
+        pool: &SqlitePool,
+    ) -> Result<SqliteQueryResult, sqlx::Error> {
+        sqlx::query!(
+            "UPDATE projects SET scheduled_at = ? WHERE uuid = ?",
+            at,
+            project.uuid
+        )
+        .execute(pool)
+        .await
+    }
+
     pub async fn create(
         project: &Project,
         tx: &SqlitePool,
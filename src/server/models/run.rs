@@ -1,28 +1,52 @@
+use chrono::Utc;
+use serde::Serialize;
 use sqlx::SqlitePool;
+use url::Url;
+use uuid::Uuid;
 
 use crate::models::*;
 
-#[derive(Clone, Debug, Default)]
+#[derive(Clone, Debug, Default, Serialize)]
 pub struct Run {
-    run: RunRow,
-    project: Project,
-    scm_info: ScmInfo,
-    definition: RunDefinition,
+    pub run: RunRow,
+    pub project: Project,
+    pub scm_info: ScmInfo,
+    pub definition: RunDefinition,
+    // Empty until the dispatcher has planned the steps from the definition
+    pub steps: Vec<Step>,
 }
 /* The basic implementation for Run has all the database access operations
  */
 impl Run {
+    pub fn new(project: Project, scm_info: ScmInfo, definition: RunDefinition) -> Self {
+        let run = RunRow {
+            project: project.uuid.clone(),
+            scm_info: scm_info.uuid.clone(),
+            definition: definition.uuid.clone(),
+            ..Default::default()
+        };
+        Self {
+            run,
+            project,
+            scm_info,
+            definition,
+            steps: vec![],
+        }
+    }
+
     /*
-     * Create the Run in the database given the appropriate struct
+     * Create the Run in the database given the appropriate struct, the number of the Run
+     * is assigned by the database so the created Run is returned
      */
-    async fn create(run: &Run, pool: &SqlitePool) -> Result<(), sqlx::Error> {
+    pub async fn create(run: &Run, pool: &SqlitePool) -> Result<Run, sqlx::Error> {
         let mut tx = pool.begin().await?;
         sqlx::query!(
-            r#"INSERT INTO scm_info (uuid, git_url, ref, created_at) VALUES (?, ?, ?, ?)"#,
+            r#"INSERT INTO scm_info (uuid, git_url, ref, created_at, sha) VALUES (?, ?, ?, ?, ?)"#,
             run.scm_info.uuid,
             run.scm_info.git_url,
             run.scm_info.r#ref,
-            run.scm_info.created_at
+            run.scm_info.created_at,
+            run.scm_info.sha
         )
         .execute(&mut tx)
         .await?;
@@ -36,28 +60,212 @@ impl Run {
         .execute(&mut tx)
         .await?;
 
+        /*
+         * The num is computed in the same statement to keep it monotonically increasing
+         * for the project, the unique index on (project, num) guards against races
+         */
         sqlx::query!(
-                "INSERT INTO runs (uuid, num, status, log_url, definition, scm_info, project) VALUES (?, ?, ?, ?, ?, ?, ?)",
+                r#"INSERT INTO runs (uuid, num, status, log_url, trigger, sender, parameters, definition, scm_info, project, token)
+                VALUES (?, (SELECT COALESCE(MAX(num), 0) + 1 FROM runs WHERE project = ?), ?, ?, ?, ?, ?, ?, ?, ?, ?)"#,
                 run.run.uuid,
-                run.run.num,
+                run.project.uuid,
                 run.run.status,
                 run.run.log_url,
+                run.run.trigger,
+                run.run.sender,
+                run.run.parameters,
                 run.definition.uuid,
                 run.scm_info.uuid,
                 run.project.uuid,
+                run.run.token,
             )
             .execute(&mut tx)
             .await?;
-        tx.commit().await
+        tx.commit().await?;
+        Self::find_by(&run.run.uuid, pool).await
+    }
+
+    /*
+     * Update the status of the Run identified by the given Uuid
+     */
+    pub async fn update_status(
+        uuid: &str,
+        status: RunStatus,
+        pool: &SqlitePool,
+    ) -> Result<(), sqlx::Error> {
+        let finished_at = match status.is_finished() {
+            true => Some(Utc::now().naive_utc()),
+            false => None,
+        };
+        let status = status.as_str();
+        sqlx::query!(
+            "UPDATE runs SET status = ?, finished_at = ? WHERE uuid = ?",
+            status,
+            finished_at,
+            uuid
+        )
+        .execute(pool)
+        .await?;
+        Ok(())
+    }
+
+    /*
+     * Mark the Run as running once an agent has accepted it, recording which agent it
+     * is on and where its logs live. A Run which has already finished keeps its status
+     */
+    pub async fn started(
+        uuid: &str,
+        agent: &str,
+        log_url: &str,
+        stream_url: Option<&str>,
+        task_url: Option<&str>,
+        pool: &SqlitePool,
+    ) -> Result<(), sqlx::Error> {
+        let status = RunStatus::Running.as_str();
+        let started_at = Utc::now().naive_utc();
+        sqlx::query!(
+            r#"UPDATE runs SET status = CASE WHEN status IN ('queued', 'pending') THEN ? ELSE status END,
+            agent = ?, log_url = ?, stream_url = ?, task_url = ?, started_at = ? WHERE uuid = ?"#,
+            status,
+            agent,
+            log_url,
+            stream_url,
+            task_url,
+            started_at,
+            uuid
+        )
+        .execute(pool)
+        .await?;
+        Ok(())
+    }
+
+    /*
+     * Mark a queued Run as running once the first of its steps has been dispatched
+     */
+    pub async fn running(uuid: &str, pool: &SqlitePool) -> Result<(), sqlx::Error> {
+        let status = RunStatus::Running.as_str();
+        let started_at = Utc::now().naive_utc();
+        sqlx::query!(
+            r#"UPDATE runs SET status = ?, started_at = COALESCE(started_at, ?)
+            WHERE uuid = ? AND status = 'queued'"#,
+            status,
+            started_at,
+            uuid
+        )
+        .execute(pool)
+        .await?;
+        Ok(())
     }
 
     /*
      * Allow finding a Run by the given Uuid
      */
-    async fn find_by(uuid: &str, pool: &SqlitePool) -> Result<Run, sqlx::Error> {
+    pub async fn find_by(uuid: &str, pool: &SqlitePool) -> Result<Run, sqlx::Error> {
         let row = sqlx::query_as!(RunRow, "SELECT * FROM runs WHERE uuid = ?", uuid)
             .fetch_one(pool)
             .await?;
+        Self::from_row(row, pool).await
+    }
+
+    /*
+     * List the Runs which have not finished, and so may have steps waiting to be
+     * dispatched to an agent, oldest first
+     */
+    pub async fn active(pool: &SqlitePool) -> Result<Vec<Run>, sqlx::Error> {
+        let rows = sqlx::query_as!(
+            RunRow,
+            r#"SELECT * FROM runs WHERE status IN ('queued', 'pending', 'running')
+            ORDER BY created_at, num"#
+        )
+        .fetch_all(pool)
+        .await?;
+
+        let mut runs = vec![];
+        for row in rows {
+            runs.push(Self::from_row(row, pool).await?);
+        }
+        Ok(runs)
+    }
+
+    /*
+     * List the Runs of the given project, most recent first
+     */
+    pub async fn list_for(
+        project: &Project,
+        limit: i64,
+        offset: i64,
+        pool: &SqlitePool,
+    ) -> Result<Vec<Run>, sqlx::Error> {
+        let rows = sqlx::query_as!(
+            RunRow,
+            "SELECT * FROM runs WHERE project = ? ORDER BY num DESC LIMIT ? OFFSET ?",
+            project.uuid,
+            limit,
+            offset
+        )
+        .fetch_all(pool)
+        .await?;
+
+        let mut runs = vec![];
+        for row in rows {
+            runs.push(Self::from_row(row, pool).await?);
+        }
+        Ok(runs)
+    }
+
+    /*
+     * Find the most recent Run of the given project which built the ref, whether it was
+     * recorded by its short name or, as webhooks do, by its full name
+     */
+    pub async fn latest_on_ref(
+        project: &Project,
+        scm_ref: &str,
+        pool: &SqlitePool,
+    ) -> Result<Option<Run>, sqlx::Error> {
+        let branch = format!("refs/heads/{}", scm_ref);
+        let tag = format!("refs/tags/{}", scm_ref);
+        let row = sqlx::query_as!(
+            RunRow,
+            r#"SELECT runs.* FROM runs JOIN scm_info ON runs.scm_info = scm_info.uuid
+            WHERE runs.project = ? AND scm_info.ref IN (?, ?, ?)
+            ORDER BY runs.num DESC LIMIT 1"#,
+            project.uuid,
+            scm_ref,
+            branch,
+            tag
+        )
+        .fetch_optional(pool)
+        .await?;
+
+        match row {
+            Some(row) => Ok(Some(Self::from_row(row, pool).await?)),
+            None => Ok(None),
+        }
+    }
+
+    /*
+     * Find a Run by its number within the given project
+     */
+    pub async fn find_by_num(
+        project: &Project,
+        num: i64,
+        pool: &SqlitePool,
+    ) -> Result<Run, sqlx::Error> {
+        let row = sqlx::query_as!(
+            RunRow,
+            "SELECT * FROM runs WHERE project = ? AND num = ?",
+            project.uuid,
+            num
+        )
+        .fetch_one(pool)
+        .await?;
+        Self::from_row(row, pool).await
+    }
+
+    /*
+     * Load the rest of the Run for the given RunRow
+     */
+    async fn from_row(row: RunRow, pool: &SqlitePool) -> Result<Run, sqlx::Error> {
         let scm_info = sqlx::query_as!(
             ScmInfo,
             "SELECT * FROM scm_info WHERE uuid = ?",
@@ -82,11 +290,49 @@ impl Run {
         .fetch_one(pool)
         .await?;
 
+        let steps = Step::list_for(&row.uuid, pool).await?;
+
         Ok(Run {
             run: row,
             scm_info,
             project,
             definition,
+            steps,
+        })
+    }
+}
+
+/*
+ * Convert the Run into the representation shared with API clients
+ */
+impl TryFrom<&Run> for synchronik::RunResponse {
+    type Error = anyhow::Error;
+
+    fn try_from(run: &Run) -> Result<Self, Self::Error> {
+        Ok(Self {
+            uuid: Uuid::parse_str(&run.run.uuid)?,
+            num: run.run.num,
+            project: run.project.name.clone(),
+            status: run.run.status.parse().map_err(anyhow::Error::msg)?,
+            trigger: run.run.trigger.clone(),
+            sender: run.run.sender.clone(),
+            agent: run.run.agent.clone(),
+            scm: synchronik::RunScm {
+                git_url: run.scm_info.git_url.clone(),
+                r#ref: run.scm_info.r#ref.clone(),
+                sha: run.scm_info.sha.clone(),
+            },
+            definition: run.definition.definition.clone(),
+            log: run.run.log_url.as_deref().map(Url::parse).transpose()?,
+            created_at: run.run.created_at.and_utc(),
+            started_at: run.run.started_at.map(|t| t.and_utc()),
+            finished_at: run.run.finished_at.map(|t| t.and_utc()),
+            parameters: run.run.parameters(),
+            steps: run
+                .steps
+                .iter()
+                .map(synchronik::StepResponse::try_from)
+                .collect::<Result<_, _>>()?,
         })
     }
 }
@@ -114,10 +360,196 @@ mod tests {
         let project = crate::models::Project::new("test");
         Project::create(&project, &pool).await.unwrap();
 
-        let mut run = Run::default();
-        run.project = project;
+        let run = Run {
+            project,
+            ..Default::default()
+        };
         Run::create(&run, &pool).await.unwrap();
         let fetched_run = Run::find_by(&run.run.uuid, &pool).await.unwrap();
         assert_eq!(run.run.uuid, fetched_run.run.uuid);
     }
+
+    #[async_std::test]
+    async fn test_run_numbers_increase_per_project() {
+        let _ = pretty_env_logger::try_init();
+        let pool = setup_database().await;
+        let project = crate::models::Project::new("test");
+        Project::create(&project, &pool).await.unwrap();
+        let other = crate::models::Project::new("other");
+        Project::create(&other, &pool).await.unwrap();
+
+        let first = Run::new(
+            project.clone(),
+            ScmInfo::default(),
+            RunDefinition::default(),
+        );
+        let first = Run::create(&first, &pool).await.unwrap();
+        let second = Run::new(project, ScmInfo::default(), RunDefinition::default());
+        let second = Run::create(&second, &pool).await.unwrap();
+        let unrelated = Run::new(other, ScmInfo::default(), RunDefinition::default());
+        let unrelated = Run::create(&unrelated, &pool).await.unwrap();
+
+        assert_eq!(1, first.run.num);
+        assert_eq!(2, second.run.num);
+        assert_eq!(1, unrelated.run.num);
+    }
+
+    #[async_std::test]
+    async fn test_run_status_updates() {
+        let _ = pretty_env_logger::try_init();
+        let pool = setup_database().await;
+        let project = crate::models::Project::new("test");
+        Project::create(&project, &pool).await.unwrap();
+
+        let run = Run::new(project, ScmInfo::default(), RunDefinition::default());
+        let run = Run::create(&run, &pool).await.unwrap();
+        assert_eq!(RunStatus::Queued.as_str(), run.run.status);
+
+        Run::started(
+            &run.run.uuid,
+            "local",
+            "http://localhost/console.log",
+            Some("ws://localhost/stream"),
+            Some("http://localhost/api/v1/tasks/1"),
+            &pool,
+        )
+        .await
+        .unwrap();
+        let fetched = Run::find_by(&run.run.uuid, &pool).await.unwrap();
+        assert_eq!(RunStatus::Running.as_str(), fetched.run.status);
+        assert_eq!(Some("local".to_string()), fetched.run.agent);
+        assert_eq!(
+            Some("http://localhost/console.log".to_string()),
+            fetched.run.log_url
+        );
+        assert_eq!(
+            Some("http://localhost/api/v1/tasks/1".to_string()),
+            fetched.run.task_url
+        );
+
+        Run::update_status(&run.run.uuid, RunStatus::Succeeded, &pool)
+            .await
+            .unwrap();
+        let fetched = Run::find_by(&run.run.uuid, &pool).await.unwrap();
+        assert_eq!(RunStatus::Succeeded.as_str(), fetched.run.status);
+        assert!(fetched.run.started_at.is_some());
+        assert!(fetched.run.finished_at >= fetched.run.started_at);
+        assert!(fetched.run.duration().is_some());
+    }
+
+    #[async_std::test]
+    async fn test_list_for_project() {
+        let _ = pretty_env_logger::try_init();
+        let pool = setup_database().await;
+        let project = crate::models::Project::new("test");
+        Project::create(&project, &pool).await.unwrap();
+        let other = crate::models::Project::new("other");
+        Project::create(&other, &pool).await.unwrap();
+
+        for _ in 0..3 {
+            let run = Run::new(
+                project.clone(),
+                ScmInfo::default(),
+                RunDefinition::default(),
+            );
+            Run::create(&run, &pool).await.unwrap();
+        }
+        let run = Run::new(other, ScmInfo::default(), RunDefinition::default());
+        Run::create(&run, &pool).await.unwrap();
+
+        let runs = Run::list_for(&project, 2, 0, &pool).await.unwrap();
+        let nums: Vec<i64> = runs.iter().map(|r| r.run.num).collect();
+        assert_eq!(vec![3, 2], nums);
+
+        let runs = Run::list_for(&project, 2, 2, &pool).await.unwrap();
+        let nums: Vec<i64> = runs.iter().map(|r| r.run.num).collect();
+        assert_eq!(vec![1], nums);
+    }
+
+    #[async_std::test]
+    async fn test_active_runs() {
+        let _ = pretty_env_logger::try_init();
+        let pool = setup_database().await;
+        let project = crate::models::Project::new("test");
+        Project::create(&project, &pool).await.unwrap();
+
+        let first = Run::new(
+            project.clone(),
+            ScmInfo::default(),
+            RunDefinition::default(),
+        );
+        let first = Run::create(&first, &pool).await.unwrap();
+        let second = Run::new(project, ScmInfo::default(), RunDefinition::default());
+        let second = Run::create(&second, &pool).await.unwrap();
+
+        let active = Run::active(&pool).await.unwrap();
+        assert_eq!(2, active.len());
+        assert_eq!(first.run.uuid, active[0].run.uuid);
+
+        Run::started(
+            &first.run.uuid,
+            "local",
+            "http://localhost/console.log",
+            None,
+            None,
+            &pool,
+        )
+        .await
+        .unwrap();
+        assert_eq!(2, Run::active(&pool).await.unwrap().len());
+
+        Run::update_status(&first.run.uuid, RunStatus::Succeeded, &pool)
+            .await
+            .unwrap();
+        let active = Run::active(&pool).await.unwrap();
+        assert_eq!(1, active.len());
+        assert_eq!(second.run.uuid, active[0].run.uuid);
+    }
+
+    #[async_std::test]
+    async fn test_find_by_num() {
+        let _ = pretty_env_logger::try_init();
+        let pool = setup_database().await;
+        let project = crate::models::Project::new("test");
+        Project::create(&project, &pool).await.unwrap();
+
+        let run = Run::new(
+            project.clone(),
+            ScmInfo::default(),
+            RunDefinition::default(),
+        );
+        let run = Run::create(&run, &pool).await.unwrap();
+
+        let fetched = Run::find_by_num(&project, run.run.num, &pool)
+            .await
+            .unwrap();
+        assert_eq!(run.run.uuid, fetched.run.uuid);
+        assert!(Run::find_by_num(&project, run.run.num + 1, &pool)
+            .await
+            .is_err());
+    }
+
+    #[async_std::test]
+    async fn test_run_response() {
+        let pool = setup_database().await;
+        let project = crate::models::Project::new("response");
+        crate::models::Project::create(&project, &pool)
+            .await
+            .unwrap();
+        let run = Run::new(
+            project,
+            ScmInfo::new("https://example.com/repo.git", "main"),
+            RunDefinition::new("needs: []\ncommands: []\n"),
+        );
+        let run = Run::create(&run, &pool).await.unwrap();
+
+        let response = synchronik::RunResponse::try_from(&run).unwrap();
+        assert_eq!(run.run.uuid, response.uuid.to_string());
+        assert_eq!(1, response.num);
+        assert_eq!("response", response.project);
+        assert_eq!(RunStatus::Queued, response.status);
+        assert_eq!("main", response.scm.r#ref);
+        assert_eq!(None, response.log);
+        assert_eq!(None, response.started_at);
+    }
 }
@@ -1,13 +1,23 @@
 use chrono::{NaiveDateTime, Utc};
+use serde::Serialize;
 use uuid::Uuid;
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize)]
 pub struct RunDefinition {
     pub uuid: String,
     pub definition: String,
     pub created_at: NaiveDateTime,
 }
 
+impl RunDefinition {
+    pub fn new(definition: &str) -> Self {
+        Self {
+            definition: definition.into(),
+            ..Default::default()
+        }
+    }
+}
+
 impl Default for RunDefinition {
     fn default() -> Self {
         Self {
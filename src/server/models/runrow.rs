@@ -1,21 +1,29 @@
+use std::collections::HashMap;
+
 use chrono::{NaiveDateTime, Utc};
+use serde::Serialize;
 use uuid::Uuid;
 
+/*
+ * The RunStatus is shared with API clients, it is stored as text in the runs table
+ */
+pub use synchronik::RunStatus;
+
 /*
  * The RunRow is the struct for the deserialization/serialization of the runs table
  * unfortunately this is a little bit of misdirection due to the inability to make
  * nested structs with sqlx work well
  */
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize)]
 pub struct RunRow {
     // Unique identifier for the Run
     pub uuid: String,
-    // User-identifiable number for the Run, monotonically increasing
+    // User-identifiable number for the Run, monotonically increasing per project
     pub num: i64,
-    // Unix status return code from the run, zero is success
-    pub status: i64,
-    // Globally resolvable URL for fetching raw logs
-    pub log_url: String,
+    // Textual representation of the RunStatus
+    pub status: String,
+    // Globally resolvable URL for fetching raw logs, known once an agent accepts the Run
+    pub log_url: Option<String>,
     // Foreign key to projects
     pub project: String,
     // Foreign key to run_definition
@@ -23,19 +31,63 @@ pub struct RunRow {
     // Foreign key to scm_info
     pub scm_info: String,
     pub created_at: NaiveDateTime,
+    // WebSockets URL on the agent for streaming the logs while the Run executes
+    pub stream_url: Option<String>,
+    // Name of the agent the Run was dispatched to
+    pub agent: Option<String>,
+    // What caused the Run to be created
+    pub trigger: String,
+    pub started_at: Option<NaiveDateTime>,
+    pub finished_at: Option<NaiveDateTime>,
+    // URL of the task on the agent executing the Run
+    pub task_url: Option<String>,
+    // JSON object of the parameter values the Run was triggered with
+    pub parameters: String,
+    // Who caused the Run to be created, when that is known
+    pub sender: Option<String>,
+    // Secret the agents present when reporting the status of the Run or its steps
+    #[serde(skip_serializing)]
+    pub token: Option<String>,
+}
+
+impl RunRow {
+    /*
+     * How long the Run took to execute, or has been executing for if it is still running
+     */
+    pub fn duration(&self) -> Option<chrono::Duration> {
+        let started_at = self.started_at?;
+        let finished_at = self.finished_at.unwrap_or_else(|| Utc::now().naive_utc());
+        Some(finished_at - started_at)
+    }
+
+    /*
+     * The parameter values the Run was triggered with
+     */
+    pub fn parameters(&self) -> HashMap<String, String> {
+        serde_json::from_str(&self.parameters).unwrap_or_default()
+    }
 }
 
 impl Default for RunRow {
     fn default() -> Self {
         Self {
             uuid: Uuid::new_v4().hyphenated().to_string(),
-            num: 42,
-            status: 0,
-            log_url: "https://example.com/console.log".into(),
+            num: 0,
+            status: RunStatus::Queued.to_string(),
+            log_url: None,
             definition: Uuid::new_v4().hyphenated().to_string(),
             project: Uuid::new_v4().hyphenated().to_string(),
             scm_info: Uuid::new_v4().hyphenated().to_string(),
             created_at: Utc::now().naive_utc(),
+            stream_url: None,
+            agent: None,
+            trigger: "manual".into(),
+            started_at: None,
+            finished_at: None,
+            task_url: None,
+            parameters: "{}".into(),
+            sender: None,
+            token: Some(Uuid::new_v4().simple().to_string()),
         }
     }
 }
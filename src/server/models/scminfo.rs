@@ -1,12 +1,27 @@
 use chrono::{NaiveDateTime, Utc};
+use serde::Serialize;
 use uuid::Uuid;
 
-#[derive(Clone, Debug)]
+use crate::config::Scm;
+
+#[derive(Clone, Debug, Serialize)]
 pub struct ScmInfo {
     pub uuid: String,
     pub git_url: String,
     pub r#ref: String,
     pub created_at: NaiveDateTime,
+    // Commit the ref resolved to, which the agent checks out before running commands
+    pub sha: Option<String>,
+}
+
+impl ScmInfo {
+    pub fn new(git_url: &str, r#ref: &str) -> Self {
+        Self {
+            git_url: git_url.into(),
+            r#ref: r#ref.into(),
+            ..Default::default()
+        }
+    }
 }
 
 impl Default for ScmInfo {
@@ -16,6 +31,38 @@ impl Default for ScmInfo {
             git_url: "https://example.com/some/repo.git".into(),
             r#ref: "main".into(),
             created_at: Utc::now().naive_utc(),
+            sha: None,
+        }
+    }
+}
+
+impl From<&Scm> for ScmInfo {
+    fn from(scm: &Scm) -> Self {
+        match scm {
+            Scm::Nonexistent => ScmInfo::new("", ""),
+            Scm::GitHub {
+                owner,
+                repo,
+                scm_ref,
+                ..
+            } => ScmInfo::new(
+                &format!("https://github.com/{}/{}.git", owner, repo),
+                scm_ref,
+            ),
+            Scm::Git { url, scm_ref } => ScmInfo::new(url, scm_ref),
+            Scm::GitLab {
+                url,
+                project,
+                scm_ref,
+            }
+            | Scm::Gitea {
+                url,
+                project,
+                scm_ref,
+            } => ScmInfo::new(
+                &format!("{}/{}.git", url.as_str().trim_end_matches('/'), project),
+                scm_ref,
+            ),
         }
     }
 }
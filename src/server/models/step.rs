@@ -0,0 +1,227 @@
+use chrono::{NaiveDateTime, Utc};
+use serde::Serialize;
+use sqlx::SqlitePool;
+use uuid::Uuid;
+
+use crate::models::RunStatus;
+
+/*
+ * A Step is the part of a Run which is executed by a single agent. Runs of the flat Yml
+ * format have exactly one, while the steps of a stage may execute in parallel
+ */
+#[derive(Clone, Debug, Serialize)]
+pub struct Step {
+    pub uuid: String,
+    // Foreign key to runs
+    pub run: String,
+    // Position of the stage within the Yml
+    pub stage: i64,
+    // Position of the step within its stage
+    pub position: i64,
+    pub stage_name: String,
+    pub name: String,
+    // Textual representation of the RunStatus
+    pub status: String,
+    // Name of the agent the Step was dispatched to
+    pub agent: Option<String>,
+    pub log_url: Option<String>,
+    pub stream_url: Option<String>,
+    pub task_url: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub started_at: Option<NaiveDateTime>,
+    pub finished_at: Option<NaiveDateTime>,
+}
+
+impl Step {
+    pub fn new(run: &str, stage: usize, position: usize, stage_name: &str, name: &str) -> Self {
+        Self {
+            uuid: Uuid::new_v4().hyphenated().to_string(),
+            run: run.into(),
+            stage: stage as i64,
+            position: position as i64,
+            stage_name: stage_name.into(),
+            name: name.into(),
+            status: RunStatus::Queued.to_string(),
+            agent: None,
+            log_url: None,
+            stream_url: None,
+            task_url: None,
+            created_at: Utc::now().naive_utc(),
+            started_at: None,
+            finished_at: None,
+        }
+    }
+
+    /*
+     * The status of the Step, unknown statuses are treated as failures
+     */
+    pub fn status(&self) -> RunStatus {
+        self.status.parse().unwrap_or(RunStatus::Failed)
+    }
+
+    /*
+     * How long the Step took to execute, or has been executing for if it is still running
+     */
+    pub fn duration(&self) -> Option<chrono::Duration> {
+        let started_at = self.started_at?;
+        let finished_at = self.finished_at.unwrap_or_else(|| Utc::now().naive_utc());
+        Some(finished_at - started_at)
+    }
+
+    /*
+     * Create all of the steps of a Run at once
+     */
+    pub async fn create_all(steps: &[Step], pool: &SqlitePool) -> Result<(), sqlx::Error> {
+        let mut tx = pool.begin().await?;
+        for step in steps {
+            sqlx::query!(
+                r#"INSERT INTO steps (uuid, run, stage, position, stage_name, name, status, created_at)
+                VALUES (?, ?, ?, ?, ?, ?, ?, ?)"#,
+                step.uuid,
+                step.run,
+                step.stage,
+                step.position,
+                step.stage_name,
+                step.name,
+                step.status,
+                step.created_at,
+            )
+            .execute(&mut tx)
+            .await?;
+        }
+        tx.commit().await
+    }
+
+    /*
+     * List the steps of the given Run in the order they are defined in
+     */
+    pub async fn list_for(run: &str, pool: &SqlitePool) -> Result<Vec<Step>, sqlx::Error> {
+        sqlx::query_as!(
+            Step,
+            "SELECT * FROM steps WHERE run = ? ORDER BY stage, position",
+            run
+        )
+        .fetch_all(pool)
+        .await
+    }
+
+    pub async fn find_by(uuid: &str, pool: &SqlitePool) -> Result<Step, sqlx::Error> {
+        sqlx::query_as!(Step, "SELECT * FROM steps WHERE uuid = ?", uuid)
+            .fetch_one(pool)
+            .await
+    }
+
+    /*
+     * Mark the Step as running once an agent has accepted it. A Step which has already
+     * finished, having been cancelled or reported by the agent in the meantime, keeps its
+     * status
+     */
+    pub async fn started(
+        uuid: &str,
+        agent: &str,
+        log_url: &str,
+        stream_url: Option<&str>,
+        task_url: Option<&str>,
+        pool: &SqlitePool,
+    ) -> Result<(), sqlx::Error> {
+        let status = RunStatus::Running.as_str();
+        let started_at = Utc::now().naive_utc();
+        sqlx::query!(
+            r#"UPDATE steps SET status = CASE WHEN status IN ('queued', 'pending') THEN ? ELSE status END,
+            agent = ?, log_url = ?, stream_url = ?, task_url = ?, started_at = ? WHERE uuid = ?"#,
+            status,
+            agent,
+            log_url,
+            stream_url,
+            task_url,
+            started_at,
+            uuid
+        )
+        .execute(pool)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn update_status(
+        uuid: &str,
+        status: RunStatus,
+        pool: &SqlitePool,
+    ) -> Result<(), sqlx::Error> {
+        let finished_at = match status.is_finished() {
+            true => Some(Utc::now().naive_utc()),
+            false => None,
+        };
+        let status = status.as_str();
+        sqlx::query!(
+            "UPDATE steps SET status = ?, finished_at = ? WHERE uuid = ?",
+            status,
+            finished_at,
+            uuid
+        )
+        .execute(pool)
+        .await?;
+        Ok(())
+    }
+
+    /*
+     * Put a pending Step back in the queue once no agent accepted it
+     */
+    pub async fn requeue(uuid: &str, pool: &SqlitePool) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            "UPDATE steps SET status = 'queued' WHERE uuid = ? AND status = 'pending'",
+            uuid
+        )
+        .execute(pool)
+        .await?;
+        Ok(())
+    }
+
+    /*
+     * Put every pending Step back in the queue, for those left behind by a server which
+     * stopped while handing them to agents
+     */
+    pub async fn requeue_pending(pool: &SqlitePool) -> Result<(), sqlx::Error> {
+        sqlx::query!("UPDATE steps SET status = 'queued' WHERE status = 'pending'")
+            .execute(pool)
+            .await?;
+        Ok(())
+    }
+
+    /*
+     * Cancel every step of the Run which has not finished yet
+     */
+    pub async fn cancel_unfinished(run: &str, pool: &SqlitePool) -> Result<(), sqlx::Error> {
+        let status = RunStatus::Cancelled.as_str();
+        let finished_at = Utc::now().naive_utc();
+        sqlx::query!(
+            r#"UPDATE steps SET status = ?, finished_at = ?
+            WHERE run = ? AND status IN ('queued', 'pending', 'running')"#,
+            status,
+            finished_at,
+            run
+        )
+        .execute(pool)
+        .await?;
+        Ok(())
+    }
+}
+
+/*
+ * Convert the Step into the representation shared with API clients
+ */
+impl TryFrom<&Step> for synchronik::StepResponse {
+    type Error = anyhow::Error;
+
+    fn try_from(step: &Step) -> Result<Self, Self::Error> {
+        Ok(Self {
+            uuid: Uuid::parse_str(&step.uuid)?,
+            stage: step.stage_name.clone(),
+            name: step.name.clone(),
+            status: step.status(),
+            agent: step.agent.clone(),
+            log: step.log_url.as_deref().map(url::Url::parse).transpose()?,
+            started_at: step.started_at.map(|t| t.and_utc()),
+            finished_at: step.finished_at.map(|t| t.and_utc()),
+        })
+    }
+}
@@ -0,0 +1,199 @@
+/**
+ * The parameters module contains the typed inputs which a project can declare for its
+ * manually triggered runs, and the validation of the values provided for them.
+ */
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct Parameter {
+    /*
+     * The name is also the name of the environment variable the value is exposed as
+     */
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(flatten)]
+    pub kind: ParameterKind,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ParameterKind {
+    // Free form text, required when there is no default
+    String {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        default: Option<String>,
+    },
+    Boolean {
+        #[serde(default)]
+        default: bool,
+    },
+    // One of a fixed set of values, defaulting to the first choice
+    Choice {
+        choices: Vec<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        default: Option<String>,
+    },
+}
+
+impl Parameter {
+    /*
+     * Validate the value provided for the parameter, falling back to its default
+     */
+    fn resolve(&self, value: Option<&String>) -> Result<String, String> {
+        match (&self.kind, value) {
+            (ParameterKind::String { .. }, Some(value)) => Ok(value.clone()),
+            (ParameterKind::String { default }, None) => default
+                .clone()
+                .ok_or_else(|| format!("The parameter {} is required", self.name)),
+            (ParameterKind::Boolean { .. }, Some(value)) => match value.as_str() {
+                "true" | "false" => Ok(value.clone()),
+                _ => Err(format!(
+                    "The parameter {} must be true or false, not {}",
+                    self.name, value
+                )),
+            },
+            (ParameterKind::Boolean { default }, None) => Ok(default.to_string()),
+            (ParameterKind::Choice { choices, .. }, Some(value)) => match choices.contains(value) {
+                true => Ok(value.clone()),
+                false => Err(format!(
+                    "The parameter {} must be one of {}, not {}",
+                    self.name,
+                    choices.join(", "),
+                    value
+                )),
+            },
+            (ParameterKind::Choice { choices, default }, None) => default
+                .as_ref()
+                .or_else(|| choices.first())
+                .cloned()
+                .ok_or_else(|| format!("The parameter {} has no choices", self.name)),
+        }
+    }
+}
+
+/*
+ * Check the declarations of the parameters, which is done when they are loaded so that a
+ * mistake is found before anyone tries to trigger a run
+ */
+pub fn validate(parameters: &[Parameter]) -> Result<(), String> {
+    for parameter in parameters {
+        if let ParameterKind::Choice { choices, default } = &parameter.kind {
+            if choices.is_empty() {
+                return Err(format!("The parameter {} has no choices", parameter.name));
+            }
+            if let Some(default) = default.as_ref().filter(|d| !choices.contains(d)) {
+                return Err(format!(
+                    "The default {} of the parameter {} is not one of {}",
+                    default,
+                    parameter.name,
+                    choices.join(", ")
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+/*
+ * Validate the provided values against the declared parameters, returning the value of
+ * every parameter with defaults filled in
+ */
+pub fn resolve(
+    parameters: &[Parameter],
+    values: &HashMap<String, String>,
+) -> Result<HashMap<String, String>, String> {
+    if let Some(unknown) = values
+        .keys()
+        .find(|name| !parameters.iter().any(|p| &p.name == *name))
+    {
+        return Err(format!("Unknown parameter {}", unknown));
+    }
+
+    parameters
+        .iter()
+        .map(|p| Ok((p.name.clone(), p.resolve(values.get(&p.name))?)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parameters() -> Vec<Parameter> {
+        serde_yaml::from_str(
+            r#"
+- name: 'TARGET'
+  type: 'string'
+- name: 'GREETING'
+  type: 'string'
+  default: 'hello'
+- name: 'VERBOSE'
+  type: 'boolean'
+- name: 'ENVIRONMENT'
+  type: 'choice'
+  choices: ['staging', 'production']
+"#,
+        )
+        .expect("Failed to parse parameters")
+    }
+
+    fn values(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_resolve_defaults() {
+        let resolved = resolve(&parameters(), &values(&[("TARGET", "all")])).unwrap();
+        assert_eq!(
+            values(&[
+                ("TARGET", "all"),
+                ("GREETING", "hello"),
+                ("VERBOSE", "false"),
+                ("ENVIRONMENT", "staging"),
+            ]),
+            resolved
+        );
+    }
+
+    #[test]
+    fn test_resolve_required() {
+        assert!(resolve(&parameters(), &HashMap::new()).is_err());
+    }
+
+    #[test]
+    fn test_resolve_invalid_values() {
+        let base = [("TARGET", "all")];
+        for invalid in [
+            ("VERBOSE", "yes"),
+            ("ENVIRONMENT", "development"),
+            ("UNKNOWN", "value"),
+        ] {
+            let mut provided = values(&base);
+            provided.insert(invalid.0.into(), invalid.1.into());
+            assert!(
+                resolve(&parameters(), &provided).is_err(),
+                "{:?} should be invalid",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(validate(&parameters()).is_ok());
+
+        for invalid in [
+            "- name: 'ENVIRONMENT'\n  type: 'choice'\n  choices: ['staging']\n  default: 'production'\n",
+            "- name: 'ENVIRONMENT'\n  type: 'choice'\n  choices: []\n",
+        ] {
+            let parameters: Vec<Parameter> = serde_yaml::from_str(invalid).unwrap();
+            assert!(validate(&parameters).is_err(), "{} should be invalid", invalid);
+        }
+    }
+}
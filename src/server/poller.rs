@@ -0,0 +1,151 @@
+/**
+ * The poller checks the source control systems of projects which cannot send webhooks,
+ * enqueuing a run whenever the ref of a project points at a commit which has not been
+ * built yet.
+ */
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use log::*;
+
+use crate::config::Project;
+use crate::models::Run;
+use crate::routes::api::enqueue;
+use crate::AppState;
+
+/*
+ * The shortest time between two checks of the same project
+ */
+const MINIMUM_INTERVAL: Duration = Duration::from_secs(10);
+
+/*
+ * Loop forever polling the projects which have a poll interval
+ */
+pub async fn run(state: AppState<'_>) {
+    let polled: Vec<(&String, &Project, Duration)> = state
+        .config
+        .projects
+        .iter()
+        .filter_map(|(name, project)| {
+            project
+                .poll
+                .map(|p| (name, project, Duration::from_secs(p).max(MINIMUM_INTERVAL)))
+        })
+        .collect();
+    if polled.is_empty() {
+        debug!("No projects to poll");
+        return;
+    }
+    debug!("Poller starting for {} projects", polled.len());
+
+    let mut due: HashMap<&String, Instant> = HashMap::new();
+    loop {
+        for (name, project, interval) in polled.iter() {
+            if due.get(name).is_none_or(|d| *d <= Instant::now()) {
+                if let Err(e) = poll(&state, name, project).await {
+                    error!("Failed to poll {}: {:?}", name, e);
+                }
+                due.insert(name, Instant::now() + *interval);
+            }
+        }
+
+        let next = due.values().min().copied().unwrap_or_else(Instant::now);
+        async_std::task::sleep(next.saturating_duration_since(Instant::now())).await;
+    }
+}
+
+/*
+ * Enqueue a Run of the project when its ref points at a different commit than the one
+ * the most recent Run of that ref was created from. Runs of pull requests, or of other
+ * refs, don't say anything about whether the ref has been built
+ */
+async fn poll(state: &AppState<'_>, name: &str, project: &Project) -> anyhow::Result<Option<Run>> {
+    let sha = match crate::scm::current_sha(project, &state.config, &state.http).await? {
+        Some(sha) => sha,
+        None => return Ok(None),
+    };
+
+    let record = crate::models::Project::by_name(name, &state.db).await?;
+    let scm_ref = crate::models::ScmInfo::from(&project.scm).r#ref;
+    let last = Run::latest_on_ref(&record, &scm_ref, &state.db).await?;
+    if last.as_ref().and_then(|r| r.scm_info.sha.as_ref()) == Some(&sha) {
+        debug!("{} is still at {}", name, sha);
+        return Ok(None);
+    }
+
+    info!("{} has moved to {}, enqueuing a run", name, sha);
+    let resolved =
+        crate::scm::resolve(name, project, &state.config, &state.http, Some(&sha)).await?;
+    let parameters =
+        crate::parameters::resolve(&project.parameters(Some(&resolved.config)), &HashMap::new())
+            .map_err(anyhow::Error::msg)?;
+    let run = enqueue(state, name, resolved, &parameters, "poll", None)
+        .await
+        .map_err(|e| e.into_inner())?;
+    Ok(Some(run))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ServerConfig;
+    use crate::testing::{commit, git, repository};
+    use sqlx::SqlitePool;
+
+    #[async_std::test]
+    async fn test_poll() {
+        let repo = repository("poll");
+        commit(&repo, "synchronik.yml", "needs: []\ncommands:\n- 'true'\n");
+
+        let pool = SqlitePool::connect(":memory:").await.unwrap();
+        sqlx::migrate!().run(&pool).await.unwrap();
+        let config: ServerConfig = serde_yaml::from_str(&format!(
+            "agents: {{}}\nprojects:\n  'polled':\n    description: 'Polled'\n    poll: 60\n    scm:\n      git:\n        url: 'file://{}'\n        ref: 'main'\n",
+            repo.display()
+        ))
+        .unwrap();
+        crate::models::Project::create(&crate::models::Project::new("polled"), &pool)
+            .await
+            .unwrap();
+        let state = AppState::new(pool, config);
+        let project = &state.config.projects["polled"];
+
+        let first = poll(&state, "polled", project)
+            .await
+            .unwrap()
+            .expect("The first poll should enqueue a run");
+        assert_eq!("poll", first.run.trigger);
+        assert_eq!(Some(git(&repo, &["rev-parse", "HEAD"])), first.scm_info.sha);
+        assert!(poll(&state, "polled", project).await.unwrap().is_none());
+
+        git(
+            &repo,
+            &["commit", "--quiet", "--allow-empty", "-m", "second"],
+        );
+        let second = poll(&state, "polled", project)
+            .await
+            .unwrap()
+            .expect("A new commit should enqueue a run");
+        assert_eq!(
+            Some(git(&repo, &["rev-parse", "HEAD"])),
+            second.scm_info.sha
+        );
+        assert_eq!(2, second.run.num);
+
+        /*
+         * A newer Run of a pull request doesn't mean the ref has moved
+         */
+        let mut scm_info =
+            crate::models::ScmInfo::new(&second.scm_info.git_url, "refs/pull/1/head");
+        scm_info.sha = Some("0".repeat(40));
+        let pull = Run::new(
+            second.project.clone(),
+            scm_info,
+            crate::models::RunDefinition::new(&second.definition.definition),
+        );
+        Run::create(&pull, &state.db).await.unwrap();
+        assert!(poll(&state, "polled", project).await.unwrap().is_none());
+
+        let _ = std::fs::remove_dir_all(&repo);
+    }
+}
@@ -5,11 +5,83 @@
  * Modules are nested for cleaner organization here
  */
 use log::*;
-use tide::{Body, Request};
+use serde::Deserialize;
+use tide::{Body, Request, StatusCode};
 
-use crate::models::Project;
+use crate::models::{Project, Run};
 use crate::AppState;
 
+/*
+ * Number of runs shown on each page of a project's history
+ */
+const RUNS_PER_PAGE: i64 = 25;
+
+/*
+ * Largest part of a log embedded in the run page, longer logs only have their end shown
+ */
+const MAX_CONSOLE: usize = 1024 * 1024;
+/*
+ * How long the agent is given to send the log for the run page
+ */
+const LOG_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct Pagination {
+    page: Option<i64>,
+}
+
+/*
+ * Look up the project by name, turning a missing project into a 404
+ */
+pub(crate) async fn find_project(name: &str, state: &AppState<'_>) -> Result<Project, tide::Error> {
+    match Project::by_name(name, &state.db).await {
+        Ok(project) => Ok(project),
+        Err(sqlx::Error::RowNotFound) => Err(tide::Error::from_str(
+            StatusCode::NotFound,
+            format!("No project named {}", name),
+        )),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/*
+ * The parameters the project can be triggered with. A definition read from source control
+ * is only known once it has been resolved, so the one resolved for its latest Run is used
+ */
+async fn parameters_of(
+    project: &Project,
+    state: &AppState<'_>,
+) -> Result<Vec<crate::parameters::Parameter>, sqlx::Error> {
+    let configured = match state.config.projects.get(&project.name) {
+        Some(configured) => configured,
+        None => return Ok(vec![]),
+    };
+    if configured.inline.is_some() {
+        return Ok(configured.parameters(configured.inline.as_ref()));
+    }
+
+    let resolved = Run::list_for(project, 1, 0, &state.db)
+        .await?
+        .into_iter()
+        .next()
+        .and_then(|run| {
+            serde_yaml::from_str::<crate::config::Yml>(&run.definition.definition).ok()
+        });
+    Ok(configured.parameters(resolved.as_ref()))
+}
+
+/*
+ * Render a duration in a compact human readable form, e.g. 1m 5s
+ */
+fn format_duration(duration: Option<chrono::Duration>) -> Option<String> {
+    let seconds = duration?.num_seconds();
+    Some(match seconds {
+        s if s >= 3600 => format!("{}h {}m", s / 3600, (s % 3600) / 60),
+        s if s >= 60 => format!("{}m {}s", s / 60, s % 60),
+        s => format!("{}s", s),
+    })
+}
+
 /**
  *  GET /
  */
@@ -20,11 +92,24 @@ pub async fn index(req: Request<AppState<'_>>) -> Result<Body, tide::Error> {
         .iter()
         .map(|a| a.render_compact(req.state()))
         .collect();
+
+    /*
+     * Projects with parameters cannot be triggered from the home page since their values
+     * are entered on the project's page
+     */
+    let mut projects: Vec<serde_json::Value> = vec![];
+    for project in Project::list(&req.state().db).await? {
+        let parameterized = !parameters_of(&project, req.state()).await?.is_empty();
+        projects.push(json!({
+            "name" : project.name,
+            "parameterized" : parameterized,
+        }));
+    }
     let params = json!({
         "page": "home",
         "agents" : agents,
         "config" : req.state().config,
-        "projects" : Project::list(&req.state().db).await?,
+        "projects" : projects,
     });
 
     debug!("Rendering home page with: {:?}", params);
@@ -38,8 +123,50 @@ pub async fn index(req: Request<AppState<'_>>) -> Result<Body, tide::Error> {
  */
 pub async fn project(req: Request<AppState<'_>>) -> Result<Body, tide::Error> {
     let name: String = req.param("name")?.into();
+    let page = req.query::<Pagination>()?.page.unwrap_or(1).max(1);
+    let project = find_project(&name, req.state()).await?;
+
+    /*
+     * Fetch one more run than will be shown to determine whether there is a next page
+     */
+    let mut runs = Run::list_for(
+        &project,
+        RUNS_PER_PAGE + 1,
+        (page - 1) * RUNS_PER_PAGE,
+        &req.state().db,
+    )
+    .await?;
+    let has_next = runs.len() as i64 > RUNS_PER_PAGE;
+    runs.truncate(RUNS_PER_PAGE as usize);
+
+    let runs: Vec<serde_json::Value> = runs
+        .iter()
+        .map(|run| {
+            json!({
+                "run" : run,
+                "duration" : format_duration(run.run.duration()),
+            })
+        })
+        .collect();
+    let parameters = parameters_of(&project, req.state()).await?;
+    let schedule = req
+        .state()
+        .config
+        .projects
+        .get(&name)
+        .and_then(|p| p.schedule.as_ref());
+    let next_run = schedule
+        .and_then(|s| s.next_after(chrono::Utc::now()).ok().flatten())
+        .map(|n| n.format("%Y-%m-%d %H:%M %Z").to_string());
     let params = json!({
         "name" : name,
+        "parameters" : parameters,
+        "schedule" : schedule,
+        "next_run" : next_run,
+        "runs" : runs,
+        "page" : page,
+        "previous" : (page > 1).then(|| page - 1),
+        "next" : has_next.then(|| page + 1),
     });
 
     let mut body = req.state().render("project", &params).await?;
@@ -47,17 +174,387 @@ pub async fn project(req: Request<AppState<'_>>) -> Result<Body, tide::Error> {
     Ok(body)
 }
 
+/**
+ * GET /project/:name/runs/:num
+ */
+pub async fn run(req: Request<AppState<'_>>) -> Result<Body, tide::Error> {
+    let name: String = req.param("name")?.into();
+    let num: i64 = req.param("num")?.parse()?;
+    let project = find_project(&name, req.state()).await?;
+    let run = match Run::find_by_num(&project, num, &req.state().db).await {
+        Ok(run) => run,
+        Err(sqlx::Error::RowNotFound) => {
+            return Err(tide::Error::from_str(
+                StatusCode::NotFound,
+                format!("No run #{} for {}", num, name),
+            ))
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    /*
+     * Once the run has finished the whole log is fetched from the agent, otherwise the
+     * page follows the live stream
+     */
+    let mut console = None;
+    let mut truncated = false;
+    if let (Some(_), Some(log_url)) = (&run.run.finished_at, &run.run.log_url) {
+        match fetch_log(&req.state().http, log_url).await {
+            Ok((log, cut)) => {
+                console = Some(log);
+                truncated = cut;
+            }
+            Err(e) => warn!("Failed to fetch the log for {}: {:?}", run.run.uuid, e),
+        }
+    }
+
+    /*
+     * Runs with more than one step have them broken down by stage, each step with its own
+     * agent and log
+     */
+    let mut stages: Vec<serde_json::Value> = vec![];
+    if run.steps.len() > 1 {
+        for step in run.steps.iter() {
+            let step = json!({
+                "step" : step,
+                "duration" : format_duration(step.duration()),
+            });
+            match stages.last_mut() {
+                Some(stage) if stage["name"] == step["step"]["stage_name"] => {
+                    stage["steps"].as_array_mut().unwrap().push(step)
+                }
+                _ => stages.push(json!({
+                    "name" : step["step"]["stage_name"],
+                    "steps" : [step],
+                })),
+            }
+        }
+    }
+
+    let graph = match serde_yaml::from_str::<crate::config::Yml>(&run.definition.definition) {
+        Ok(config) if run.steps.len() > 1 => {
+            crate::graph::layout(&run.steps, &config.dependencies())
+        }
+        _ => None,
+    };
+
+    let params = json!({
+        "name" : name,
+        "run" : run,
+        "stages" : stages,
+        "graph" : graph,
+        "parameters" : run.run.parameters(),
+        "duration" : format_duration(run.run.duration()),
+        "console" : console,
+        "truncated" : truncated,
+    });
+
+    let mut body = req.state().render("run", &params).await?;
+    body.set_mime("text/html");
+    Ok(body)
+}
+
+/*
+ * Fetch the end of the log from the agent, returning whether the start of it had to be
+ * left out to stay within MAX_CONSOLE
+ */
+async fn fetch_log(client: &reqwest::Client, log_url: &str) -> reqwest::Result<(String, bool)> {
+    let mut res = client
+        .get(log_url)
+        .timeout(LOG_TIMEOUT)
+        .send()
+        .await?
+        .error_for_status()?;
+
+    let mut log: Vec<u8> = vec![];
+    let mut truncated = false;
+    while let Some(chunk) = res.chunk().await? {
+        log.extend_from_slice(&chunk);
+        if log.len() > 2 * MAX_CONSOLE {
+            log.drain(..log.len() - MAX_CONSOLE);
+            truncated = true;
+        }
+    }
+    if log.len() > MAX_CONSOLE {
+        log.drain(..log.len() - MAX_CONSOLE);
+        truncated = true;
+    }
+
+    /*
+     * Start at a whole line rather than part way through one, or a character
+     */
+    if truncated {
+        if let Some(newline) = log.iter().position(|b| *b == b'\n') {
+            log.drain(..=newline);
+        }
+    }
+    Ok((String::from_utf8_lossy(&log).into_owned(), truncated))
+}
+
 pub mod api {
-    use crate::config::{Scm, Yml};
-    use crate::Agent;
+    use std::collections::HashMap;
+
+    use super::{find_project, Pagination, RUNS_PER_PAGE};
+    use crate::dispatcher;
+    use crate::models::{Project, Run, RunDefinition, RunStatus, Step};
     use crate::AppState;
+    use async_std::prelude::*;
+    use async_tungstenite::tungstenite::Message;
     use log::*;
     use serde::Deserialize;
-    use tide::{Request, Response, StatusCode};
+    use tide::{Body, Request, Response, StatusCode};
+    use tide_websockets::WebSocketConnection;
 
-    #[derive(Debug, Deserialize)]
-    struct RedirectedForm {
+    /*
+     * What was submitted along with a request, either as a form post from the web UI or
+     * as JSON from an API client
+     */
+    #[derive(Debug, Default)]
+    struct Submission {
+        // Where the browser should be redirected to once the request has been handled
         next: Option<String>,
+        parameters: HashMap<String, String>,
+    }
+
+    #[derive(Debug, Deserialize)]
+    struct JsonSubmission {
+        #[serde(default)]
+        parameters: HashMap<String, serde_json::Value>,
+    }
+
+    /*
+     * Read the submission from the body of the request. Every form field other than next
+     * is a parameter, and clients which ask for JSON are never redirected
+     */
+    async fn read_submission(req: &mut Request<AppState<'_>>) -> tide::Result<Submission> {
+        let json = req
+            .header("Accept")
+            .map(|accept| accept.as_str().contains("application/json"))
+            .unwrap_or(false);
+
+        let mut submission = match req.content_type() {
+            Some(mime) if mime.essence() == "application/x-www-form-urlencoded" => {
+                let mut fields: HashMap<String, String> = req.body_form().await?;
+                Submission {
+                    next: fields.remove("next"),
+                    parameters: fields,
+                }
+            }
+            Some(mime) if mime.essence() == "application/json" => {
+                let body: JsonSubmission = req.body_json().await?;
+                Submission {
+                    next: None,
+                    parameters: body
+                        .parameters
+                        .into_iter()
+                        .map(|(name, value)| match value {
+                            serde_json::Value::String(value) => (name, value),
+                            value => (name, value.to_string()),
+                        })
+                        .collect(),
+                }
+            }
+            _ => Submission::default(),
+        };
+
+        if json {
+            submission.next = None;
+        }
+        Ok(submission)
+    }
+
+    /*
+     * Build the response describing a newly triggered Run
+     */
+    fn trigger_response(
+        run: &Run,
+        state: &AppState<'_>,
+    ) -> Result<synchronik::TriggerResponse, tide::Error> {
+        let response = synchronik::RunResponse::try_from(run)?;
+
+        /*
+         * Only a Run executed as a single step has a log stream of its own, the steps have
+         * not been planned yet so they are counted from the definition
+         */
+        let steps: usize = serde_yaml::from_str::<crate::config::Yml>(&run.definition.definition)
+            .map(|config| config.stages().iter().map(|s| s.steps.len()).sum())
+            .unwrap_or_default();
+        let stream = match steps {
+            1 => {
+                let mut stream = state
+                    .url
+                    .join(&format!("/api/v1/runs/{}/stream", run.run.uuid))?;
+                let scheme = match stream.scheme() {
+                    "https" => "wss",
+                    _ => "ws",
+                };
+                stream
+                    .set_scheme(scheme)
+                    .expect("Failed to set the WebSockets scheme");
+                Some(stream)
+            }
+            _ => None,
+        };
+
+        Ok(synchronik::TriggerResponse {
+            uuid: response.uuid,
+            num: response.num,
+            project: response.project,
+            status: response.status,
+            agent: response.agent,
+            run: state.url.join(&format!("/api/v1/runs/{}", run.run.uuid))?,
+            page: state.url.join(&format!(
+                "/project/{}/runs/{}",
+                run.project.name, run.run.num
+            ))?,
+            log: response.log,
+            stream,
+        })
+    }
+
+    /*
+     * Combine the configuration of the project with its database record
+     */
+    fn project_response(
+        project: &Project,
+        state: &AppState<'_>,
+    ) -> Option<synchronik::ProjectResponse> {
+        let config = state.config.projects.get(&project.name)?;
+        Some(synchronik::ProjectResponse {
+            name: project.name.clone(),
+            description: config.description.clone(),
+            labels: config.labels.clone(),
+            created_at: project.created_at.and_utc(),
+        })
+    }
+
+    /**
+     *  GET /projects
+     */
+    pub async fn list_projects(req: Request<AppState<'_>>) -> tide::Result {
+        let state = req.state();
+        let projects: HashMap<String, Project> = Project::list(&state.db)
+            .await?
+            .into_iter()
+            .map(|p| (p.name.clone(), p))
+            .collect();
+
+        /*
+         * Only projects which are still configured are listed, the database may hold
+         * projects which have since been removed from the configuration
+         */
+        let mut names: Vec<&String> = state.config.projects.keys().collect();
+        names.sort();
+        let projects = names
+            .into_iter()
+            .filter_map(|name| projects.get(name))
+            .filter_map(|project| project_response(project, state))
+            .collect();
+
+        Ok(Body::from_json(&synchronik::ProjectsResponse { projects })?.into())
+    }
+
+    /**
+     *  GET /projects/{name}
+     */
+    pub async fn get_project(req: Request<AppState<'_>>) -> tide::Result {
+        let name: String = req.param("name")?.into();
+        let state = req.state();
+
+        match project_response(&find_project(&name, state).await?, state) {
+            Some(project) => Ok(Body::from_json(&project)?.into()),
+            None => Ok(Response::new(StatusCode::NotFound)),
+        }
+    }
+
+    /**
+     *  GET /projects/{name}/runs
+     *
+     *  List the runs of the project most recent first, a page at a time
+     */
+    pub async fn list_runs(req: Request<AppState<'_>>) -> tide::Result {
+        let name: String = req.param("name")?.into();
+        let page = req.query::<Pagination>()?.page.unwrap_or(1).max(1);
+        let state = req.state();
+        let project = find_project(&name, state).await?;
+
+        let runs = Run::list_for(
+            &project,
+            RUNS_PER_PAGE,
+            (page - 1) * RUNS_PER_PAGE,
+            &state.db,
+        )
+        .await?
+        .iter()
+        .map(synchronik::RunResponse::try_from)
+        .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Body::from_json(&synchronik::RunsResponse { runs })?.into())
+    }
+
+    /**
+     *  GET /runs/{uuid}
+     */
+    pub async fn get_run(req: Request<AppState<'_>>) -> tide::Result {
+        let uuid: String = req.param("uuid")?.into();
+
+        match Run::find_by(&uuid, &req.state().db).await {
+            Ok(run) => {
+                let run = synchronik::RunResponse::try_from(&run)?;
+                Ok(Body::from_json(&run)?.into())
+            }
+            Err(sqlx::Error::RowNotFound) => Ok(Response::new(StatusCode::NotFound)),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /**
+     *  GET /agents
+     */
+    pub async fn list_agents(req: Request<AppState<'_>>) -> tide::Result {
+        let agents = req
+            .state()
+            .agents
+            .iter()
+            .map(|agent| synchronik::AgentResponse {
+                name: agent.name.clone(),
+                url: agent.url.clone(),
+                capabilities: agent.capabilities.clone(),
+                labels: agent.labels.clone(),
+            })
+            .collect();
+
+        Ok(Body::from_json(&synchronik::AgentsResponse { agents })?.into())
+    }
+
+    /*
+     * Create a Run of the project from its resolved definition and wake the dispatcher
+     * loop to hand it to an agent. If no agent is available it remains queued
+     */
+    pub(crate) async fn enqueue(
+        state: &AppState<'_>,
+        name: &str,
+        resolved: crate::scm::Resolved,
+        parameters: &HashMap<String, String>,
+        trigger: &str,
+        sender: Option<&str>,
+    ) -> tide::Result<Run> {
+        let mut run = Run::new(
+            Project::by_name(name, &state.db).await?,
+            resolved.scm_info,
+            RunDefinition::new(&resolved.definition),
+        );
+        run.run.parameters = serde_json::to_string(parameters)?;
+        run.run.trigger = trigger.into();
+        run.run.sender = sender.map(String::from);
+        let run = Run::create(&run, &state.db).await?;
+        info!(
+            "Created run #{} for {} at {} by {}: {}",
+            run.run.num, name, run.run.created_at, trigger, run.run.uuid
+        );
+
+        state.wake_dispatcher();
+        Ok(run)
     }
 
     /**
@@ -65,7 +562,7 @@ pub mod api {
      */
     pub async fn execute_project(mut req: Request<AppState<'_>>) -> tide::Result {
         let name: String = req.param("name")?.into();
-        let next: RedirectedForm = req.body_form().await?;
+        let submission = read_submission(&mut req).await?;
         let state = req.state();
 
         if !state.config.has_project(&name) {
@@ -74,69 +571,760 @@ pub mod api {
         }
 
         if let Some(project) = state.config.projects.get(&name) {
-            match &project.scm {
-                Scm::Nonexistent => {
-                    info!("Nonexistent SCM, using inline configuration for {}", name);
-                    info!("configuration: {:?}", project.inline);
-                    if let Some(config) = &project.inline {
-                        execute_commands(config, &state.agents).await?;
-                        if let Some(red) = &next.next {
-                            return Ok(tide::Redirect::new(red).into());
-                        }
-                    }
-                }
-                Scm::GitHub {
-                    owner,
-                    repo,
-                    scm_ref,
-                } => {
-                    let filename = match &project.filename {
-                        None => "synchronik.yml".to_string(),
-                        Some(filename) => filename.to_string(),
-                    };
-                    debug!("Fetching the file {} from {}/{}", filename, owner, repo);
-                    let res = octocrab::instance()
-                        .repos(owner, repo)
-                        .raw_file(octocrab::params::repos::Commitish(scm_ref.into()), filename)
-                        .await?;
-                    let config_file: Yml = serde_yaml::from_str(&res.text().await?)?;
-                    debug!("text: {:?}", config_file);
-                    execute_commands(&config_file, &state.agents).await?;
-                    if let Some(red) = &next.next {
-                        return Ok(tide::Redirect::new(red).into());
-                    }
-                }
+            /*
+             * Resolve the exact text of the Yml definition so that it can be recorded with
+             * the Run and later handed to an agent by the dispatcher
+             */
+            let resolved = crate::scm::resolve(&name, project, &state.config, &state.http, None)
+                .await
+                .map_err(|e| {
+                    error!("Failed to resolve the definition of {}: {:?}", name, e);
+                    tide::Error::from_str(StatusCode::InternalServerError, e.to_string())
+                })?;
+
+            let parameters = crate::parameters::resolve(
+                &project.parameters(Some(&resolved.config)),
+                &submission.parameters,
+            )
+            .map_err(|e| tide::Error::from_str(StatusCode::BadRequest, e))?;
+
+            let run = enqueue(state, &name, resolved, &parameters, "manual", None).await?;
+
+            if let Some(red) = &submission.next {
+                return Ok(tide::Redirect::new(red).into());
             }
-            return Ok("{}".into());
+
+            let trigger = trigger_response(&run, state)?;
+            let mut response = Response::new(StatusCode::Created);
+            response.insert_header("Location", trigger.run.as_str());
+            response.set_body(Body::from_json(&trigger)?);
+            return Ok(response);
         }
         Ok(Response::new(StatusCode::InternalServerError))
     }
 
-    async fn execute_commands(config: &Yml, agents: &Vec<Agent>) -> Result<(), tide::Error> {
-        debug!("working {:?}", config);
-        for agent in agents {
-            debug!("agent: {:?}", agent);
-            if agent.can_meet(&config.needs) {
-                debug!("agent: {:?} can meet our needs", agent);
-                let commands: Vec<synchronik::Command> = config
-                    .commands
-                    .iter()
-                    .map(|c| synchronik::Command::with_script(c))
-                    .collect();
-                let commands = synchronik::CommandRequest { commands };
-                let client = reqwest::Client::new();
-                let _res = client
-                    .put(
-                        agent
-                            .url
-                            .join("/api/v1/execute")
-                            .expect("Failed to join execute URL"),
-                    )
-                    .json(&commands)
-                    .send()
-                    .await?;
+    /**
+     *  POST /runs/{uuid}/cancel
+     *
+     *  Cancel the Run, asking the agent executing it to stop if it has been dispatched
+     */
+    pub async fn cancel_run(mut req: Request<AppState<'_>>) -> tide::Result {
+        let uuid: String = req.param("uuid")?.into();
+        let next = read_submission(&mut req).await?.next;
+        let state = req.state();
+
+        /*
+         * Holding the dispatch lock ensures a queued Run is not handed to an agent while it
+         * is being cancelled, the agents are only asked to stop once it has been released
+         */
+        let guard = state.dispatch_lock.lock().await;
+        let run = match Run::find_by(&uuid, &state.db).await {
+            Ok(run) => run,
+            Err(sqlx::Error::RowNotFound) => return Ok(Response::new(StatusCode::NotFound)),
+            Err(e) => return Err(e.into()),
+        };
+
+        let status: RunStatus = run.run.status.parse().map_err(tide::Error::from_display)?;
+        if status.is_finished() {
+            return Ok(Response::new(StatusCode::Conflict));
+        }
+
+        /*
+         * Runs dispatched before they had steps only know the task of the Run itself
+         */
+        let tasks: Vec<String> = match run.steps.is_empty() {
+            true => run.run.task_url.iter().cloned().collect(),
+            false => run
+                .steps
+                .iter()
+                .filter(|step| !step.status().is_finished())
+                .filter_map(|step| step.task_url.clone())
+                .collect(),
+        };
+
+        info!("Cancelling run {}", uuid);
+        Step::cancel_unfinished(&uuid, &state.db).await?;
+        Run::update_status(&uuid, RunStatus::Cancelled, &state.db).await?;
+        drop(guard);
+        dispatcher::cancel_tasks(&state.http, tasks).await;
+
+        if let Some(red) = &next {
+            return Ok(tide::Redirect::new(red).into());
+        }
+        let run = synchronik::RunResponse::try_from(&Run::find_by(&uuid, &state.db).await?)?;
+        Ok(Body::from_json(&run)?.into())
+    }
+
+    /*
+     * The token of the Run which the agent was handed in its callback URL
+     */
+    fn callback_token(req: &Request<AppState<'_>>) -> Option<String> {
+        #[derive(Deserialize)]
+        struct Callback {
+            token: Option<String>,
+        }
+        req.query::<Callback>().ok().and_then(|c| c.token)
+    }
+
+    /**
+     *  PUT /runs/{uuid}/steps/{step}/status
+     *
+     *  Record the outcome of a step once the agent executing it has finished, which may
+     *  finish the Run or allow the steps of its next stage to be dispatched
+     */
+    pub async fn update_step_status(mut req: Request<AppState<'_>>) -> tide::Result {
+        let uuid: String = req.param("uuid")?.into();
+        let step: String = req.param("step")?.into();
+        let token = callback_token(&req);
+        let task: synchronik::TaskStatus = req.body_json().await?;
+        let state = req.state();
+
+        match Run::find_by(&uuid, &state.db).await {
+            Ok(run) if token.is_some() && run.run.token == token => {}
+            Ok(_) => return Ok(Response::new(StatusCode::Forbidden)),
+            Err(sqlx::Error::RowNotFound) => return Ok(Response::new(StatusCode::NotFound)),
+            Err(e) => return Err(e.into()),
+        }
+
+        let stopped = {
+            let _guard = state.dispatch_lock.lock().await;
+            let step = match Step::find_by(&step, &state.db).await {
+                Ok(step) if step.run == uuid => step,
+                Ok(_) | Err(sqlx::Error::RowNotFound) => {
+                    return Ok(Response::new(StatusCode::NotFound))
+                }
+                Err(e) => return Err(e.into()),
+            };
+
+            /*
+             * Steps which were cancelled, along with their Run, stay cancelled
+             */
+            if step.status().is_finished() {
+                debug!(
+                    "Ignoring the status of finished step {}: {:?}",
+                    step.uuid, task
+                );
+                return Ok(Response::new(StatusCode::Ok));
+            }
+
+            let status = RunStatus::from(task.state);
+            debug!(
+                "Updating step {} of {} to {}: {:?}",
+                step.name, uuid, status, task
+            );
+            Step::update_status(&step.uuid, status, &state.db).await?;
+            match status.is_finished() {
+                true => dispatcher::settle(state, &uuid).await?,
+                false => vec![],
+            }
+        };
+
+        dispatcher::cancel_tasks(&state.http, stopped).await;
+        state.wake_dispatcher();
+        Ok(Response::new(StatusCode::Ok))
+    }
+
+    /**
+     *  PUT /runs/{uuid}/status
+     *
+     *  Record the outcome of a Run once the agent executing it has finished, only Runs
+     *  dispatched before they had steps report their status here
+     */
+    pub async fn update_run_status(mut req: Request<AppState<'_>>) -> tide::Result {
+        let uuid: String = req.param("uuid")?.into();
+        let token = callback_token(&req);
+        let task: synchronik::TaskStatus = req.body_json().await?;
+        let state = req.state();
+
+        match Run::find_by(&uuid, &state.db).await {
+            Ok(run) if token.is_none() || run.run.token != token => {
+                Ok(Response::new(StatusCode::Forbidden))
+            }
+            Ok(run) if run.run.status == RunStatus::Cancelled.as_str() => {
+                debug!("Ignoring the status of cancelled run {}: {:?}", uuid, task);
+                Ok(Response::new(StatusCode::Ok))
             }
+            Ok(_) => {
+                let status = RunStatus::from(task.state);
+                debug!("Updating run {} to {}: {:?}", uuid, status, task);
+                Run::update_status(&uuid, status, &state.db).await?;
+                Ok(Response::new(StatusCode::Ok))
+            }
+            Err(sqlx::Error::RowNotFound) => Ok(Response::new(StatusCode::NotFound)),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /**
+     *  GET /runs/{uuid}/stream
+     *
+     *  Proxy the WebSockets log stream from the agent executing the Run, which only Runs
+     *  executed as a single step have
+     */
+    pub async fn stream_run(
+        req: Request<AppState<'_>>,
+        stream: WebSocketConnection,
+    ) -> Result<(), tide::Error> {
+        let uuid: String = req.param("uuid")?.into();
+        let run = Run::find_by(&uuid, &req.state().db).await?;
+
+        if let Some(url) = &run.run.stream_url {
+            debug!("Proxying the log stream for {} from {}", uuid, url);
+            proxy_stream(url, &stream).await?;
         }
         Ok(())
     }
+
+    /**
+     *  GET /runs/{uuid}/steps/{step}/stream
+     *
+     *  Proxy the WebSockets log stream from the agent executing the step
+     */
+    pub async fn stream_step(
+        req: Request<AppState<'_>>,
+        stream: WebSocketConnection,
+    ) -> Result<(), tide::Error> {
+        let uuid: String = req.param("uuid")?.into();
+        let step = Step::find_by(req.param("step")?, &req.state().db).await?;
+        if step.run != uuid {
+            return Err(tide::Error::from_str(
+                StatusCode::NotFound,
+                format!("No step {} of run {}", step.uuid, uuid),
+            ));
+        }
+
+        if let Some(url) = &step.stream_url {
+            debug!(
+                "Proxying the log stream for step {} of {} from {}",
+                step.name, uuid, url
+            );
+            proxy_stream(url, &stream).await?;
+        }
+        Ok(())
+    }
+
+    /*
+     * Relay the text of the agent's log stream until the agent closes it
+     */
+    async fn proxy_stream(url: &str, stream: &WebSocketConnection) -> Result<(), tide::Error> {
+        let (mut agent, _) = async_tungstenite::async_std::connect_async(url).await?;
+
+        while let Some(message) = agent.next().await {
+            match message? {
+                Message::Text(text) => stream.send_string(text).await?,
+                Message::Close(_) => break,
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ServerConfig;
+    use crate::models::{RunDefinition, RunStatus, ScmInfo};
+    use sqlx::SqlitePool;
+    use tide::http::{Method, Url};
+
+    async fn setup_app() -> tide::Server<AppState<'static>> {
+        let pool = SqlitePool::connect(":memory:")
+            .await
+            .expect("Failed to setup_database()");
+        sqlx::migrate!()
+            .run(&pool)
+            .await
+            .expect("Failed to run migrations in a test");
+        let config: ServerConfig = serde_yaml::from_str(
+            r#"
+agents: {}
+projects:
+  'test':
+    description: 'A test project'
+    labels: ['linux']
+    parameters:
+      - name: 'ENVIRONMENT'
+        type: 'choice'
+        choices: ['staging', 'production']
+    inline:
+      needs: []
+      parameters:
+        - name: 'VERBOSE'
+          type: 'boolean'
+      commands:
+        - 'whoami'
+"#,
+        )
+        .unwrap();
+        Project::create(&Project::new("test"), &pool).await.unwrap();
+
+        let mut app = tide::with_state(AppState::new(pool, config));
+        app.at("/api/v1/projects").get(api::list_projects);
+        app.at("/api/v1/projects/:name")
+            .get(api::get_project)
+            .post(api::execute_project);
+        app.at("/api/v1/projects/:name/runs").get(api::list_runs);
+        app.at("/api/v1/runs/:uuid").get(api::get_run);
+        app.at("/api/v1/runs/:uuid/cancel").post(api::cancel_run);
+        app.at("/api/v1/runs/:uuid/status")
+            .put(api::update_run_status);
+        app.at("/api/v1/runs/:uuid/steps/:step/status")
+            .put(api::update_step_status);
+        app
+    }
+
+    async fn get(app: &tide::Server<AppState<'static>>, path: &str) -> tide::http::Response {
+        let url = Url::parse("http://localhost").unwrap().join(path).unwrap();
+        app.respond(tide::http::Request::new(Method::Get, url))
+            .await
+            .unwrap()
+    }
+
+    fn post(path: &str) -> tide::http::Request {
+        let url = Url::parse("http://localhost").unwrap().join(path).unwrap();
+        tide::http::Request::new(Method::Post, url)
+    }
+
+    #[async_std::test]
+    async fn test_api_trigger_json() {
+        let app = setup_app().await;
+        let mut req = post("/api/v1/projects/test");
+        req.insert_header("Accept", "application/json");
+
+        let mut res: tide::http::Response = app.respond(req).await.unwrap();
+        assert_eq!(StatusCode::Created, res.status());
+        let trigger: synchronik::TriggerResponse = res.body_json().await.unwrap();
+        assert_eq!(1, trigger.num);
+        assert_eq!(RunStatus::Queued, trigger.status);
+        assert_eq!(None, trigger.agent);
+        assert_eq!(Some("ws"), trigger.stream.as_ref().map(|s| s.scheme()));
+        assert_eq!(
+            Some(trigger.run.as_str()),
+            res.header("Location").map(|h| h.as_str())
+        );
+        assert_eq!(
+            Ok(()),
+            app.state().woken.try_recv(),
+            "The dispatcher is woken rather than dispatching within the request"
+        );
+    }
+
+    #[async_std::test]
+    async fn test_api_trigger_form() {
+        let app = setup_app().await;
+        let mut req = post("/api/v1/projects/test");
+        req.set_body("next=/project/test");
+        req.set_content_type(tide::http::mime::FORM);
+
+        let res: tide::http::Response = app.respond(req).await.unwrap();
+        assert_eq!(StatusCode::Found, res.status());
+        assert_eq!(
+            Some("/project/test"),
+            res.header("Location").map(|h| h.as_str())
+        );
+    }
+
+    #[async_std::test]
+    async fn test_api_cancel_queued_run() {
+        let app = setup_app().await;
+        let project = Project::by_name("test", &app.state().db).await.unwrap();
+        let run = Run::new(project, ScmInfo::default(), RunDefinition::default());
+        let run = Run::create(&run, &app.state().db).await.unwrap();
+        let path = format!("/api/v1/runs/{}/cancel", run.run.uuid);
+
+        let mut res: tide::http::Response = app.respond(post(&path)).await.unwrap();
+        assert_eq!(StatusCode::Ok, res.status());
+        let cancelled: synchronik::RunResponse = res.body_json().await.unwrap();
+        assert_eq!(RunStatus::Cancelled, cancelled.status);
+        assert!(cancelled.finished_at.is_some());
+
+        let res: tide::http::Response = app.respond(post(&path)).await.unwrap();
+        assert_eq!(StatusCode::Conflict, res.status());
+
+        let res: tide::http::Response = app
+            .respond(post("/api/v1/runs/not-a-run/cancel"))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::NotFound, res.status());
+    }
+
+    #[async_std::test]
+    async fn test_api_cancel_with_unreachable_agent() {
+        let app = setup_app().await;
+        let state = app.state();
+        let project = Project::by_name("test", &state.db).await.unwrap();
+        let run = Run::new(
+            project,
+            ScmInfo::default(),
+            RunDefinition::new("needs: []\ncommands:\n  - 'whoami'\n"),
+        );
+        let run = Run::create(&run, &state.db).await.unwrap();
+        crate::dispatcher::dispatch_queued(state).await.unwrap();
+        let run = Run::find_by(&run.run.uuid, &state.db).await.unwrap();
+        crate::models::Step::started(
+            &run.steps[0].uuid,
+            "gone",
+            "http://127.0.0.1:1/log",
+            None,
+            Some("http://127.0.0.1:1/api/v1/tasks/gone"),
+            &state.db,
+        )
+        .await
+        .unwrap();
+
+        /*
+         * The Run is cancelled even though the agent cannot be asked to stop its task
+         */
+        let path = format!("/api/v1/runs/{}/cancel", run.run.uuid);
+        let mut res: tide::http::Response = app.respond(post(&path)).await.unwrap();
+        assert_eq!(StatusCode::Ok, res.status());
+        let cancelled: synchronik::RunResponse = res.body_json().await.unwrap();
+        assert_eq!(RunStatus::Cancelled, cancelled.status);
+        let run = Run::find_by(&run.run.uuid, &state.db).await.unwrap();
+        assert_eq!(RunStatus::Cancelled, run.steps[0].status());
+    }
+
+    #[async_std::test]
+    async fn test_parameters_of_resolved_definition() {
+        let pool = SqlitePool::connect(":memory:").await.unwrap();
+        sqlx::migrate!().run(&pool).await.unwrap();
+        let config: ServerConfig = serde_yaml::from_str(
+            r#"
+agents: {}
+projects:
+  'remote':
+    description: 'Defined in source control'
+    parameters:
+      - name: 'ENVIRONMENT'
+        type: 'choice'
+        choices: ['staging', 'production']
+    scm:
+      git:
+        url: 'https://example.com/remote.git'
+        ref: 'main'
+"#,
+        )
+        .unwrap();
+        Project::create(&Project::new("remote"), &pool)
+            .await
+            .unwrap();
+        let state = AppState::new(pool, config);
+        let project = Project::by_name("remote", &state.db).await.unwrap();
+
+        let names = |parameters: Vec<crate::parameters::Parameter>| -> Vec<String> {
+            parameters.into_iter().map(|p| p.name).collect()
+        };
+        assert_eq!(
+            vec!["ENVIRONMENT"],
+            names(parameters_of(&project, &state).await.unwrap())
+        );
+
+        /*
+         * The parameters declared by the Yml are known once a run has resolved it
+         */
+        let run = Run::new(
+            project.clone(),
+            ScmInfo::default(),
+            RunDefinition::new(
+                "needs: []\nparameters:\n  - name: 'VERBOSE'\n    type: 'boolean'\ncommands:\n  - 'whoami'\n",
+            ),
+        );
+        Run::create(&run, &state.db).await.unwrap();
+        assert_eq!(
+            vec!["ENVIRONMENT", "VERBOSE"],
+            names(parameters_of(&project, &state).await.unwrap())
+        );
+    }
+
+    #[async_std::test]
+    async fn test_fetch_log() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = Url::parse(&format!("http://{}", listener.local_addr().unwrap())).unwrap();
+        let mut agent = tide::new();
+        agent
+            .at("/short.log")
+            .get(|_| async { Ok("first\nsecond\n") });
+        agent.at("/long.log").get(|_| async {
+            Ok((0..MAX_CONSOLE)
+                .map(|i| format!("line {}\n", i))
+                .collect::<String>())
+        });
+        async_std::task::spawn(agent.listen(listener));
+        let client = reqwest::Client::new();
+
+        let (log, truncated) = fetch_log(&client, url.join("short.log").unwrap().as_str())
+            .await
+            .unwrap();
+        assert_eq!("first\nsecond\n", log);
+        assert!(!truncated);
+
+        let (log, truncated) = fetch_log(&client, url.join("long.log").unwrap().as_str())
+            .await
+            .unwrap();
+        assert!(truncated);
+        assert!(log.len() <= MAX_CONSOLE);
+        assert!(log.starts_with("line "), "Starts at a whole line");
+        assert!(log.ends_with(&format!("line {}\n", MAX_CONSOLE - 1)));
+
+        assert!(
+            fetch_log(&client, url.join("missing.log").unwrap().as_str())
+                .await
+                .is_err()
+        );
+    }
+
+    #[async_std::test]
+    async fn test_api_update_step_status() {
+        let app = setup_app().await;
+        let project = Project::by_name("test", &app.state().db).await.unwrap();
+        let run = Run::new(
+            project,
+            ScmInfo::default(),
+            RunDefinition::new("needs: []\ncommands:\n  - 'whoami'\n"),
+        );
+        let run = Run::create(&run, &app.state().db).await.unwrap();
+        crate::dispatcher::dispatch_queued(app.state())
+            .await
+            .unwrap();
+        let run = Run::find_by(&run.run.uuid, &app.state().db).await.unwrap();
+        assert_eq!(1, run.steps.len(), "The run should have been planned");
+
+        let put = |path: &str| {
+            let url = Url::parse("http://localhost").unwrap().join(path).unwrap();
+            let mut req = tide::http::Request::new(Method::Put, url);
+            let mut task = synchronik::TaskStatus::new(uuid::Uuid::new_v4(), &[]);
+            task.state = synchronik::TaskState::Failed;
+            req.set_body(tide::Body::from_json(&task).unwrap());
+            req
+        };
+
+        let path = format!(
+            "/api/v1/runs/{}/steps/{}/status",
+            run.run.uuid, run.steps[0].uuid
+        );
+        for rejected in [
+            path.clone(),
+            format!("{}?token=guessed", path),
+            format!("/api/v1/runs/{}/status", run.run.uuid),
+        ] {
+            let res: tide::http::Response = app.respond(put(&rejected)).await.unwrap();
+            assert_eq!(StatusCode::Forbidden, res.status(), "{}", rejected);
+        }
+        let unchanged = Run::find_by(&run.run.uuid, &app.state().db).await.unwrap();
+        assert_eq!(RunStatus::Queued.as_str(), unchanged.steps[0].status);
+
+        let token = run.run.token.as_ref().expect("The run should have a token");
+        let path = format!("{}?token={}", path, token);
+        let res: tide::http::Response = app.respond(put(&path)).await.unwrap();
+        assert_eq!(StatusCode::Ok, res.status());
+        let run = Run::find_by(&run.run.uuid, &app.state().db).await.unwrap();
+        assert_eq!(RunStatus::Failed.as_str(), run.steps[0].status);
+        assert_eq!(RunStatus::Failed.as_str(), run.run.status);
+
+        let res: tide::http::Response = app
+            .respond(put(&format!(
+                "/api/v1/runs/not-a-run/steps/{}/status",
+                run.steps[0].uuid
+            )))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::NotFound, res.status());
+    }
+
+    #[async_std::test]
+    async fn test_api_stream_steps() {
+        use crate::models::Step;
+        use async_std::prelude::*;
+        use async_tungstenite::tungstenite::Message;
+        use tide_websockets::{WebSocket, WebSocketConnection};
+
+        let pool = SqlitePool::connect(":memory:")
+            .await
+            .expect("Failed to setup_database()");
+        sqlx::migrate!()
+            .run(&pool)
+            .await
+            .expect("Failed to run migrations in a test");
+        let config: ServerConfig = serde_yaml::from_str(
+            r#"
+agents: {}
+projects:
+  'stages':
+    description: 'A project of two steps'
+    inline:
+      needs: []
+      jobs:
+        - name: 'first'
+          commands: ['whoami']
+        - name: 'second'
+          commands: ['whoami']
+"#,
+        )
+        .unwrap();
+        Project::create(&Project::new("stages"), &pool)
+            .await
+            .unwrap();
+        let mut app = tide::with_state(AppState::new(pool, config));
+        app.at("/api/v1/projects/:name").post(api::execute_project);
+        app.at("/api/v1/runs/:uuid/stream")
+            .get(WebSocket::new(api::stream_run));
+        app.at("/api/v1/runs/:uuid/steps/:step/stream")
+            .get(WebSocket::new(api::stream_step));
+
+        let mut req = post("/api/v1/projects/stages");
+        req.insert_header("Accept", "application/json");
+        let mut res: tide::http::Response = app.respond(req).await.unwrap();
+        assert_eq!(StatusCode::Created, res.status());
+        let trigger: synchronik::TriggerResponse = res.body_json().await.unwrap();
+        assert_eq!(None, trigger.stream, "Each step is streamed on its own");
+
+        /*
+         * The agent streams which task it is executing
+         */
+        let agent = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let agent_addr = agent.local_addr().unwrap();
+        let mut mock = tide::new();
+        mock.at("/api/v1/tasks/:uuid/stream").get(WebSocket::new(
+            |req: tide::Request<()>, stream: WebSocketConnection| async move {
+                stream
+                    .send_string(format!("output of {}", req.param("uuid")?))
+                    .await?;
+                Ok(())
+            },
+        ));
+        async_std::task::spawn(mock.listen(agent));
+
+        crate::dispatcher::dispatch_queued(app.state())
+            .await
+            .unwrap();
+        let run = Run::find_by(&trigger.uuid.to_string(), &app.state().db)
+            .await
+            .unwrap();
+        assert_eq!(2, run.steps.len(), "The run should have been planned");
+        for step in &run.steps {
+            let stream = format!("ws://{}/api/v1/tasks/{}/stream", agent_addr, step.uuid);
+            Step::started(
+                &step.uuid,
+                "agent",
+                "http://agent/log",
+                Some(&stream),
+                None,
+                &app.state().db,
+            )
+            .await
+            .unwrap();
+        }
+
+        let server = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+        async_std::task::spawn(app.clone().listen(server));
+
+        for step in &run.steps {
+            let url = format!(
+                "ws://{}/api/v1/runs/{}/steps/{}/stream",
+                addr, run.run.uuid, step.uuid
+            );
+            let (mut socket, _) = async_tungstenite::async_std::connect_async(url)
+                .await
+                .unwrap();
+            match socket.next().await {
+                Some(Ok(Message::Text(text))) => {
+                    assert_eq!(format!("output of {}", step.uuid), text)
+                }
+                other => panic!("Expected the log of {}: {:?}", step.name, other),
+            }
+        }
+
+        let url = format!(
+            "ws://{}/api/v1/runs/not-a-run/steps/{}/stream",
+            addr, run.steps[0].uuid
+        );
+        let (mut socket, _) = async_tungstenite::async_std::connect_async(url)
+            .await
+            .unwrap();
+        assert!(!matches!(socket.next().await, Some(Ok(Message::Text(_)))));
+    }
+
+    #[async_std::test]
+    async fn test_api_trigger_parameters() {
+        let app = setup_app().await;
+
+        let mut req = post("/api/v1/projects/test");
+        req.insert_header("Accept", "application/json");
+        req.set_body(json!({"parameters" : {"VERBOSE" : true}}));
+        let mut res: tide::http::Response = app.respond(req).await.unwrap();
+        assert_eq!(StatusCode::Created, res.status());
+        let trigger: synchronik::TriggerResponse = res.body_json().await.unwrap();
+
+        let run = Run::find_by(&trigger.uuid.to_string(), &app.state().db)
+            .await
+            .unwrap();
+        let parameters = run.run.parameters();
+        assert_eq!("true", parameters["VERBOSE"]);
+        assert_eq!("staging", parameters["ENVIRONMENT"]);
+
+        let mut req = post("/api/v1/projects/test");
+        req.set_body("next=/project/test&ENVIRONMENT=development");
+        req.set_content_type(tide::http::mime::FORM);
+        let res: tide::http::Response = app.respond(req).await.unwrap();
+        assert_eq!(StatusCode::BadRequest, res.status());
+    }
+
+    #[async_std::test]
+    async fn test_api_projects() {
+        let app = setup_app().await;
+
+        let mut res = get(&app, "/api/v1/projects").await;
+        assert_eq!(StatusCode::Ok, res.status());
+        let projects: synchronik::ProjectsResponse = res.body_json().await.unwrap();
+        assert_eq!(1, projects.projects.len());
+        assert_eq!("A test project", projects.projects[0].description);
+
+        let mut res = get(&app, "/api/v1/projects/test").await;
+        assert_eq!(StatusCode::Ok, res.status());
+        let project: synchronik::ProjectResponse = res.body_json().await.unwrap();
+        assert_eq!(vec!["linux".to_string()], project.labels);
+
+        let res = get(&app, "/api/v1/projects/missing").await;
+        assert_eq!(StatusCode::NotFound, res.status());
+    }
+
+    #[async_std::test]
+    async fn test_api_runs() {
+        let app = setup_app().await;
+        let project = Project::by_name("test", &app.state().db).await.unwrap();
+        let run = Run::new(project, ScmInfo::default(), RunDefinition::default());
+        let run = Run::create(&run, &app.state().db).await.unwrap();
+
+        let mut res = get(&app, "/api/v1/projects/test/runs").await;
+        assert_eq!(StatusCode::Ok, res.status());
+        let runs: synchronik::RunsResponse = res.body_json().await.unwrap();
+        assert_eq!(1, runs.runs.len());
+
+        let mut res = get(&app, &format!("/api/v1/runs/{}", run.run.uuid)).await;
+        assert_eq!(StatusCode::Ok, res.status());
+        let fetched: synchronik::RunResponse = res.body_json().await.unwrap();
+        assert_eq!(runs.runs[0], fetched);
+
+        let res = get(&app, "/api/v1/runs/not-a-run").await;
+        assert_eq!(StatusCode::NotFound, res.status());
+    }
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!(None, format_duration(None));
+        assert_eq!(
+            Some("42s".to_string()),
+            format_duration(Some(chrono::Duration::seconds(42)))
+        );
+        assert_eq!(
+            Some("1m 5s".to_string()),
+            format_duration(Some(chrono::Duration::seconds(65)))
+        );
+        assert_eq!(
+            Some("2h 1m".to_string()),
+            format_duration(Some(chrono::Duration::seconds(7265)))
+        );
+    }
 }
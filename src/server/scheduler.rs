@@ -0,0 +1,224 @@
+/**
+ * The scheduler enqueues runs of projects which have a cron schedule. The last time each
+ * schedule fired is kept in the database so that restarting the server neither fires a
+ * schedule twice nor silently skips a time which passed while it was down.
+ */
+use std::collections::HashMap;
+use std::time::Duration;
+
+use chrono::{DateTime, TimeZone, Utc};
+use log::*;
+
+use crate::config::{Project, Schedule};
+use crate::models::Run;
+use crate::routes::api::enqueue;
+use crate::AppState;
+
+/*
+ * The longest the scheduler sleeps before looking at the schedules again
+ */
+const MAXIMUM_SLEEP: Duration = Duration::from_secs(60);
+
+/*
+ * Loop forever enqueuing runs of the projects which have a schedule
+ */
+pub async fn run(state: AppState<'_>) {
+    let scheduled: Vec<(&String, &Project, &Schedule)> = state
+        .config
+        .projects
+        .iter()
+        .filter_map(|(name, project)| {
+            let schedule = project.schedule.as_ref()?;
+            match schedule.validate() {
+                Ok(_) => Some((name, project, schedule)),
+                Err(e) => {
+                    error!("Not scheduling {}: {:?}", name, e);
+                    None
+                }
+            }
+        })
+        .collect();
+    if scheduled.is_empty() {
+        debug!("No projects to schedule");
+        return;
+    }
+    debug!("Scheduler starting for {} projects", scheduled.len());
+
+    loop {
+        let now = Utc::now();
+        let mut next = now + chrono::Duration::from_std(MAXIMUM_SLEEP).unwrap();
+        for (name, project, schedule) in scheduled.iter() {
+            match tick(&state, name, project, schedule, now).await {
+                Ok(Some(at)) => next = next.min(at),
+                Ok(None) => {}
+                Err(e) => error!("Failed to schedule {}: {:?}", name, e),
+            }
+        }
+
+        let sleep = (next - Utc::now()).to_std().unwrap_or(Duration::ZERO);
+        async_std::task::sleep(sleep.min(MAXIMUM_SLEEP)).await;
+    }
+}
+
+/*
+ * Enqueue a Run of the project if its schedule has fired since it last did, returning
+ * when the schedule fires next.
+ *
+ * The first time a project is seen only the current time is recorded, otherwise adding a
+ * schedule would immediately fire it. When several times have passed, such as while the
+ * server was down, a single Run is enqueued for the most recent of them. The fire time is
+ * only recorded once its Run has been enqueued, so a time which could not be enqueued,
+ * such as while the source control system is unreachable, is tried again on the next tick.
+ */
+async fn tick(
+    state: &AppState<'_>,
+    name: &str,
+    project: &Project,
+    schedule: &Schedule,
+    now: DateTime<Utc>,
+) -> anyhow::Result<Option<DateTime<Utc>>> {
+    let record = crate::models::Project::by_name(name, &state.db).await?;
+    let next = schedule.next_after(now)?.map(|n| n.with_timezone(&Utc));
+
+    let last = match record.scheduled_at {
+        Some(last) => Utc.from_utc_datetime(&last),
+        None => {
+            debug!("Starting the schedule of {} at {}", name, now);
+            crate::models::Project::scheduled(&record, now.naive_utc(), &state.db).await?;
+            return Ok(next);
+        }
+    };
+
+    if let Some(fired) = schedule.last_between(last, now)? {
+        enqueue_scheduled(state, name, project, fired).await?;
+        crate::models::Project::scheduled(&record, fired.naive_utc(), &state.db).await?;
+    }
+    Ok(next)
+}
+
+async fn enqueue_scheduled(
+    state: &AppState<'_>,
+    name: &str,
+    project: &Project,
+    fired: DateTime<Utc>,
+) -> anyhow::Result<Run> {
+    info!(
+        "The schedule of {} fired at {}, enqueuing a run",
+        name, fired
+    );
+    let resolved = crate::scm::resolve(name, project, &state.config, &state.http, None).await?;
+    let parameters =
+        crate::parameters::resolve(&project.parameters(Some(&resolved.config)), &HashMap::new())
+            .map_err(anyhow::Error::msg)?;
+    enqueue(state, name, resolved, &parameters, "schedule", None)
+        .await
+        .map_err(|e| e.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ServerConfig;
+    use sqlx::SqlitePool;
+
+    async fn state() -> AppState<'static> {
+        let pool = SqlitePool::connect(":memory:").await.unwrap();
+        sqlx::migrate!().run(&pool).await.unwrap();
+        let config: ServerConfig = serde_yaml::from_str(
+            "agents: {}\nprojects:\n  'nightly':\n    description: 'Nightly'\n    schedule:\n      cron: '0 2 * * *'\n      timezone: 'Europe/Berlin'\n    inline:\n      needs: []\n      commands:\n      - 'true'\n  'required':\n    description: 'Cannot be enqueued without a value'\n    parameters:\n    - name: 'TARGET'\n      type: 'string'\n    schedule:\n      cron: '0 2 * * *'\n    inline:\n      needs: []\n      commands:\n      - 'true'\n",
+        )
+        .unwrap();
+        for name in ["nightly", "required"] {
+            crate::models::Project::create(&crate::models::Project::new(name), &pool)
+                .await
+                .unwrap();
+        }
+        AppState::new(pool, config)
+    }
+
+    async fn runs(state: &AppState<'_>) -> Vec<Run> {
+        let record = crate::models::Project::by_name("nightly", &state.db)
+            .await
+            .unwrap();
+        Run::list_for(&record, 10, 0, &state.db).await.unwrap()
+    }
+
+    #[async_std::test]
+    async fn test_tick() {
+        let state = state().await;
+        let project = &state.config.projects["nightly"];
+        let schedule = project.schedule.as_ref().unwrap();
+
+        // 02:00 in Berlin is 00:00 UTC during the summer
+        let start = Utc.with_ymd_and_hms(2023, 5, 1, 12, 0, 0).unwrap();
+        let next = tick(&state, "nightly", project, schedule, start)
+            .await
+            .unwrap();
+        let fire = Utc.with_ymd_and_hms(2023, 5, 2, 0, 0, 0).unwrap();
+        assert_eq!(Some(fire), next);
+        assert!(runs(&state).await.is_empty(), "Starting shouldn't fire");
+
+        let before = Utc.with_ymd_and_hms(2023, 5, 1, 23, 59, 0).unwrap();
+        tick(&state, "nightly", project, schedule, before)
+            .await
+            .unwrap();
+        assert!(runs(&state).await.is_empty());
+
+        let after = Utc.with_ymd_and_hms(2023, 5, 2, 0, 0, 30).unwrap();
+        tick(&state, "nightly", project, schedule, after)
+            .await
+            .unwrap();
+        let fired = runs(&state).await;
+        assert_eq!(1, fired.len());
+        assert_eq!("schedule", fired[0].run.trigger);
+
+        // Ticking again, as a restarted server would, must not fire a second time
+        tick(&state, "nightly", project, schedule, after)
+            .await
+            .unwrap();
+        assert_eq!(1, runs(&state).await.len());
+
+        // Several nights passing while the server was down enqueue a single run
+        let later = Utc.with_ymd_and_hms(2023, 5, 5, 6, 0, 0).unwrap();
+        tick(&state, "nightly", project, schedule, later)
+            .await
+            .unwrap();
+        assert_eq!(2, runs(&state).await.len());
+        let record = crate::models::Project::by_name("nightly", &state.db)
+            .await
+            .unwrap();
+        assert_eq!(
+            Some(
+                Utc.with_ymd_and_hms(2023, 5, 5, 0, 0, 0)
+                    .unwrap()
+                    .naive_utc()
+            ),
+            record.scheduled_at
+        );
+    }
+
+    #[async_std::test]
+    async fn test_tick_failed_enqueue() {
+        let state = state().await;
+        let project = &state.config.projects["required"];
+        let schedule = project.schedule.as_ref().unwrap();
+
+        let start = Utc.with_ymd_and_hms(2023, 5, 1, 12, 0, 0).unwrap();
+        tick(&state, "required", project, schedule, start)
+            .await
+            .unwrap();
+
+        /*
+         * The fire time isn't recorded when the Run cannot be enqueued, so that it is
+         * attempted again
+         */
+        let after = Utc.with_ymd_and_hms(2023, 5, 2, 2, 0, 30).unwrap();
+        assert!(tick(&state, "required", project, schedule, after)
+            .await
+            .is_err());
+        let record = crate::models::Project::by_name("required", &state.db)
+            .await
+            .unwrap();
+        assert_eq!(Some(start.naive_utc()), record.scheduled_at);
+    }
+}
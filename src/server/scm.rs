@@ -0,0 +1,947 @@
+/**
+ * The scm module resolves what a Run should execute from the source control system
+ * a project is configured with: the exact text of its Yml definition and the commit it
+ * was taken from.
+ */
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use async_std::sync::Mutex;
+use log::*;
+use octocrab::models::InstallationId;
+use octocrab::Octocrab;
+use secrecy::ExposeSecret;
+use serde::Deserialize;
+use url::Url;
+use uuid::Uuid;
+
+use crate::config::{GitHubApp, GitHubConfig, Project, Scm, ServerConfig, Yml};
+use crate::models::ScmInfo;
+
+/*
+ * Everything needed to create a Run for a project
+ */
+#[derive(Clone, Debug)]
+pub struct Resolved {
+    // Exact text of the Yml definition, recorded with the Run
+    pub definition: String,
+    pub config: Yml,
+    pub scm_info: ScmInfo,
+}
+
+/*
+ * Resolve the definition of the named project, pinned to the given commit or otherwise
+ * to the commit its ref currently points at when the project lives in source control.
+ * GitLab and Gitea are asked with the given client, whose timeouts keep an unresponsive
+ * forge from stalling the caller
+ */
+pub async fn resolve(
+    name: &str,
+    project: &Project,
+    config: &ServerConfig,
+    http: &reqwest::Client,
+    at: Option<&str>,
+) -> anyhow::Result<Resolved> {
+    let mut scm_info = ScmInfo::from(&project.scm);
+    scm_info.git_url = git_url(&project.scm, config)?;
+    let filename = project.filename.as_deref().unwrap_or("synchronik.yml");
+
+    let (sha, definition) = match &project.scm {
+        Scm::Nonexistent => {
+            info!("Nonexistent SCM, using inline configuration for {}", name);
+            info!("configuration: {:?}", project.inline);
+            return match &project.inline {
+                Some(config) => {
+                    config.validate()?;
+                    Ok(Resolved {
+                        definition: serde_yaml::to_string(config)?,
+                        config: config.clone(),
+                        scm_info,
+                    })
+                }
+                None => Err(anyhow::anyhow!(
+                    "Project {} has no inline configuration",
+                    name
+                )),
+            };
+        }
+        Scm::GitHub {
+            owner,
+            repo,
+            scm_ref,
+            credentials,
+        } => {
+            let github = config.github(credentials.as_deref())?;
+            let crab = github_client(&github, owner, repo).await?;
+            let sha = pinned(at, github_sha(&crab, owner, repo, scm_ref)).await?;
+            let text = github_file(&crab, owner, repo, &sha, filename).await?;
+            (sha, text)
+        }
+        Scm::Git { url, scm_ref } => {
+            let sha = pinned(at, git_sha(url, scm_ref)).await?;
+            let text = git_show(url, scm_ref, &sha, filename).await?;
+            (sha, text)
+        }
+        Scm::GitLab {
+            url,
+            project,
+            scm_ref,
+        } => {
+            let sha = pinned(at, gitlab_sha(http, url, project, scm_ref)).await?;
+            let text = gitlab_file(http, url, project, &sha, filename).await?;
+            (sha, text)
+        }
+        Scm::Gitea {
+            url,
+            project,
+            scm_ref,
+        } => {
+            let sha = pinned(at, gitea_sha(http, url, project, scm_ref)).await?;
+            let text = gitea_file(http, url, project, &sha, filename).await?;
+            (sha, text)
+        }
+    };
+    debug!(
+        "Resolved {} of {} to {} for {}",
+        scm_info.r#ref, scm_info.git_url, sha, name
+    );
+
+    let config: Yml = serde_yaml::from_str(&definition)?;
+    debug!("configuration: {:?}", config);
+    config.validate()?;
+    scm_info.sha = Some(sha);
+    Ok(Resolved {
+        definition,
+        config,
+        scm_info,
+    })
+}
+
+/*
+ * Look up the commit the ref of the project currently points at, without reading its
+ * definition
+ */
+pub async fn current_sha(
+    project: &Project,
+    config: &ServerConfig,
+    http: &reqwest::Client,
+) -> anyhow::Result<Option<String>> {
+    let sha = match &project.scm {
+        Scm::Nonexistent => return Ok(None),
+        Scm::GitHub {
+            owner,
+            repo,
+            scm_ref,
+            credentials,
+        } => {
+            let github = config.github(credentials.as_deref())?;
+            let crab = github_client(&github, owner, repo).await?;
+            github_sha(&crab, owner, repo, scm_ref).await?
+        }
+        Scm::Git { url, scm_ref } => git_sha(url, scm_ref).await?,
+        Scm::GitLab {
+            url,
+            project,
+            scm_ref,
+        } => gitlab_sha(http, url, project, scm_ref).await?,
+        Scm::Gitea {
+            url,
+            project,
+            scm_ref,
+        } => gitea_sha(http, url, project, scm_ref).await?,
+    };
+    Ok(Some(sha))
+}
+
+/*
+ * Use the commit the Run was pinned to, only looking up the ref without one
+ */
+async fn pinned(
+    at: Option<&str>,
+    lookup: impl std::future::Future<Output = anyhow::Result<String>>,
+) -> anyhow::Result<String> {
+    match at {
+        Some(sha) => Ok(sha.into()),
+        None => lookup.await,
+    }
+}
+
+/*
+ * URL which the repository of the Scm can be cloned from
+ */
+pub fn git_url(scm: &Scm, config: &ServerConfig) -> anyhow::Result<String> {
+    match scm {
+        Scm::GitHub {
+            owner,
+            repo,
+            credentials,
+            ..
+        } => Ok(config
+            .github(credentials.as_deref())?
+            .clone_url(owner, repo)),
+        scm => Ok(ScmInfo::from(scm).git_url),
+    }
+}
+
+/*
+ * API routes are joined onto the base URL, which drops the last segment of its path
+ * unless the path ends with a slash
+ */
+fn github_base(url: &Url) -> Url {
+    let mut url = url.clone();
+    if !url.path().ends_with('/') {
+        url.set_path(&format!("{}/", url.path()));
+    }
+    url
+}
+
+/*
+ * Authenticate as the GitHub App and find its installation on the repository
+ */
+async fn github_app(
+    config: &GitHubConfig,
+    app: &GitHubApp,
+    owner: &str,
+    repo: &str,
+) -> anyhow::Result<(Octocrab, InstallationId)> {
+    let key = std::fs::read(&app.key)
+        .map_err(|e| anyhow::anyhow!("Failed to read the key {:?}: {}", app.key, e))?;
+    let mut builder = Octocrab::builder().app(
+        app.id.into(),
+        jsonwebtoken::EncodingKey::from_rsa_pem(&key)?,
+    );
+    if let Some(url) = &config.url {
+        builder = builder.base_url(github_base(url))?;
+    }
+    let crab = builder.build()?;
+    let installation = crab.apps().get_repository_installation(owner, repo).await?;
+    Ok((crab, installation.id))
+}
+
+/*
+ * Build a client for reading the repository, authenticated as the installation of the
+ * GitHub App or with the personal access token when either is configured
+ */
+async fn github_client(config: &GitHubConfig, owner: &str, repo: &str) -> anyhow::Result<Octocrab> {
+    if let Some(app) = &config.app {
+        let (crab, installation) = github_app(config, app, owner, repo).await?;
+        return Ok(crab.installation(installation));
+    }
+
+    let mut builder = Octocrab::builder();
+    if let Some(url) = &config.url {
+        builder = builder.base_url(github_base(url))?;
+    }
+    if let Some(token) = &config.token {
+        builder = builder.personal_token(token.clone());
+    }
+    Ok(builder.build()?)
+}
+
+/*
+ * Installation tokens of GitHub Apps expire after an hour, they are reused for somewhat
+ * less than that so that an agent is never handed one which expires while it clones
+ */
+const TOKEN_REUSE: Duration = Duration::from_secs(45 * 60);
+
+/*
+ * Installation tokens which have already been created, so that a step waiting for a busy
+ * agent doesn't create a new one on every attempt to dispatch it
+ */
+#[derive(Default)]
+pub struct TokenCache {
+    tokens: Mutex<HashMap<String, (String, Instant)>>,
+}
+
+/*
+ * The state is logged while debugging, so the tokens are kept out of the debug
+ * representation
+ */
+impl std::fmt::Debug for TokenCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TokenCache").finish_non_exhaustive()
+    }
+}
+
+impl TokenCache {
+    async fn get(&self, key: &str) -> Option<String> {
+        match self.tokens.lock().await.get(key) {
+            Some((token, created)) if created.elapsed() < TOKEN_REUSE => Some(token.clone()),
+            _ => None,
+        }
+    }
+
+    async fn insert(&self, key: String, token: String) {
+        let mut tokens = self.tokens.lock().await;
+        tokens.retain(|_, (_, created)| created.elapsed() < TOKEN_REUSE);
+        tokens.insert(key, (token, Instant::now()));
+    }
+}
+
+/*
+ * Token which an agent can clone the repository with, the installation tokens of a
+ * GitHub App being created when there is no unexpired one in the cache
+ */
+pub async fn github_token(
+    config: &GitHubConfig,
+    owner: &str,
+    repo: &str,
+    cache: &TokenCache,
+) -> anyhow::Result<Option<String>> {
+    match &config.app {
+        Some(app) => {
+            let key = format!(
+                "{}:{}:{}/{}",
+                config.url.as_ref().map(Url::as_str).unwrap_or_default(),
+                app.id,
+                owner,
+                repo
+            );
+            if let Some(token) = cache.get(&key).await {
+                return Ok(Some(token));
+            }
+            let (crab, installation) = github_app(config, app, owner, repo).await?;
+            let (_, token) = crab.installation_and_token(installation).await?;
+            let token = token.expose_secret().clone();
+            cache.insert(key, token.clone()).await;
+            Ok(Some(token))
+        }
+        None => Ok(config.token.clone()),
+    }
+}
+
+/*
+ * Look up the SHA of the commit the ref points at in the GitHub repository
+ */
+async fn github_sha(
+    crab: &Octocrab,
+    owner: &str,
+    repo: &str,
+    scm_ref: &str,
+) -> anyhow::Result<String> {
+    let url = crab.absolute_url(format!("repos/{}/{}/commits/{}", owner, repo, scm_ref))?;
+
+    let mut headers = reqwest::header::HeaderMap::new();
+    headers.insert(
+        reqwest::header::ACCEPT,
+        reqwest::header::HeaderValue::from_static("application/vnd.github.sha"),
+    );
+    let res = crab
+        ._get_with_headers(url, None::<&()>, Some(headers))
+        .await?;
+    let sha = octocrab::map_github_error(res).await?.text().await?;
+    Ok(sha.trim().to_string())
+}
+
+async fn github_file(
+    crab: &Octocrab,
+    owner: &str,
+    repo: &str,
+    sha: &str,
+    filename: &str,
+) -> anyhow::Result<String> {
+    debug!("Fetching the file {} from {}/{}", filename, owner, repo);
+    let res = crab
+        .repos(owner, repo)
+        .raw_file(octocrab::params::repos::Commitish(sha.into()), filename)
+        .await?;
+    Ok(octocrab::map_github_error(res).await?.text().await?)
+}
+
+/*
+ * Append the segments to the path of the base URL, which may itself have a path when the
+ * server is not hosted at the root of its domain. Every segment is escaped, including any
+ * slashes within it
+ */
+fn api_url(base: &Url, segments: &[&str]) -> anyhow::Result<Url> {
+    let mut url = base.clone();
+    url.path_segments_mut()
+        .map_err(|_| anyhow::anyhow!("{} cannot be used as a base URL", base))?
+        .pop_if_empty()
+        .extend(segments);
+    Ok(url)
+}
+
+/*
+ * Look up the SHA of the commit the ref points at in the GitLab project, which is
+ * addressed by its full path such as group/project
+ */
+async fn gitlab_sha(
+    http: &reqwest::Client,
+    base: &Url,
+    project: &str,
+    scm_ref: &str,
+) -> anyhow::Result<String> {
+    #[derive(Deserialize)]
+    struct Commit {
+        id: String,
+    }
+
+    let url = api_url(
+        base,
+        &[
+            "api",
+            "v4",
+            "projects",
+            project,
+            "repository",
+            "commits",
+            scm_ref,
+        ],
+    )?;
+    let commit: Commit = http
+        .get(url)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    Ok(commit.id)
+}
+
+async fn gitlab_file(
+    http: &reqwest::Client,
+    base: &Url,
+    project: &str,
+    sha: &str,
+    filename: &str,
+) -> anyhow::Result<String> {
+    debug!("Fetching the file {} from {}", filename, project);
+    let mut url = api_url(
+        base,
+        &[
+            "api",
+            "v4",
+            "projects",
+            project,
+            "repository",
+            "files",
+            filename,
+            "raw",
+        ],
+    )?;
+    url.query_pairs_mut().append_pair("ref", sha);
+    Ok(http
+        .get(url)
+        .send()
+        .await?
+        .error_for_status()?
+        .text()
+        .await?)
+}
+
+/*
+ * Look up the SHA of the commit the ref points at in the Gitea repository, which is
+ * addressed as owner/repo
+ */
+async fn gitea_sha(
+    http: &reqwest::Client,
+    base: &Url,
+    project: &str,
+    scm_ref: &str,
+) -> anyhow::Result<String> {
+    #[derive(Deserialize)]
+    struct Commit {
+        sha: String,
+    }
+
+    let mut segments = vec!["api", "v1", "repos"];
+    segments.extend(project.split('/'));
+    segments.extend(["git", "commits", scm_ref]);
+    let url = api_url(base, &segments)?;
+    let commit: Commit = http
+        .get(url)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    Ok(commit.sha)
+}
+
+async fn gitea_file(
+    http: &reqwest::Client,
+    base: &Url,
+    project: &str,
+    sha: &str,
+    filename: &str,
+) -> anyhow::Result<String> {
+    debug!("Fetching the file {} from {}", filename, project);
+    let mut segments = vec!["api", "v1", "repos"];
+    segments.extend(project.split('/'));
+    segments.push("raw");
+    segments.extend(filename.split('/'));
+    let mut url = api_url(base, &segments)?;
+    url.query_pairs_mut().append_pair("ref", sha);
+    Ok(http
+        .get(url)
+        .send()
+        .await?
+        .error_for_status()?
+        .text()
+        .await?)
+}
+
+/*
+ * How long git is given to talk to a remote before it is assumed to be unreachable
+ */
+const GIT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/*
+ * Run git with the given arguments, returning what it wrote to stdout. git is stopped if
+ * it takes longer than GIT_TIMEOUT, so that an unreachable remote cannot hold up the
+ * poller or a webhook forever
+ */
+async fn git(args: Vec<String>) -> anyhow::Result<String> {
+    use subprocess::{Exec, NullFile, Redirection};
+
+    debug!("Running git {:?}", args);
+    let (status, stdout, stderr) = async_std::task::spawn_blocking(move || {
+        let mut process = Exec::cmd("git")
+            .args(&args)
+            // Never wait on somebody to type in credentials
+            .env("GIT_TERMINAL_PROMPT", "0")
+            .stdin(NullFile)
+            .stdout(Redirection::Pipe)
+            .stderr(Redirection::Pipe)
+            .popen()?;
+        match process
+            .communicate_start(None)
+            .limit_time(GIT_TIMEOUT)
+            .read()
+        {
+            Ok((stdout, stderr)) => Ok((
+                process.wait()?,
+                stdout.unwrap_or_default(),
+                stderr.unwrap_or_default(),
+            )),
+            Err(e) => {
+                let _ = process.kill();
+                let _ = process.wait();
+                Err(anyhow::anyhow!("git {:?} did not finish: {}", args, e))
+            }
+        }
+    })
+    .await?;
+
+    match status.success() {
+        true => Ok(String::from_utf8(stdout)?),
+        false => Err(anyhow::anyhow!(
+            "git failed with {:?}: {}",
+            status,
+            String::from_utf8_lossy(&stderr).trim()
+        )),
+    }
+}
+
+/*
+ * Look up the SHA of the commit the ref points at in the remote repository. The ref may
+ * be a branch, a tag, a full ref name or already a commit SHA
+ */
+async fn git_sha(url: &str, scm_ref: &str) -> anyhow::Result<String> {
+    if scm_ref.len() == 40 && scm_ref.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Ok(scm_ref.to_lowercase());
+    }
+
+    let output = git(vec![
+        "ls-remote".into(),
+        "--".into(),
+        url.into(),
+        scm_ref.into(),
+        // The peeled commit of an annotated tag is only listed when asked for
+        format!("{}^{{}}", scm_ref),
+    ])
+    .await?;
+    let refs: Vec<(&str, &str)> = output
+        .lines()
+        .filter_map(|line| line.split_once('\t'))
+        .collect();
+
+    /*
+     * ls-remote matches the pattern against the end of every ref, so refs/heads/main and
+     * refs/heads/feature/main both match main. Branches are preferred over tags, and
+     * annotated tags are peeled to the commit they point at
+     */
+    let candidates = [
+        scm_ref.to_string(),
+        format!("refs/heads/{}", scm_ref),
+        format!("refs/tags/{}^{{}}", scm_ref),
+        format!("refs/tags/{}", scm_ref),
+    ];
+    candidates
+        .iter()
+        .find_map(|candidate| {
+            refs.iter()
+                .find(|(_, name)| name == candidate)
+                .map(|(sha, _)| sha.to_string())
+        })
+        .ok_or_else(|| anyhow::anyhow!("Could not find the ref {} in {}", scm_ref, url))
+}
+
+/*
+ * Read the file at the commit from the remote repository by fetching the commit into a
+ * scratch repository which is removed afterwards
+ */
+async fn git_show(url: &str, scm_ref: &str, sha: &str, filename: &str) -> anyhow::Result<String> {
+    let scratch = std::env::temp_dir().join(format!("synchronik-scm-{}", Uuid::new_v4()));
+    let result = fetch_and_show(&scratch, url, scm_ref, sha, filename).await;
+    if let Err(e) = std::fs::remove_dir_all(&scratch) {
+        warn!(
+            "Failed to remove the scratch repository {:?}: {:?}",
+            scratch, e
+        );
+    }
+    result
+}
+
+async fn fetch_and_show(
+    scratch: &Path,
+    url: &str,
+    scm_ref: &str,
+    sha: &str,
+    filename: &str,
+) -> anyhow::Result<String> {
+    let dir = scratch.to_string_lossy().to_string();
+    git(vec![
+        "init".into(),
+        "--quiet".into(),
+        "--bare".into(),
+        dir.clone(),
+    ])
+    .await?;
+
+    /*
+     * Not every server allows fetching a commit which is not the tip of a ref, in which
+     * case the ref is fetched instead since it contained the commit a moment ago
+     */
+    let fetch = |target: &str, depth: bool| {
+        let mut args = vec!["-C".into(), dir.clone(), "fetch".into(), "--quiet".into()];
+        if depth {
+            args.push("--depth=1".into());
+        }
+        args.extend(["--".into(), url.into(), target.into()]);
+        git(args)
+    };
+    if let Err(e) = fetch(sha, true).await {
+        debug!(
+            "Failed to fetch {} directly, fetching {}: {:?}",
+            sha, scm_ref, e
+        );
+        fetch(scm_ref, false).await?;
+    }
+
+    git(vec![
+        "-C".into(),
+        dir,
+        "show".into(),
+        format!("{}:{}", sha, filename),
+    ])
+    .await
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    #[async_std::test]
+    async fn test_resolve_inline() {
+        let project: Project = serde_yaml::from_str(
+            r#"
+description: 'Inline'
+inline:
+  needs: []
+  commands:
+    - 'whoami'
+"#,
+        )
+        .unwrap();
+
+        let resolved = resolve(
+            "inline",
+            &project,
+            &ServerConfig::default(),
+            &reqwest::Client::new(),
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(1, resolved.config.commands.len());
+        assert_eq!(None, resolved.scm_info.sha);
+        assert_eq!(
+            resolved.config.commands,
+            serde_yaml::from_str::<Yml>(&resolved.definition)
+                .unwrap()
+                .commands
+        );
+    }
+
+    #[async_std::test]
+    async fn test_resolve_without_inline() {
+        let project: Project = serde_yaml::from_str("description: 'Empty'").unwrap();
+        assert!(resolve(
+            "empty",
+            &project,
+            &ServerConfig::default(),
+            &reqwest::Client::new(),
+            None
+        )
+        .await
+        .is_err());
+    }
+
+    /*
+     * Create a repository with two commits of the synchronik.yml, the first of which is
+     * tagged, returning the path and the SHAs of both commits
+     */
+    fn repository() -> (std::path::PathBuf, String, String) {
+        use crate::testing::{commit, git};
+
+        let repo = crate::testing::repository("scm");
+        let first = commit(&repo, "synchronik.yml", "needs: []\ncommands:\n- 'first'\n");
+        git(&repo, &["tag", "-a", "-m", "release", "v1"]);
+        let second = commit(
+            &repo,
+            "synchronik.yml",
+            "needs: []\ncommands:\n- 'second'\n",
+        );
+        (repo, first, second)
+    }
+
+    fn git_project(repo: &Path, scm_ref: &str) -> Project {
+        serde_yaml::from_str(&format!(
+            "description: 'git'\nscm:\n  git:\n    url: 'file://{}'\n    ref: '{}'\n",
+            repo.display(),
+            scm_ref
+        ))
+        .unwrap()
+    }
+
+    #[async_std::test]
+    async fn test_resolve_git() {
+        let (repo, first, second) = repository();
+
+        for (scm_ref, sha, command) in [
+            ("main", &second, "second"),
+            ("v1", &first, "first"),
+            (first.as_str(), &first, "first"),
+        ] {
+            let resolved = resolve(
+                "git",
+                &git_project(&repo, scm_ref),
+                &ServerConfig::default(),
+                &reqwest::Client::new(),
+                None,
+            )
+            .await
+            .expect("Failed to resolve");
+            assert_eq!(Some(sha), resolved.scm_info.sha.as_ref(), "{}", scm_ref);
+            assert_eq!(
+                vec![crate::config::YmlCommand::Script(command.into())],
+                resolved.config.commands
+            );
+            assert!(resolved.definition.contains(command));
+        }
+
+        assert!(resolve(
+            "git",
+            &git_project(&repo, "missing"),
+            &ServerConfig::default(),
+            &reqwest::Client::new(),
+            None
+        )
+        .await
+        .is_err());
+
+        let pinned = resolve(
+            "git",
+            &git_project(&repo, "main"),
+            &ServerConfig::default(),
+            &reqwest::Client::new(),
+            Some(&first),
+        )
+        .await
+        .expect("Failed to resolve the pinned commit");
+        assert_eq!(Some(&first), pinned.scm_info.sha.as_ref());
+        assert!(pinned.definition.contains("first"));
+        let _ = std::fs::remove_dir_all(&repo);
+    }
+
+    pub(crate) const SHA: &str = "7d5b3c0e1b0f4a8c9e2d6f1a3b5c7d9e0f2a4b6c";
+
+    /*
+     * Serve just enough of the GitLab, Gitea and GitHub Enterprise APIs under a path
+     * prefix, only answering for the main ref, and only with the file at its commit
+     */
+    pub(crate) fn forge() -> Url {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = Url::parse(&format!("http://{}/forge/", listener.local_addr().unwrap())).unwrap();
+
+        fn file(req: &tide::Request<()>, expected: &str) -> tide::Response {
+            #[derive(Deserialize)]
+            struct Query {
+                r#ref: String,
+            }
+            match req.query::<Query>() {
+                Ok(q) if q.r#ref == SHA && req.url().path().ends_with(expected) => {
+                    let mut res = tide::Response::new(200);
+                    res.set_body("needs: []\ncommands:\n- 'forged'\n");
+                    res
+                }
+                _ => tide::Response::new(404),
+            }
+        }
+
+        let mut app = tide::new();
+        app.at("/forge/api/v4/projects/group%2Fproject/repository/commits/main")
+            .get(|_| async { tide::Body::from_json(&serde_json::json!({ "id": SHA })) });
+        app.at("/forge/api/v4/projects/group%2Fproject/repository/files/:file/raw")
+            .get(
+                |req: tide::Request<()>| async move { Ok(file(&req, "/ci%2Fsynchronik.yml/raw")) },
+            );
+        app.at("/forge/api/v1/repos/owner/repo/git/commits/main")
+            .get(|_| async { tide::Body::from_json(&serde_json::json!({ "sha": SHA })) });
+        app.at("/forge/api/v3/repos/owner/repo/commits/main").get(
+            |req: tide::Request<()>| async move {
+                match req.header("Authorization").map(|h| h.as_str()) {
+                    Some("Bearer ghp_test") => Ok(tide::Response::from(SHA)),
+                    _ => Ok(tide::Response::new(404)),
+                }
+            },
+        );
+        app.at("/forge/api/v3/repos/owner/repo/contents/*path").get(
+            |req: tide::Request<()>| async move { Ok(file(&req, "/contents/ci/synchronik.yml")) },
+        );
+        app.at("/forge/api/v1/repos/owner/repo/raw/*path")
+            .get(|req: tide::Request<()>| async move { Ok(file(&req, "/raw/ci/synchronik.yml")) });
+        async_std::task::spawn(app.listen(listener));
+        url
+    }
+
+    #[async_std::test]
+    async fn test_resolve_forges() {
+        let url = forge();
+
+        for (kind, path) in [("gitlab", "group/project"), ("gitea", "owner/repo")] {
+            let project = |scm_ref: &str| -> Project {
+                serde_yaml::from_str(&format!(
+                    "description: '{kind}'\nfilename: 'ci/synchronik.yml'\nscm:\n  {kind}:\n    url: '{url}'\n    project: '{path}'\n    ref: '{scm_ref}'\n"
+                ))
+                .unwrap()
+            };
+
+            let resolved = resolve(
+                kind,
+                &project("main"),
+                &ServerConfig::default(),
+                &reqwest::Client::new(),
+                None,
+            )
+            .await
+            .expect("Failed to resolve");
+            assert_eq!(Some(SHA), resolved.scm_info.sha.as_deref(), "{}", kind);
+            assert_eq!(format!("{}{}.git", url, path), resolved.scm_info.git_url);
+            assert_eq!(
+                vec![crate::config::YmlCommand::Script("forged".into())],
+                resolved.config.commands
+            );
+
+            assert!(resolve(
+                kind,
+                &project("missing"),
+                &ServerConfig::default(),
+                &reqwest::Client::new(),
+                None
+            )
+            .await
+            .is_err());
+        }
+    }
+
+    #[async_std::test]
+    async fn test_resolve_unresponsive_forge() {
+        /*
+         * Connections are accepted by the listener's backlog but never answered
+         */
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let http = reqwest::Client::builder()
+            .timeout(Duration::from_millis(500))
+            .build()
+            .unwrap();
+
+        for (kind, path) in [("gitlab", "group/project"), ("gitea", "owner/repo")] {
+            let project: Project = serde_yaml::from_str(&format!(
+                "description: '{kind}'\nscm:\n  {kind}:\n    url: '{url}'\n    project: '{path}'\n    ref: 'main'\n"
+            ))
+            .unwrap();
+
+            let started = Instant::now();
+            assert!(
+                resolve(kind, &project, &ServerConfig::default(), &http, None)
+                    .await
+                    .is_err()
+            );
+            assert!(current_sha(&project, &ServerConfig::default(), &http)
+                .await
+                .is_err());
+            assert!(started.elapsed() < Duration::from_secs(5), "{}", kind);
+        }
+        drop(listener);
+    }
+
+    #[async_std::test]
+    async fn test_token_cache() {
+        let cache = TokenCache::default();
+        assert_eq!(None, cache.get("app:1:owner/repo").await);
+        cache
+            .insert("app:1:owner/repo".into(), "ghs_token".into())
+            .await;
+        assert_eq!(
+            Some("ghs_token".to_string()),
+            cache.get("app:1:owner/repo").await
+        );
+        assert_eq!(None, cache.get("app:1:owner/other").await);
+    }
+
+    #[async_std::test]
+    async fn test_resolve_github_enterprise() {
+        let url = forge();
+        let mut config: ServerConfig = serde_yaml::from_str(&format!(
+            "agents: {{}}\nprojects: {{}}\ngithub:\n  enterprise:\n    url: '{}api/v3'\n    token: 'ghp_test'\n",
+            url
+        ))
+        .unwrap();
+        let project: Project = serde_yaml::from_str(
+            "description: 'github'\nfilename: 'ci/synchronik.yml'\nscm:\n  github:\n    owner: 'owner'\n    repo: 'repo'\n    ref: 'main'\n    credentials: 'enterprise'\n",
+        )
+        .unwrap();
+
+        let resolved = resolve("github", &project, &config, &reqwest::Client::new(), None)
+            .await
+            .expect("Failed to resolve");
+        assert_eq!(Some(SHA), resolved.scm_info.sha.as_deref());
+        assert_eq!(
+            format!("{}owner/repo.git", url.join("/").unwrap()),
+            resolved.scm_info.git_url
+        );
+        assert_eq!(
+            Some("ghp_test".to_string()),
+            github_token(
+                &config.github(Some("enterprise")).unwrap(),
+                "owner",
+                "repo",
+                &TokenCache::default()
+            )
+            .await
+            .unwrap()
+        );
+
+        config.github.get_mut("enterprise").unwrap().token = None;
+        assert!(
+            resolve("github", &project, &config, &reqwest::Client::new(), None)
+                .await
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,179 @@
+/**
+ * The strategy module contains the implementations for selecting which agent a run should
+ * be dispatched to when more than one agent can meet its needs.
+ */
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use crate::config::{Agent, SelectionStrategy};
+
+/*
+ * A Selector orders the capable agents by preference, the dispatcher will then offer the
+ * run to each agent in that order until one accepts it
+ */
+pub trait Selector: std::fmt::Debug + Send + Sync {
+    fn order<'a>(&self, agents: Vec<&'a Agent>, labels: &[String]) -> Vec<&'a Agent>;
+
+    /*
+     * Notify the Selector that the agent has accepted a run
+     */
+    fn accepted(&self, _agent: &Agent) {}
+}
+
+impl SelectionStrategy {
+    pub fn selector(&self) -> Box<dyn Selector> {
+        match self {
+            SelectionStrategy::FirstAvailable => Box::new(FirstAvailable),
+            SelectionStrategy::RoundRobin => Box::<RoundRobin>::default(),
+            SelectionStrategy::LeastRecentlyUsed => Box::<LeastRecentlyUsed>::default(),
+            SelectionStrategy::LabelAffinity => Box::new(LabelAffinity),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct FirstAvailable;
+
+impl Selector for FirstAvailable {
+    fn order<'a>(&self, agents: Vec<&'a Agent>, _labels: &[String]) -> Vec<&'a Agent> {
+        agents
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct RoundRobin {
+    // Name of the agent which most recently accepted a run
+    last: Mutex<Option<String>>,
+}
+
+impl Selector for RoundRobin {
+    fn order<'a>(&self, mut agents: Vec<&'a Agent>, _labels: &[String]) -> Vec<&'a Agent> {
+        let last = self.last.lock().expect("Failed to lock round robin state");
+
+        if let Some(last) = last.as_ref() {
+            if let Some(index) = agents.iter().position(|a| &a.name == last) {
+                agents.rotate_left(index + 1);
+            }
+        }
+        agents
+    }
+
+    fn accepted(&self, agent: &Agent) {
+        *self.last.lock().expect("Failed to lock round robin state") = Some(agent.name.clone());
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct LeastRecentlyUsed {
+    last_used: Mutex<HashMap<String, Instant>>,
+}
+
+impl Selector for LeastRecentlyUsed {
+    fn order<'a>(&self, mut agents: Vec<&'a Agent>, _labels: &[String]) -> Vec<&'a Agent> {
+        let last_used = self.last_used.lock().expect("Failed to lock LRU state");
+        // Agents which have never been used sort first since None < Some
+        agents.sort_by_key(|a| last_used.get(&a.name).copied());
+        agents
+    }
+
+    fn accepted(&self, agent: &Agent) {
+        self.last_used
+            .lock()
+            .expect("Failed to lock LRU state")
+            .insert(agent.name.clone(), Instant::now());
+    }
+}
+
+#[derive(Debug)]
+pub struct LabelAffinity;
+
+impl Selector for LabelAffinity {
+    fn order<'a>(&self, mut agents: Vec<&'a Agent>, labels: &[String]) -> Vec<&'a Agent> {
+        agents.sort_by_key(|a| {
+            std::cmp::Reverse(a.labels.iter().filter(|l| labels.contains(l)).count())
+        });
+        agents
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn agent(name: &str, labels: &[&str]) -> Agent {
+        Agent {
+            name: name.into(),
+            labels: labels.iter().map(|l| l.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    fn names(agents: Vec<&Agent>) -> Vec<&str> {
+        agents.iter().map(|a| a.name.as_str()).collect()
+    }
+
+    #[test]
+    fn test_first_available() {
+        let agents = [agent("a", &[]), agent("b", &[])];
+        let selector = SelectionStrategy::FirstAvailable.selector();
+        selector.accepted(&agents[0]);
+        assert_eq!(
+            vec!["a", "b"],
+            names(selector.order(agents.iter().collect(), &[]))
+        );
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let agents = [agent("a", &[]), agent("b", &[]), agent("c", &[])];
+        let selector = SelectionStrategy::RoundRobin.selector();
+        assert_eq!(
+            vec!["a", "b", "c"],
+            names(selector.order(agents.iter().collect(), &[]))
+        );
+
+        selector.accepted(&agents[0]);
+        assert_eq!(
+            vec!["b", "c", "a"],
+            names(selector.order(agents.iter().collect(), &[]))
+        );
+
+        selector.accepted(&agents[2]);
+        assert_eq!(
+            vec!["a", "b", "c"],
+            names(selector.order(agents.iter().collect(), &[]))
+        );
+    }
+
+    #[test]
+    fn test_least_recently_used() {
+        let agents = [agent("a", &[]), agent("b", &[]), agent("c", &[])];
+        let selector = SelectionStrategy::LeastRecentlyUsed.selector();
+        selector.accepted(&agents[1]);
+        selector.accepted(&agents[0]);
+        assert_eq!(
+            vec!["c", "b", "a"],
+            names(selector.order(agents.iter().collect(), &[]))
+        );
+    }
+
+    #[test]
+    fn test_label_affinity() {
+        let agents = [
+            agent("a", &[]),
+            agent("b", &["linux"]),
+            agent("c", &["linux", "fast"]),
+        ];
+        let selector = SelectionStrategy::LabelAffinity.selector();
+        let labels = vec!["linux".to_string(), "fast".to_string()];
+        assert_eq!(
+            vec!["c", "b", "a"],
+            names(selector.order(agents.iter().collect(), &labels))
+        );
+        assert_eq!(
+            vec!["a", "b", "c"],
+            names(selector.order(agents.iter().collect(), &[]))
+        );
+    }
+}
@@ -0,0 +1,40 @@
+/*
+ * Fixtures shared by the tests of the server and the agent, which are included into both
+ * binaries as they cannot reach the test code of one another
+ */
+use std::path::{Path, PathBuf};
+
+/*
+ * Run git in the repository, returning what it printed
+ */
+pub fn git(repo: &Path, args: &[&str]) -> String {
+    let output = std::process::Command::new("git")
+        .args(["-c", "user.name=test", "-c", "user.email=test@example.com"])
+        .args(args)
+        .current_dir(repo)
+        .output()
+        .expect("Failed to run git");
+    assert!(output.status.success(), "git {:?} failed", args);
+    String::from_utf8(output.stdout).unwrap().trim().to_string()
+}
+
+/*
+ * Initialize an empty git repository on the main branch in a temporary directory, which
+ * the test is expected to remove
+ */
+pub fn repository(prefix: &str) -> PathBuf {
+    let repo = std::env::temp_dir().join(format!("{}-{}", prefix, uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&repo).unwrap();
+    git(&repo, &["init", "--quiet", "--initial-branch=main"]);
+    repo
+}
+
+/*
+ * Commit the content of the file to the repository, returning the SHA of the new commit
+ */
+pub fn commit(repo: &Path, file: &str, content: &str) -> String {
+    std::fs::write(repo.join(file), content).unwrap();
+    git(repo, &["add", file]);
+    git(repo, &["commit", "--quiet", "-m", content]);
+    git(repo, &["rev-parse", "HEAD"])
+}